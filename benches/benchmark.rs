@@ -76,6 +76,25 @@ fn unpickle_list_no_memo(c: &mut Criterion) {
     });
 }
 
+fn unpickle_shared_list(c: &mut Criterion) {
+    // Creates [l, l, ...] with l = [0, 1, 2, ...]
+    let mut buffer = b"\x80\x02](]q\x00(".to_vec();
+    for i in 0..1000 {
+        buffer.push(b'M');
+        buffer.write_u16::<LittleEndian>(i as u16).unwrap();
+    }
+    buffer.push(b'e');
+    // Reference it again from the memo
+    for _ in 0..99 {
+        buffer.extend(b"h\x00");
+    }
+    buffer.extend(b"e.");
+
+    c.bench_function("unpickle_shared_list", |b| {
+        b.iter(|| value_from_slice(&buffer, Default::default()).unwrap());
+    });
+}
+
 fn unpickle_dict(c: &mut Criterion) {
     // Creates {0: "string", 1: "string", ...}
     let mut buffer = b"\x80\x03}q\x00(K\x00".to_vec();
@@ -193,6 +212,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
 
     unpickle_list(c);
     unpickle_list_no_memo(c);
+    unpickle_shared_list(c);
     unpickle_dict(c);
     unpickle_nested_list(c);
     unpickle_nested_list_no_memo(c);
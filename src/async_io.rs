@@ -0,0 +1,75 @@
+// Copyright (c) 2015-2021 Georg Brandl.  Licensed under the Apache License,
+// Version 2.0 <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>
+// or the MIT license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at
+// your option. This file may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Reading and writing pickles with tokio's asynchronous I/O traits.
+//!
+//! Decoding runs the same opcode machine as the blocking `Deserializer`,
+//! suspending whenever the data received so far is exhausted.  Encoding
+//! pickles into a buffer first, which is then written asynchronously.
+
+use serde::{de, ser};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::de::{DeOptions, Deserializer, Feed};
+use crate::error::{Error, Result};
+use crate::ser::{SerOptions, to_vec};
+use crate::value;
+
+// Decode a pickle from the reader.  Only the data up to its STOP opcode is
+// consumed, so that nothing is lost if another message follows, and the
+// pickle is returned without waiting for the reader to be closed.
+async fn parse_async<R>(rdr: &mut R, options: DeOptions) -> Result<Deserializer<Feed, Feed>>
+    where R: AsyncBufRead + Unpin
+{
+    let mut de = Deserializer::from_feed(options);
+    loop {
+        let data = rdr.fill_buf().await.map_err(Error::Io)?;
+        let len = data.len();
+        if len == 0 {
+            de.feed_eof();
+        } else {
+            de.feed(data);
+        }
+        if de.parse_available()? {
+            rdr.consume(len - de.unparsed_len());
+            return Ok(de);
+        }
+        rdr.consume(len);
+    }
+}
+
+/// Decodes a value from a `tokio::io::AsyncBufRead`.
+///
+/// Like `Deserializer::from_buf_read`, this stops reading after the end of
+/// the pickle, and leaves any data that follows in the reader.  Wrap an
+/// `AsyncRead` in a `tokio::io::BufReader` to use it.
+pub async fn from_async_reader<R, T>(mut rdr: R, options: DeOptions) -> Result<T>
+    where R: AsyncBufRead + Unpin, T: de::DeserializeOwned
+{
+    let mut de = parse_async(&mut rdr, options).await?;
+    de::Deserialize::deserialize(&mut de)
+}
+
+/// Decodes a value from a `tokio::io::AsyncBufRead`.
+///
+/// See `from_async_reader` for how the reader is consumed.
+pub async fn value_from_async_reader<R>(mut rdr: R, options: DeOptions) -> Result<value::Value>
+    where R: AsyncBufRead + Unpin
+{
+    let mut de = parse_async(&mut rdr, options).await?;
+    de.deserialize_value()
+}
+
+/// Encode the specified struct into a `tokio::io::AsyncWrite`.
+///
+/// This doesn't stream: the whole pickle is encoded into memory first, and
+/// then written.
+pub async fn to_async_writer<W, T>(writer: &mut W, value: &T, options: SerOptions) -> Result<()>
+    where W: AsyncWrite + Unpin, T: ser::Serialize
+{
+    let buf = to_vec(value, options)?;
+    writer.write_all(&buf).await.map_err(Error::Io)
+}
@@ -70,14 +70,87 @@ pub const BUILD            : u8 = b'b';    // call __setstate__ or __dict__.upda
 pub const NEWOBJ           : u8 = b'\x81'; // build object by applying cls.__new__ to argtuple
 pub const NEWOBJ_EX        : u8 = b'\x92'; // like NEWOBJ but work with keyword only arguments
 pub const BYTEARRAY8       : u8 = b'\x96'; // push bytearray
+pub const PERSID           : u8 = b'P';    // push persistent object; id is taken from string arg
+pub const BINPERSID        : u8 = b'Q';    //  "       "         "  ;  "  "   "     "  stack
+pub const EXT1             : u8 = b'\x82'; // push object from extension registry; 1-byte index
+pub const EXT2             : u8 = b'\x83'; // ditto, but 2-byte index
+pub const EXT4             : u8 = b'\x84'; // ditto, but 4-byte index
 
-// Ops used for out-of-band buffers; these are unsupported.
-// pub const NEXT_BUFFER      : u8 = b'\x97'; // push next out-of-band buffer
-// pub const READONLY_BUFFER  : u8 = b'\x98'; // make top of stack readonly
+// Protocol 5
+pub const NEXT_BUFFER      : u8 = b'\x97'; // push next out-of-band buffer
+pub const READONLY_BUFFER  : u8 = b'\x98'; // make top of stack readonly
 
-// Ops only used for recursive objects; these are unsupported.
-// pub const PERSID           : u8 = b'P';    // push persistent object; id is taken from string arg
-// pub const BINPERSID        : u8 = b'Q';    //  "       "         "  ;  "  "   "     "  stack
-// pub const EXT1             : u8 = b'\x82'; // push object from extension registry; 1-byte index
-// pub const EXT2             : u8 = b'\x83'; // ditto, but 2-byte index
-// pub const EXT4             : u8 = b'\x84'; // ditto, but 4-byte index
+/// Return the name of an opcode, if it is known.
+pub fn opcode_name(opcode: u8) -> Option<&'static str> {
+    Some(match opcode {
+        MARK             => "MARK",
+        STOP             => "STOP",
+        POP              => "POP",
+        POP_MARK         => "POP_MARK",
+        DUP              => "DUP",
+        FLOAT            => "FLOAT",
+        INT              => "INT",
+        BININT           => "BININT",
+        BININT1          => "BININT1",
+        LONG             => "LONG",
+        BININT2          => "BININT2",
+        NONE             => "NONE",
+        STRING           => "STRING",
+        BINSTRING        => "BINSTRING",
+        SHORT_BINSTRING  => "SHORT_BINSTRING",
+        UNICODE          => "UNICODE",
+        BINUNICODE       => "BINUNICODE",
+        APPEND           => "APPEND",
+        DICT             => "DICT",
+        EMPTY_DICT       => "EMPTY_DICT",
+        APPENDS          => "APPENDS",
+        LIST             => "LIST",
+        EMPTY_LIST       => "EMPTY_LIST",
+        SETITEM          => "SETITEM",
+        TUPLE            => "TUPLE",
+        EMPTY_TUPLE      => "EMPTY_TUPLE",
+        SETITEMS         => "SETITEMS",
+        BINFLOAT         => "BINFLOAT",
+        PUT              => "PUT",
+        BINPUT           => "BINPUT",
+        LONG_BINPUT      => "LONG_BINPUT",
+        GET              => "GET",
+        BINGET           => "BINGET",
+        LONG_BINGET      => "LONG_BINGET",
+        GLOBAL           => "GLOBAL",
+        STACK_GLOBAL     => "STACK_GLOBAL",
+        REDUCE           => "REDUCE",
+        PROTO            => "PROTO",
+        TUPLE1           => "TUPLE1",
+        TUPLE2           => "TUPLE2",
+        TUPLE3           => "TUPLE3",
+        NEWTRUE          => "NEWTRUE",
+        NEWFALSE         => "NEWFALSE",
+        LONG1            => "LONG1",
+        LONG4            => "LONG4",
+        BINBYTES         => "BINBYTES",
+        SHORT_BINBYTES   => "SHORT_BINBYTES",
+        SHORT_BINUNICODE => "SHORT_BINUNICODE",
+        BINUNICODE8      => "BINUNICODE8",
+        BINBYTES8        => "BINBYTES8",
+        EMPTY_SET        => "EMPTY_SET",
+        ADDITEMS         => "ADDITEMS",
+        FROZENSET        => "FROZENSET",
+        MEMOIZE          => "MEMOIZE",
+        FRAME            => "FRAME",
+        INST             => "INST",
+        OBJ              => "OBJ",
+        BUILD            => "BUILD",
+        NEWOBJ           => "NEWOBJ",
+        NEWOBJ_EX        => "NEWOBJ_EX",
+        BYTEARRAY8       => "BYTEARRAY8",
+        PERSID           => "PERSID",
+        BINPERSID        => "BINPERSID",
+        EXT1             => "EXT1",
+        EXT2             => "EXT2",
+        EXT4             => "EXT4",
+        NEXT_BUFFER      => "NEXT_BUFFER",
+        READONLY_BUFFER  => "READONLY_BUFFER",
+        _ => return None,
+    })
+}
@@ -11,14 +11,21 @@
 //! `value_from_*` functions exported here, not the generic `from_*` functions.
 
 use std::io;
+use std::ascii;
+use std::cmp;
 use std::mem;
 use std::str;
 use std::char;
 use std::vec;
+use std::sync::Arc;
 use std::io::{BufReader, BufRead, Read};
 use std::str::FromStr;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::collections::btree_map::Entry;
 use std::iter::FusedIterator;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::convert::TryFrom;
 use serde::{de, forward_to_deserialize_any};
 use serde::de::Visitor;
 use num_bigint::{BigInt, Sign};
@@ -26,12 +33,19 @@ use num_traits::ToPrimitive;
 use byteorder::{ByteOrder, BigEndian, LittleEndian};
 use iter_read::{IterRead, IterReadItem};
 
-use super::error::{Error, ErrorCode, Result};
+use super::error::{Error, ErrorCode, ErrorContext, Result};
 use super::consts::*;
+use super::hooks::Hook;
+use super::trace::{self, Step, Trace, VmState};
 use super::value;
+use super::value_impls;
 
 type MemoId = u32;
 
+/// Nesting depth limit if none is set with `DeOptions::max_depth` or
+/// `SerOptions::max_depth`.
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 256;
+
 #[derive(Clone, Debug, PartialEq)]
 enum Global {
     Set,         // builtins/__builtin__.set
@@ -40,14 +54,32 @@ enum Global {
     List,        // builtins/__builtin__.list
     Int,         // builtins/__builtin__.int
     Encode,      // _codecs.encode
-    Other,       // anything else (may be a classobj that is later discarded)
+    Other(String, String),  // anything else (module and name; may be a classobj
+                            // that is later instantiated or discarded)
+    Custom(String, String, Hook<Reconstruct>),  // resolved by the user's Resolver
+}
+
+impl Global {
+    // The name of a builtin that is applied to a single argument.
+    fn builtin_name(&self) -> &'static str {
+        match *self {
+            Global::Set => "set",
+            Global::Frozenset => "frozenset",
+            Global::Bytearray => "bytearray",
+            Global::List => "list",
+            Global::Int => "int",
+            _ => "encode",
+        }
+    }
 }
 
 /// Our intermediate representation of a value.
 ///
 /// The most striking difference to `value::Value` is that it contains a variant
 /// for `MemoRef`, which references values put into the "memo" map, and a variant
-/// for module globals that we support.
+/// for module globals that we support.  Memoized values that are used by
+/// opcodes are `Shared` with the memo instead of copied (see
+/// `Deserializer::resolve`).
 ///
 /// We also don't use sets and maps at the Rust level, since they are not
 /// needed: nothing is ever looked up in them at this stage, and Vecs are much
@@ -56,6 +88,7 @@ enum Global {
 enum Value {
     MemoRef(MemoId),
     Global(Global),
+    Object(Box<Object>),
     None,
     Bool(bool),
     I64(i64),
@@ -68,62 +101,1476 @@ enum Value {
     Set(Vec<Value>),
     FrozenSet(Vec<Value>),
     Dict(Vec<(Value, Value)>),
+    BorrowedBytes(usize, usize),   // range of the input, see `Input::read_borrowed`
+    BorrowedString(usize, usize),  // same, already checked to be UTF-8
+    Shared(Arc<Value>),            // a memoized value, copied only if it is still shared
+    Apply(Global, Arc<Value>),     // a builtin applied to a shared value, see `reduce_builtin`
+}
+
+/// Intermediate representation of `value::Object`.
+#[derive(Clone, Debug, PartialEq)]
+struct Object {
+    module: String,
+    name: String,
+    args: Value,            // a tuple, which may be shared
+    kwargs: Option<Value>,  // a dict, which may be shared
+    state: Option<Value>,
+    listitems: Vec<Value>,
+    dictitems: Vec<(Value, Value)>,
+    reconstruct: Option<Hook<Reconstruct>>,
+}
+
+impl Value {
+    // Drop a value without recursion, since it can be nested too deeply for
+    // the derived drop glue.
+    fn dismantle(self) {
+        let mut values = vec![self];
+        while let Some(value) = values.pop() {
+            match value {
+                Value::List(items) | Value::Tuple(items) |
+                Value::Set(items) | Value::FrozenSet(items) => values.extend(items),
+                Value::Dict(items) => Self::dismantle_pairs(&mut values, items),
+                Value::Object(object) => {
+                    let object = *object;
+                    values.push(object.args);
+                    values.extend(object.kwargs);
+                    values.extend(object.state);
+                    values.extend(object.listitems);
+                    Self::dismantle_pairs(&mut values, object.dictitems);
+                }
+                Value::Shared(value) | Value::Apply(_, value) => {
+                    if let Ok(value) = Arc::try_unwrap(value) {
+                        values.push(value);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn dismantle_pairs(values: &mut Vec<Value>, pairs: Vec<(Value, Value)>) {
+        for (key, value) in pairs {
+            values.push(key);
+            values.push(value);
+        }
+    }
+
+    // Copy a dict key to report it in the path of errors, if it is a string
+    // or a scalar.  Other keys are not copied, and reported by their index.
+    fn path_key(&self) -> Option<Value> {
+        match *self {
+            Value::String(_) | Value::BorrowedString(..) | Value::MemoRef(_) | Value::None |
+            Value::Bool(_) | Value::I64(_) | Value::F64(_) => Some(self.clone()),
+            Value::Shared(ref value) => value.path_key(),
+            _ => None,
+        }
+    }
+
+    // Look through a value shared with the memo.
+    fn shared(&self) -> &Value {
+        let mut value = self;
+        while let Value::Shared(ref inner) = *value {
+            value = inner;
+        }
+        value
+    }
+
+    fn new_object(module: String, name: String, args: Value, kwargs: Option<Value>,
+                  reconstruct: Option<Hook<Reconstruct>>) -> Value {
+        Value::Object(Box::new(Object {
+            module, name, args, kwargs, state: None,
+            listitems: Vec::new(), dictitems: Vec::new(), reconstruct,
+        }))
+    }
+}
+
+/// Callback that reconstructs an instance of a class, see `Resolver`.
+pub type Reconstruct = dyn Fn(value::Object) -> Result<value::Value> + Send + Sync;
+
+/// Resolves module globals referenced by a pickle, similar to overriding
+/// `Unpickler.find_class` in Python.
+///
+/// This is consulted before the builtin handling of globals (such as `set` or
+/// `_codecs.encode`), and can be implemented by closures taking the module
+/// and name.
+pub trait Resolver: Send + Sync {
+    /// Look up the global `module.name`.
+    ///
+    /// If this returns a callback, every object created by calling the global
+    /// (or its `__new__`) is collected as a `value::Object`, with arguments,
+    /// items and the state set by `BUILD`, and passed to the callback once the
+    /// pickle has been decoded.  The callback's result is used in its place.
+    ///
+    /// If this returns `None`, the global is handled as usual.
+    fn find_class(&self, module: &str, name: &str) -> Option<Box<Reconstruct>>;
+}
+
+impl<F> Resolver for F where F: Fn(&str, &str) -> Option<Box<Reconstruct>> + Send + Sync {
+    fn find_class(&self, module: &str, name: &str) -> Option<Box<Reconstruct>> {
+        self(module, name)
+    }
+}
+
+/// How strings pickled by Python 2 (`str` objects, saved with the `STRING`
+/// opcodes) are decoded, see `DeOptions::string_encoding`.
+///
+/// This corresponds to the `encoding` and `errors` arguments of Python 3's
+/// `pickle.load`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StringEncoding {
+    /// Keep them as bytes objects, like `encoding="bytes"`.
+    Bytes,
+    /// Decode them as UTF-8, failing with `ErrorCode::StringNotUTF8` for
+    /// invalid data.
+    Utf8,
+    /// Decode them as UTF-8, replacing invalid data by U+FFFD, like
+    /// `errors="replace"`.
+    Utf8Lossy,
+    /// Decode them as Latin-1, like `encoding="latin1"`.  This is required
+    /// for pickles of numpy arrays and `datetime` objects from Python 2.
+    Latin1,
+    /// Decode them as UTF-8, or as Latin-1 if they are not valid UTF-8.
+    Utf8OrLatin1,
+}
+
+// Deriving this needs `#[default]`, which is too new for our MSRV.
+#[allow(clippy::derivable_impls)]
+impl Default for StringEncoding {
+    fn default() -> Self {
+        StringEncoding::Bytes
+    }
+}
+
+/// Callback type for custom global policies, see `GlobalPolicy::predicate`.
+type GlobalPredicate = dyn Fn(&str, &str) -> bool + Send + Sync;
+
+/// Policy that decides which module globals a pickle may reference, see
+/// `DeOptions::global_policy`.
+///
+/// Referencing other globals fails with `ErrorCode::DisallowedGlobal`.  The
+/// builtins that are decoded into plain data (`set`, `frozenset`, `list`,
+/// `int` and `bytearray` from `builtins` or `__builtin__`, and
+/// `_codecs.encode`) are always allowed.
+#[derive(Clone, Debug)]
+pub struct GlobalPolicy {
+    rule: PolicyRule,
+}
+
+#[derive(Clone, Debug)]
+enum PolicyRule {
+    AllowAll,
+    Allowlist(Vec<(String, String)>),
+    Predicate(Hook<GlobalPredicate>),
+}
+
+impl Default for GlobalPolicy {
+    fn default() -> Self {
+        GlobalPolicy::allow_all()
+    }
+}
+
+impl GlobalPolicy {
+    /// Allow all globals (the default).
+    pub fn allow_all() -> Self {
+        GlobalPolicy { rule: PolicyRule::AllowAll }
+    }
+
+    /// Deny all globals, except for the builtins mentioned above.
+    pub fn deny_all() -> Self {
+        GlobalPolicy { rule: PolicyRule::Allowlist(Vec::new()) }
+    }
+
+    /// Allow only the given globals, as pairs of module and (qualified) name.
+    ///
+    /// A name of `"*"` allows all globals from the module, and a module of
+    /// the form `"package.*"` matches the package and all its submodules.
+    pub fn allowlist<'a, I>(globals: I) -> Self
+        where I: IntoIterator<Item = (&'a str, &'a str)>
+    {
+        let list = globals.into_iter().map(|(m, n)| (m.into(), n.into())).collect();
+        GlobalPolicy { rule: PolicyRule::Allowlist(list) }
+    }
+
+    /// Decide using a callback, which gets the module and name of the global
+    /// and returns whether it is allowed.
+    pub fn predicate<F>(callback: F) -> Self
+        where F: Fn(&str, &str) -> bool + Send + Sync + 'static
+    {
+        GlobalPolicy { rule: PolicyRule::Predicate(Hook(Arc::new(callback))) }
+    }
+
+    /// Check if the global `module.name` is allowed by this policy.
+    pub fn allows(&self, module: &str, name: &str) -> bool {
+        match self.rule {
+            PolicyRule::AllowAll => true,
+            PolicyRule::Allowlist(ref list) => list.iter().any(|(m, n)| {
+                let module_matches = if m.ends_with(".*") {
+                    let package = &m[..m.len() - 2];
+                    module == package || (module.starts_with(package) &&
+                                          module[package.len()..].starts_with('.'))
+                } else {
+                    module == m
+                };
+                module_matches && (n == "*" || n == name)
+            }),
+            PolicyRule::Predicate(ref callback) => callback(module, name),
+        }
+    }
+}
+
+/// Resource limits, see the `DeOptions::max_*` methods.
+#[derive(Clone, Debug, Default)]
+struct Limits {
+    depth: Option<usize>,
+    length: Option<usize>,
+    memo: Option<usize>,
+    marks: Option<usize>,
+    allocation: Option<usize>,
+}
+
+/// Callback type for resolving persistent ids, see `DeOptions::persistent_load`.
+type PersistentLoad = dyn Fn(value::Value) -> Result<value::Value> + Send + Sync;
+
+/// Options for deserializing.
+#[derive(Clone, Debug, Default)]
+pub struct DeOptions {
+    string_encoding: StringEncoding,
+    replace_unresolved_globals: bool,
+    decode_recursive: bool,
+    decode_objects: bool,
+    stream_containers: bool,
+    keep_memo: bool,
+    recover: bool,
+    resolver: Option<Hook<dyn Resolver>>,
+    global_policy: GlobalPolicy,
+    limits: Limits,
+    persistent_load: Option<Hook<PersistentLoad>>,
+    extensions: BTreeMap<u32, (String, String)>,
+}
+
+impl DeOptions {
+    /// Construct with default options:
+    ///
+    /// - keep strings saved as STRING opcodes (only protocols 0-2) as bytes
+    /// - don't replace unresolvable globals by `None`
+    /// - reject recursive structures
+    /// - don't keep instances of arbitrary classes
+    /// - build the complete value before visiting it
+    /// - drop memoized values after their last reference
+    /// - fail on truncated or corrupted pickles
+    /// - no custom resolution of globals
+    /// - allow all globals
+    /// - no resource limits, except for a nesting depth of 256
+    /// - reject persistent ids
+    /// - no registered extension codes
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Activate decoding strings saved as STRING as UTF-8.
+    ///
+    /// This is the same as `string_encoding(StringEncoding::Utf8)`.
+    pub fn decode_strings(self) -> Self {
+        self.string_encoding(StringEncoding::Utf8)
+    }
+
+    /// Set how strings saved as STRING (only protocols 0-2) are decoded.
+    pub fn string_encoding(mut self, encoding: StringEncoding) -> Self {
+        self.string_encoding = encoding;
+        self
+    }
+
+    /// Activate replacing unresolved globals by `None`.
+    pub fn replace_unresolved_globals(mut self) -> Self {
+        self.replace_unresolved_globals = true;
+        self
+    }
+
+    /// Activate decoding recursive structures into `Value`.
+    ///
+    /// A value that contains references to itself, such as a list that
+    /// contains itself or a parent object pointed to by its children, is then
+    /// wrapped in `Value::Shared` with an id, and the references within are
+    /// decoded as `Value::Ref` with the same id.  Values that are referenced
+    /// multiple times without forming a cycle are still copied.
+    ///
+    /// This only applies to the `value_from_*` functions; deserializing into
+    /// other types still fails with `ErrorCode::Recursive`.
+    pub fn decode_recursive(mut self) -> Self {
+        self.decode_recursive = true;
+        self
+    }
+
+    /// Activate decoding instances of arbitrary classes into `Value::Object`.
+    ///
+    /// By default, the class and constructor arguments of such instances are
+    /// discarded and the instance is replaced by its state (usually the
+    /// `__dict__`), or an empty dict if there is no state.  With this option,
+    /// the class name, arguments and state are kept, which also allows writing
+    /// the object back faithfully.
+    ///
+    /// When deserializing into other types than `Value`, objects are still
+    /// replaced by their state.
+    pub fn decode_objects(mut self) -> Self {
+        self.decode_objects = true;
+        self
+    }
+
+    /// Activate streaming lists and dicts directly to the serde visitor.
+    ///
+    /// Normally the whole pickle is decoded before the result is visited.
+    /// With this option, a list or dict that is deserialized as a sequence,
+    /// map or struct is visited while its items are decoded from the stream,
+    /// so that large collections are never held in memory twice.
+    ///
+    /// Since opcodes are postfix, a container could still be referenced,
+    /// modified or wrapped in another value after its items have been
+    /// decoded.  So before anything is streamed, the rest of the pickle is
+    /// checked, and containers that are used in any of these ways are
+    /// decoded in full before being visited.  The result is always the same
+    /// as without this option.  The check scans the opcodes of the rest of
+    /// the pickle without decoding them, so it needs the rest of the pickle
+    /// in memory: when decoding from a reader, its raw data is read ahead up
+    /// to the `STOP` opcode and kept until it is decoded.  At most 1 MiB is
+    /// read ahead; in larger pickles, and if reading ahead would exceed the
+    /// length or allocation limits, nothing is streamed and the read data is
+    /// decoded as usual.
+    ///
+    /// After an item that is not streamed, the rest of its batch of items (up
+    /// to 1000 in pickles written by Python or this crate) is also decoded in
+    /// full, since the start of each item isn't marked.
+    pub fn stream_containers(mut self) -> Self {
+        self.stream_containers = true;
+        self
+    }
+
+    /// Keep all memoized values until the end of decoding.
+    ///
+    /// Like with Python's `Unpickler`, a `Deserializer` can then read a
+    /// stream of pickles that refer to values memoized by earlier ones, as
+    /// written by `Serializer::dump` with `SerOptions::memoize`.  Otherwise,
+    /// values are dropped from the memo after their last reference in the
+    /// pickle, and referenced values are moved instead of copied.
+    pub fn keep_memo(mut self) -> Self {
+        self.keep_memo = true;
+        self
+    }
+
+    /// Activate best-effort recovery of truncated or corrupted pickles.
+    ///
+    /// When decoding fails because the input ends early, or because of
+    /// invalid data such as an unknown opcode, the partially decoded value is
+    /// returned instead of the error: items after an open `MARK` are added to
+    /// the container below it (or collected in a tuple), and the remaining
+    /// items on the stack are added to the containers below them.  What was
+    /// recovered is reported by `Deserializer::recovery`, and the rest of the
+    /// input is ignored.
+    ///
+    /// Resource limits, the global policy and other options are still
+    /// enforced.  Recovery doesn't apply to streamed containers (see
+    /// `stream_containers`).
+    pub fn recover(mut self) -> Self {
+        self.recover = true;
+        self
+    }
+
+    /// Set a resolver for module globals, which can provide custom
+    /// reconstruction of class instances.  See `Resolver` for details.
+    pub fn resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Some(Hook(Arc::new(resolver)));
+        self
+    }
+
+    /// Set the policy for module globals referenced by the pickle.
+    ///
+    /// When loading pickles from untrusted sources, this can be used to make
+    /// sure that they only contain the expected types.
+    pub fn global_policy(mut self, policy: GlobalPolicy) -> Self {
+        self.global_policy = policy;
+        self
+    }
+
+    /// Limit the nesting depth of decoded values (`ErrorCode::DepthLimit`).
+    /// Values that are not containers have a depth of 1.
+    ///
+    /// The default limit is 256, which fits into a thread stack of 2 MiB even
+    /// in debug builds.  Decoded values are converted and visited recursively,
+    /// so a higher limit may need a larger stack.  Deeper nesting is rejected
+    /// without overflowing the stack.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.limits.depth = Some(depth);
+        self
+    }
+
+    /// Limit the length in bytes of a single string, bytes object, integer
+    /// or other literal in the stream (`ErrorCode::LengthLimit`).
+    pub fn max_length(mut self, length: usize) -> Self {
+        self.limits.length = Some(length);
+        self
+    }
+
+    /// Limit the number of entries in the memo (`ErrorCode::MemoLimit`).
+    pub fn max_memo_size(mut self, entries: usize) -> Self {
+        self.limits.memo = Some(entries);
+        self
+    }
+
+    /// Limit the number of nested `MARK`s (`ErrorCode::MarkLimit`).
+    pub fn max_mark_depth(mut self, depth: usize) -> Self {
+        self.limits.marks = Some(depth);
+        self
+    }
+
+    /// Limit the total memory allocated for decoding a pickle, in bytes
+    /// (`ErrorCode::AllocationLimit`).
+    ///
+    /// This is an estimate, counting the string data and values created
+    /// while parsing the stream and converting the result.  Values that are
+    /// referenced multiple times via the memo count for every reference, since
+    /// they are copied.
+    pub fn max_allocation(mut self, bytes: usize) -> Self {
+        self.limits.allocation = Some(bytes);
+        self
+    }
+
+    /// Set a callback that resolves persistent ids (the `PERSID` and
+    /// `BINPERSID` opcodes) to the objects they refer to.
+    ///
+    /// This corresponds to `Unpickler.persistent_load` in Python: the callback
+    /// gets the persistent id, which was written by the pickler's
+    /// `persistent_id` method, and returns the value to use in its place.
+    /// Without a callback, persistent ids are rejected.
+    pub fn persistent_load<F>(mut self, callback: F) -> Self
+        where F: Fn(value::Value) -> Result<value::Value> + Send + Sync + 'static
+    {
+        self.persistent_load = Some(Hook(Arc::new(callback)));
+        self
+    }
+
+    /// Register an extension code for a module global.
+    ///
+    /// This corresponds to `copyreg.add_extension` in Python: pickles can
+    /// refer to registered globals using the `EXT` opcodes and the code, which
+    /// is then resolved like the global `module.name`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the code is not in the range `1..=0x7fff_ffff`, like Python.
+    pub fn extension(mut self, module: &str, name: &str, code: u32) -> Self {
+        assert!((1..=0x7fff_ffff).contains(&code), "extension code out of range");
+        self.extensions.insert(code, (module.into(), name.into()));
+        self
+    }
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// The input of a `Deserializer`.
+///
+/// This trait is sealed.  It is implemented for all `BufRead` types, and for
+/// `SliceRead`, which allows strings and bytes to be borrowed from the input.
+pub trait Input<'de>: private::Sealed {
+    #[doc(hidden)]
+    type Reader: BufRead;
+
+    #[doc(hidden)]
+    fn reader(&mut self) -> &mut Self::Reader;
+
+    /// Consume `n` bytes and return their range in the input, if they can be
+    /// borrowed.
+    #[doc(hidden)]
+    fn read_borrowed(&mut self, n: u64) -> Option<(usize, usize)>;
+
+    /// Return a range previously returned by `read_borrowed`.
+    #[doc(hidden)]
+    fn borrowed(&self, start: usize, end: usize) -> &'de [u8];
+
+    /// Return the rest of the input, if it is all in memory.
+    #[doc(hidden)]
+    fn rest(&self) -> Option<&[u8]>;
+}
+
+impl<T: BufRead> private::Sealed for T {}
+
+impl<'de, T: BufRead> Input<'de> for T {
+    type Reader = T;
+
+    fn reader(&mut self) -> &mut T {
+        self
+    }
+
+    fn read_borrowed(&mut self, _: u64) -> Option<(usize, usize)> {
+        None
+    }
+
+    fn borrowed(&self, _: usize, _: usize) -> &'de [u8] {
+        unreachable!("nothing is borrowed from a reader")
+    }
+
+    fn rest(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Input for a `Deserializer` that borrows from a byte slice.
+///
+/// See `Deserializer::from_slice`.
+pub struct SliceRead<'de> {
+    data: &'de [u8],
+    rest: &'de [u8],
+}
+
+impl<'de> SliceRead<'de> {
+    /// Return the part of the slice that hasn't been read yet.
+    pub fn remaining(&self) -> &'de [u8] {
+        self.rest
+    }
+}
+
+impl<'de> private::Sealed for SliceRead<'de> {}
+
+impl<'de> Input<'de> for SliceRead<'de> {
+    type Reader = &'de [u8];
+
+    fn reader(&mut self) -> &mut &'de [u8] {
+        &mut self.rest
+    }
+
+    fn read_borrowed(&mut self, n: u64) -> Option<(usize, usize)> {
+        if n > self.rest.len() as u64 {
+            return None;
+        }
+        let start = self.data.len() - self.rest.len();
+        self.rest = &self.rest[n as usize..];
+        Some((start, start + n as usize))
+    }
+
+    fn borrowed(&self, start: usize, end: usize) -> &'de [u8] {
+        &self.data[start..end]
+    }
+
+    fn rest(&self) -> Option<&[u8]> {
+        Some(self.rest)
+    }
+}
+
+/// Input buffer for data that arrives in chunks.
+///
+/// Reading from it fails with `WouldBlock` when the buffered data is exhausted
+/// before the end of input was signaled.  The `Deserializer` then rewinds to
+/// the start of the current opcode, which is retried once more data has been
+/// appended.  For opcodes with a length prefix, the retry waits until all of
+/// the data is buffered, so that long strings are not read over and over.
+pub(crate) struct Feed {
+    buf: Vec<u8>,
+    base: usize,  // stream position of buf[0]
+    pos: usize,   // read position in buf
+    eof: bool,
+}
+
+impl Feed {
+    fn new() -> Self {
+        Feed { buf: Vec::new(), base: 0, pos: 0, eof: false }
+    }
+
+    // Append data, dropping what was read before the given stream position.
+    fn push(&mut self, data: &[u8], keep_from: usize) {
+        let done = keep_from - self.base;
+        self.buf.drain(..done);
+        self.base = keep_from;
+        self.pos -= done;
+        self.buf.extend_from_slice(data);
+    }
+
+    fn rewind(&mut self, pos: usize) {
+        self.pos = pos - self.base;
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    // Stream position of the end of the buffered data.
+    fn end(&self) -> usize {
+        self.base + self.buf.len()
+    }
+}
+
+impl Read for Feed {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let data = self.fill_buf()?;
+            let n = data.len().min(out.len());
+            out[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Feed {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() && !self.eof {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+    }
+}
+
+/// Maximum number of bytes read ahead from a reader to check which
+/// containers can be streamed.  Containers in larger pickles are decoded in
+/// full.
+const MAX_READ_AHEAD: usize = 1 << 20;
+
+/// Data read ahead from a reader to check which containers can be streamed,
+/// see `Deserializer::plan_streaming`.  It is decoded before reading on.
+#[derive(Default)]
+struct ReadAhead {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadAhead {
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    // Mark data as read, freeing the buffer once all of it is.
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+        if self.is_empty() {
+            *self = ReadAhead::default();
+        }
+    }
+}
+
+/// The reader of a `Deserializer`, after the data it has read ahead.
+struct Reading<'a, T> {
+    ahead: &'a mut ReadAhead,
+    rdr: &'a mut T,
+}
+
+impl<'a, T: BufRead> Read for Reading<'a, T> {
+    #[inline]
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.ahead.is_empty() {
+            return self.rdr.read(out);
+        }
+        let n = (&self.ahead.buf[self.ahead.pos..]).read(out)?;
+        self.ahead.consume(n);
+        Ok(n)
+    }
+}
+
+impl<'a, T: BufRead> BufRead for Reading<'a, T> {
+    #[inline]
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.ahead.is_empty() {
+            return self.rdr.fill_buf();
+        }
+        Ok(&self.ahead.buf[self.ahead.pos..])
+    }
+
+    #[inline]
+    fn consume(&mut self, n: usize) {
+        if self.ahead.is_empty() {
+            self.rdr.consume(n);
+        } else {
+            self.ahead.consume(n);
+        }
+    }
+}
+
+/// Decodes pickle streams into values.
+///
+/// The second type parameter is the buffered reader actually used.  By
+/// default, the input is wrapped in a `BufReader` (see `Deserializer::new`),
+/// which may read ahead beyond the end of the pickle.  With
+/// `Deserializer::from_buf_read`, a caller-provided `BufRead` is used as is,
+/// and no bytes after the `STOP` opcode are consumed.  With
+/// `Deserializer::from_slice`, strings can be borrowed from the input.
+pub struct Deserializer<R: Read, B = BufReader<R>> {
+    rdr: B,
+    options: DeOptions,
+    pos: usize,
+    value: Option<Value>,                  // next value to deserialize
+    memo: Memo,                            // pickle memo (value, number of refs)
+    stack: Vec<Value>,                     // topmost items on the stack
+    stacks: Vec<Vec<Value>>,               // items further down the stack, between MARKs
+    keep_memo: bool,                       // don't use up memo references when resolving
+    converting: BTreeMap<MemoId, bool>,    // memo entries being converted (referenced within?)
+    visiting: BTreeSet<MemoId>,            // shared memo entries being converted or visited
+    depth: usize,                          // nesting depth while converting
+    allocated: usize,                      // estimated memory allocated for the current pickle
+    buffers: Option<Box<dyn Iterator<Item = Vec<u8>> + Send>>,  // out-of-band buffers
+    readonly: Vec<bool>,                   // for each buffer taken, if it is read-only
+    buffer_end: Option<usize>,             // position after the last NEXT_BUFFER
+    checkpoint: (usize, usize),            // position and allocation before current opcode
+    wanted: usize,                         // position up to which input is needed to retry
+    streams: Vec<Stream>,                  // containers currently streamed to the visitor
+    streamable: Option<(usize, Vec<usize>)>,  // pickle start, offsets of streamable containers
+    ahead: ReadAhead,                      // data read ahead to plan streaming
+    tracer: Option<Box<Tracer>>,           // called for every executed opcode
+    recovery: Option<Recovery>,            // report if the last pickle was recovered
+    raw: Option<Vec<u8>>,                  // bytes of the current opcode, while tracing
+    _reader: PhantomData<R>,
+}
+
+/// Callback for tracing opcodes, see `Deserializer::set_trace`.
+type Tracer = dyn FnMut(&Trace) + Send;
+
+/// A value in the memo.
+///
+/// Values that are referenced more than once while deserializing are shared
+/// with the memo, and only copied when they are converted.
+enum Memoized {
+    Value(Value),
+    Shared(Arc<Value>),
+}
+
+impl Memoized {
+    fn get(&self) -> &Value {
+        match *self {
+            Memoized::Value(ref value) => value,
+            Memoized::Shared(ref value) => value,
+        }
+    }
+
+    // Get the value for modification, which copies it if it is shared.
+    fn get_mut(&mut self) -> &mut Value {
+        match *self {
+            Memoized::Value(ref mut value) => value,
+            Memoized::Shared(ref mut value) => Arc::make_mut(value),
+        }
+    }
+
+    fn share(&mut self) -> Arc<Value> {
+        if let Memoized::Value(ref mut value) = *self {
+            *self = Memoized::Shared(Arc::new(mem::replace(value, Value::None)));
+        }
+        match *self {
+            Memoized::Shared(ref value) => value.clone(),
+            Memoized::Value(_) => unreachable!(),
+        }
+    }
+
+    // Drop the value without recursion, unless it is still shared.
+    fn dismantle(self) {
+        match self {
+            Memoized::Value(value) => value.dismantle(),
+            Memoized::Shared(value) => if let Ok(value) = Arc::try_unwrap(value) {
+                value.dismantle();
+            },
+        }
+    }
+
+    // Take the value, copying it if it is still shared.
+    fn into_value(self) -> Value {
+        match self {
+            Memoized::Value(value) => value,
+            Memoized::Shared(value) => Arc::try_unwrap(value).unwrap_or_else(|shared| Value::clone(&shared)),
+        }
+    }
+}
+
+/// The pickle memo.
+///
+/// Memoized values are dropped without recursion, see `Value::dismantle`.
+struct Memo(BTreeMap<MemoId, (Memoized, i32)>);
+
+impl Memo {
+    fn clear(&mut self) {
+        for (_, (value, _)) in mem::take(&mut self.0) {
+            value.dismantle();
+        }
+    }
+}
+
+impl Deref for Memo {
+    type Target = BTreeMap<MemoId, (Memoized, i32)>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Memo {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for Memo {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// A list or dict that is being visited while it is decoded.
+///
+/// Its items are added by batches of `MARK` ... `APPENDS`/`SETITEMS`.  Items
+/// that can't be streamed themselves are decoded on the stack and queued.
+struct Stream {
+    pending: VecDeque<Value>,  // decoded items not visited yet
+    marks: Option<usize>,      // number of MARKs while in a batch
+    close: u8,                 // opcode that ends a batch
+}
+
+/// Where a value on the stack comes from, while planning which containers
+/// can be streamed.
+#[derive(Clone, Copy)]
+enum Origin {
+    Other,
+    Memo,              // pushed from the memo, may have been visited already
+    Container(usize),  // an empty list or dict, i.e. a streaming candidate
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Candidate {
+    Open,      // items may be added in batches
+    Closed,    // something was pushed on top of it
+    Consumed,  // added to its parent by a batch, or returned at STOP
+    Rejected,  // used in any other way
+}
+
+/// Symbolic execution of a pickle, see `plan_streaming`.
+///
+/// Methods return `None` if the pickle is invalid, or if nothing can be
+/// streamed.
+struct Planner {
+    candidates: Vec<(usize, u8, Candidate)>,  // offset, opcode that adds items, state
+    stack: Vec<Origin>,
+    marks: Vec<usize>,                        // stack lengths at each MARK
+}
+
+impl Planner {
+    // The stack top, unless a MARK is on top.
+    fn top(&self) -> Option<Origin> {
+        if self.marks.last() == Some(&self.stack.len()) {
+            return None;
+        }
+        self.stack.last().cloned()
+    }
+
+    fn reject(&mut self, origin: Origin) {
+        if let Origin::Container(i) = origin {
+            self.candidates[i].2 = Candidate::Rejected;
+        }
+    }
+
+    // A candidate on the stack top can't get any more items.
+    fn close_top(&mut self) {
+        if let Some(Origin::Container(i)) = self.top() {
+            if self.candidates[i].2 == Candidate::Open {
+                self.candidates[i].2 = Candidate::Closed;
+            }
+        }
+    }
+
+    fn push(&mut self, origin: Origin) {
+        self.close_top();
+        self.stack.push(origin);
+    }
+
+    // Pop values for use by an opcode.
+    fn pop(&mut self, n: usize) -> Option<()> {
+        let floor = self.marks.last().cloned().unwrap_or(0);
+        if self.stack.len() < floor + n {
+            return None;
+        }
+        let start = self.stack.len() - n;
+        for origin in self.stack.split_off(start) {
+            self.reject(origin);
+        }
+        Some(())
+    }
+
+    // Modify the stack top in place.  Values from the memo may have been
+    // visited already.
+    fn modify(&mut self) -> Option<()> {
+        match self.top()? {
+            Origin::Memo => return None,
+            origin => self.reject(origin),
+        }
+        Some(())
+    }
+
+    // Pop the values up to the last MARK.  For APPENDS and SETITEMS, they
+    // are added to the candidate below, which is filled by a batch.
+    fn pop_mark(&mut self, op: u8) -> Option<()> {
+        let mark = self.marks.pop()?;
+        let items = self.stack.split_off(mark);
+        let odd = items.len() % 2 == 1;
+        let target = self.stack.last().cloned();
+        let batch = op == APPENDS || op == SETITEMS;
+        for origin in items {
+            match origin {
+                Origin::Container(i) if batch && self.candidates[i].2 != Candidate::Rejected =>
+                    self.candidates[i].2 = Candidate::Consumed,
+                _ => self.reject(origin),
+            }
+        }
+        match target {
+            Some(Origin::Container(i)) => {
+                let (_, adds, state) = self.candidates[i];
+                // A key without a value is dropped, which isn't streamed.
+                if adds != op || state != Candidate::Open || (op == SETITEMS && odd) {
+                    self.reject(Origin::Container(i));
+                }
+            }
+            Some(Origin::Memo) if batch || op == ADDITEMS => return None,
+            None if batch || op == ADDITEMS => return None,
+            _ => {}
+        }
+        Some(())
+    }
+}
+
+/// Opcodes and their arguments, as seen by `plan_streaming`.
+///
+/// Methods return `None` at the end of the input, or if it can't be read.
+trait Scan {
+    // Offset of the next opcode from the start of the scan.
+    fn offset(&self) -> usize;
+
+    fn take(&mut self, n: usize) -> Option<&[u8]>;
+
+    // A line, without the line ending.
+    fn line(&mut self) -> Option<&[u8]>;
+
+    // Data with a little-endian length prefix of `n` bytes.
+    fn counted(&mut self, n: usize) -> Option<&[u8]> {
+        let len = usize::try_from(LittleEndian::read_uint(self.take(n)?, n)).ok()?;
+        self.take(len)
+    }
+}
+
+fn strip_line_ending(line: &[u8]) -> &[u8] {
+    match line.last() {
+        Some(&b'\r') => &line[..line.len() - 1],
+        _ => line,
+    }
+}
+
+/// Scans a pickle in memory.
+struct SliceScan<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scan for SliceScan<'a> {
+    fn offset(&self) -> usize {
+        self.pos
+    }
+
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let arg = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(arg)
+    }
+
+    fn line(&mut self) -> Option<&[u8]> {
+        let len = self.data[self.pos..].iter().position(|&b| b == b'\n')?;
+        let line = &self.take(len + 1)?[..len];
+        Some(strip_line_ending(line))
+    }
+}
+
+/// Scans a pickle from a reader, keeping what was read in the `ReadAhead`
+/// buffer so that it can be decoded afterwards.
+///
+/// Reading stops at `MAX_READ_AHEAD` and at the length and allocation
+/// limits, which are enforced once the buffered data is decoded.
+struct ReaderScan<'a, R> {
+    rdr: &'a mut R,
+    ahead: &'a mut ReadAhead,
+    start: usize,                  // position in the buffer where the scan started
+    pos: usize,                    // position in the buffer
+    max_length: Option<usize>,
+    max_buffered: usize,
+}
+
+impl<'a, R: BufRead> ReaderScan<'a, R> {
+    // Check that the buffer doesn't hold more than allowed.
+    fn check_buffered(&self, end: usize) -> Option<()> {
+        if end - self.start > self.max_buffered {
+            return None;
+        }
+        Some(())
+    }
+}
+
+impl<'a, R: BufRead> Scan for ReaderScan<'a, R> {
+    fn offset(&self) -> usize {
+        self.pos - self.start
+    }
+
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let end = self.pos.checked_add(n)?;
+        if end > self.ahead.buf.len() {
+            self.check_buffered(end)?;
+            let missing = (end - self.ahead.buf.len()) as u64;
+            self.rdr.by_ref().take(missing).read_to_end(&mut self.ahead.buf).ok()?;
+            if end > self.ahead.buf.len() {
+                return None;
+            }
+        }
+        let arg = &self.ahead.buf[self.pos..end];
+        self.pos = end;
+        Some(arg)
+    }
+
+    fn line(&mut self) -> Option<&[u8]> {
+        let mut len = self.ahead.buf[self.pos..].iter().position(|&b| b == b'\n');
+        if len.is_none() {
+            // Don't read further than the line ending after the length limit,
+            // or than one byte past the buffer limit.
+            let room = self.max_buffered - (self.ahead.buf.len() - self.start) + 1;
+            let max = match self.max_length {
+                Some(max) => cmp::min(max.saturating_add(2), room),
+                None => room,
+            };
+            self.rdr.by_ref().take(max as u64).read_until(b'\n', &mut self.ahead.buf).ok()?;
+            self.check_buffered(self.ahead.buf.len())?;
+            len = self.ahead.buf[self.pos..].iter().position(|&b| b == b'\n');
+        }
+        let len = len?;
+        let line = &self.take(len + 1)?[..len];
+        Some(strip_line_ending(line))
+    }
+
+    fn counted(&mut self, n: usize) -> Option<&[u8]> {
+        let len = usize::try_from(LittleEndian::read_uint(self.take(n)?, n)).ok()?;
+        if let Some(max) = self.max_length {
+            if len > max {
+                return None;
+            }
+        }
+        self.take(len)
+    }
+}
+
+// Find the containers in a pickle that can be streamed, i.e. visited while
+// they are decoded, with the same result as decoding them in full.  These
+// are the lists and dicts that are memoized only right after their creation
+// (and never referenced from the memo), filled only by MARK ... APPENDS or
+// SETITEMS batches, and then added to their parent by a batch, or returned
+// at STOP.  Memoized containers can't be streamed unless `memoize` is set.
+//
+// Returns the offsets of their opcodes from the start of the scan, in
+// ascending order.  If the pickle is incomplete or invalid, nothing is
+// streamed, and decoding reports the error.
+fn plan_streaming<S: Scan>(src: &mut S, memoize: bool) -> Vec<usize> {
+    let mut planner = Planner { candidates: Vec::new(), stack: Vec::new(), marks: Vec::new() };
+    let mut memo = BTreeMap::new();
+    let mut fresh = None;  // candidate created by the previous opcode
+    let complete = (|| -> Option<()> {
+        loop {
+            let offset = src.offset();
+            let op = src.take(1)?[0];
+            let created = fresh.take();
+            match op {
+                MARK => planner.marks.push(planner.stack.len()),
+                STOP => {
+                    if let (1, true, Some(Origin::Container(i))) =
+                        (planner.stack.len(), planner.marks.is_empty(), planner.top())
+                    {
+                        if planner.candidates[i].2 == Candidate::Open {
+                            planner.candidates[i].2 = Candidate::Consumed;
+                        }
+                    }
+                    return Some(());
+                }
+                FRAME => {
+                    src.take(8)?;
+                    fresh = created;
+                }
+                PROTO => {
+                    src.take(1)?;
+                    planner.close_top();
+                }
+                EMPTY_LIST | EMPTY_DICT => {
+                    let i = planner.candidates.len();
+                    let adds = if op == EMPTY_DICT { SETITEMS } else { APPENDS };
+                    planner.candidates.push((offset, adds, Candidate::Open));
+                    planner.push(Origin::Container(i));
+                    fresh = Some(i);
+                }
+                PUT | BINPUT | LONG_BINPUT | MEMOIZE => {
+                    let memo_id = match op {
+                        PUT => str::from_utf8(src.line()?).ok()?.parse().ok()?,
+                        BINPUT => MemoId::from(src.take(1)?[0]),
+                        LONG_BINPUT => LittleEndian::read_u32(src.take(4)?),
+                        _ => memo.len() as MemoId,
+                    };
+                    let target = match planner.top()? {
+                        Origin::Container(i) if memoize && created == Some(i) => Some(i),
+                        origin => {
+                            planner.reject(origin);
+                            None
+                        }
+                    };
+                    memo.insert(memo_id, target);
+                }
+                GET | BINGET | LONG_BINGET => {
+                    let memo_id = match op {
+                        GET => str::from_utf8(src.line()?).ok()?.parse().ok()?,
+                        BINGET => MemoId::from(src.take(1)?[0]),
+                        _ => LittleEndian::read_u32(src.take(4)?),
+                    };
+                    if let Some(&Some(i)) = memo.get(&memo_id) {
+                        planner.reject(Origin::Container(i));
+                    }
+                    planner.push(Origin::Memo);
+                }
+                APPENDS | SETITEMS | ADDITEMS | POP_MARK => planner.pop_mark(op)?,
+                TUPLE | LIST | DICT | FROZENSET | OBJ | INST => {
+                    if op == INST {
+                        src.line()?;
+                        src.line()?;
+                    }
+                    planner.pop_mark(op)?;
+                    planner.push(Origin::Other);
+                }
+                POP => planner.pop(1)?,
+                DUP => {
+                    let top = planner.top()?;
+                    planner.reject(top);
+                    planner.push(top);
+                }
+                APPEND | BUILD => {
+                    planner.pop(1)?;
+                    planner.modify()?;
+                }
+                SETITEM => {
+                    planner.pop(2)?;
+                    planner.modify()?;
+                }
+                READONLY_BUFFER => planner.modify()?,
+                TUPLE1 | TUPLE2 | TUPLE3 | REDUCE | NEWOBJ | NEWOBJ_EX | STACK_GLOBAL |
+                BINPERSID => {
+                    let n = match op {
+                        TUPLE1 | BINPERSID => 1,
+                        TUPLE3 | NEWOBJ_EX => 3,
+                        _ => 2,
+                    };
+                    planner.pop(n)?;
+                    planner.push(Origin::Other);
+                }
+                _ => {
+                    let arg = match op {
+                        NONE | NEWTRUE | NEWFALSE | EMPTY_TUPLE | EMPTY_SET | NEXT_BUFFER => Some(()),
+                        BININT1 | EXT1 => src.take(1).map(drop),
+                        BININT2 | EXT2 => src.take(2).map(drop),
+                        BININT | EXT4 => src.take(4).map(drop),
+                        BINFLOAT => src.take(8).map(drop),
+                        INT | LONG | FLOAT | STRING | UNICODE | PERSID => src.line().map(drop),
+                        GLOBAL => src.line().map(drop).and_then(|()| src.line().map(drop)),
+                        SHORT_BINSTRING | SHORT_BINUNICODE | SHORT_BINBYTES | LONG1 =>
+                            src.counted(1).map(drop),
+                        BINSTRING | BINUNICODE | BINBYTES | LONG4 => src.counted(4).map(drop),
+                        BINUNICODE8 | BINBYTES8 | BYTEARRAY8 => src.counted(8).map(drop),
+                        _ => None,
+                    };
+                    arg?;
+                    planner.push(Origin::Other);
+                }
+            }
+        }
+    })();
+    if complete.is_none() {
+        return Vec::new();
+    }
+    planner.candidates.into_iter().filter(|&(_, _, state)| state == Candidate::Consumed)
+                                  .map(|(offset, _, _)| offset).collect()
+}
+
+/// Report about a pickle decoded with `DeOptions::recover`.
+#[derive(Debug)]
+pub struct Recovery {
+    /// The error that stopped decoding.
+    pub error: Error,
+    /// Offset of the opcode at which decoding stopped.
+    pub offset: usize,
+    /// Number of `MARK`s that were still open, i.e. unfinished containers.
+    pub open_marks: usize,
+    /// Short descriptions of the items that were dropped since there was no
+    /// container to put them in, such as a dict key without value.
+    pub dropped: Vec<String>,
+}
+
+impl<R: Read> Deserializer<R> {
+    /// Construct a new Deserializer.
+    pub fn new(rdr: R, options: DeOptions) -> Deserializer<R> {
+        Deserializer::with_reader(BufReader::new(rdr), options)
+    }
+}
+
+impl<B: BufRead> Deserializer<B, B> {
+    /// Construct a new Deserializer that reads directly from a buffered reader.
+    ///
+    /// Unlike `Deserializer::new`, this doesn't add another layer of
+    /// buffering, so that the reader is never advanced past the `STOP` opcode
+    /// that ends a pickle.  Use `into_inner` to get it back afterwards, e.g.
+    /// to continue reading other data from the same stream.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use std::io::{BufRead, Cursor};
+    /// # use serde_pickle::{Deserializer, DeOptions};
+    /// # use serde::Deserialize;
+    /// let mut data = serde_pickle::to_vec(&42, Default::default()).unwrap();
+    /// data.extend(b"trailer");
+    /// let mut de = Deserializer::from_buf_read(Cursor::new(data), DeOptions::new());
+    /// let value: i32 = Deserialize::deserialize(&mut de).unwrap();
+    /// assert_eq!(value, 42);
+    /// let mut rdr = de.into_inner();
+    /// assert_eq!(rdr.fill_buf().unwrap(), b"trailer");
+    /// ```
+    pub fn from_buf_read(rdr: B, options: DeOptions) -> Deserializer<B, B> {
+        Deserializer::with_reader(rdr, options)
+    }
+}
+
+impl<'de> Deserializer<&'de [u8], SliceRead<'de>> {
+    /// Construct a new Deserializer that reads from a byte slice.
+    ///
+    /// Byte strings and Unicode strings in the binary protocols are not
+    /// copied, but visited with `visit_borrowed_bytes` and
+    /// `visit_borrowed_str`.  This allows deserializing types that borrow
+    /// from the input, e.g. with `#[serde(borrow)]`, and saves allocations.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use serde_pickle::{Deserializer, DeOptions};
+    /// # use serde::Deserialize;
+    /// let data = serde_pickle::to_vec(&("key", 1), Default::default()).unwrap();
+    /// let mut de = Deserializer::from_slice(&data, DeOptions::new());
+    /// let value: (&str, i32) = Deserialize::deserialize(&mut de).unwrap();
+    /// assert_eq!(value, ("key", 1));
+    /// ```
+    pub fn from_slice(data: &'de [u8], options: DeOptions) -> Self {
+        Deserializer::with_reader(SliceRead { data, rest: data }, options)
+    }
 }
 
-/// Options for deserializing.
-#[derive(Clone, Debug, Default)]
-pub struct DeOptions {
-    decode_strings: bool,
-    replace_unresolved_globals: bool,
-}
+impl Deserializer<Feed, Feed> {
+    pub(crate) fn from_feed(options: DeOptions) -> Self {
+        Deserializer::with_reader(Feed::new(), options)
+    }
 
-impl DeOptions {
-    /// Construct with default options:
-    ///
-    /// - don't decode strings saved as STRING opcodes (only protocols 0-2) as UTF-8
-    /// - don't replace unresolvable globals by `None`
-    pub fn new() -> Self {
-        Default::default()
+    // Append input data.
+    pub(crate) fn feed(&mut self, data: &[u8]) {
+        self.rdr.push(data, self.checkpoint.0);
     }
 
-    /// Activate decoding strings saved as STRING.
-    pub fn decode_strings(mut self) -> Self {
-        self.decode_strings = true;
-        self
+    // Signal the end of input.
+    pub(crate) fn feed_eof(&mut self) {
+        self.rdr.eof = true;
     }
 
-    /// Activate replacing unresolved globals by `None`.
-    pub fn replace_unresolved_globals(mut self) -> Self {
-        self.replace_unresolved_globals = true;
-        self
+    // Parse as far as possible with the input fed so far.  Returns true when
+    // the pickle is complete and its value is ready to be deserialized, and
+    // false if more input is needed.
+    pub(crate) fn parse_available(&mut self) -> Result<bool> {
+        if self.value.is_some() {
+            return Ok(true);
+        }
+        if !self.rdr.eof && self.rdr.end() < self.wanted {
+            return Ok(false);
+        }
+        match self.parse_opcodes() {
+            Ok(value) => {
+                self.value = Some(value);
+                Ok(true)
+            }
+            Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::WouldBlock => {
+                let (pos, allocated) = self.checkpoint;
+                self.rdr.rewind(pos);
+                self.pos = pos;
+                self.allocated = allocated;
+                Ok(false)
+            }
+            Err(err) => {
+                self.clear_stack();
+                Err(err)
+            }
+        }
     }
-}
 
-/// Decodes pickle streams into values.
-pub struct Deserializer<R: Read> {
-    rdr: BufReader<R>,
-    options: DeOptions,
-    pos: usize,
-    value: Option<Value>,                  // next value to deserialize
-    memo: BTreeMap<MemoId, (Value, i32)>,  // pickle memo (value, number of refs)
-    stack: Vec<Value>,                     // topmost items on the stack
-    stacks: Vec<Vec<Value>>,               // items further down the stack, between MARKs
+    // Number of bytes fed, but not parsed yet.  Once the pickle is complete,
+    // these follow its STOP opcode.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn unparsed_len(&self) -> usize {
+        self.rdr.buf.len() - self.rdr.pos
+    }
 }
 
-impl<R: Read> Deserializer<R> {
-    /// Construct a new Deserializer.
-    pub fn new(rdr: R, options: DeOptions) -> Deserializer<R> {
+impl<'de, R: Read, B: Input<'de>> Deserializer<R, B> {
+    fn with_reader(rdr: B, options: DeOptions) -> Deserializer<R, B> {
         Deserializer {
-            rdr: BufReader::new(rdr),
+            rdr,
             pos: 0,
             value: None,
-            memo: BTreeMap::new(),
+            memo: Memo(BTreeMap::new()),
             stack: Vec::with_capacity(128),
             stacks: Vec::with_capacity(16),
+            keep_memo: options.keep_memo,
+            converting: BTreeMap::new(),
+            visiting: BTreeSet::new(),
+            depth: 0,
+            allocated: 0,
+            buffers: None,
+            readonly: Vec::new(),
+            buffer_end: None,
             options,
+            checkpoint: (0, 0),
+            wanted: 0,
+            streams: Vec::new(),
+            streamable: None,
+            ahead: ReadAhead::default(),
+            tracer: None,
+            recovery: None,
+            raw: None,
+            _reader: PhantomData,
+        }
+    }
+
+    // The reader, after any data read ahead.
+    #[inline]
+    fn input(&mut self) -> Reading<'_, B::Reader> {
+        Reading { ahead: &mut self.ahead, rdr: self.rdr.reader() }
+    }
+
+    /// Unwrap the underlying buffered reader.
+    ///
+    /// After a pickle has been decoded, and as long as `end` hasn't been
+    /// called, the reader is positioned directly after its `STOP` opcode.
+    pub fn into_inner(self) -> B {
+        self.rdr
+    }
+
+    /// Supply the out-of-band buffers referenced by the pickle.
+    ///
+    /// This corresponds to the `buffers` argument of Python's `Unpickler`:
+    /// every `NEXT_BUFFER` opcode (protocol 5) takes the next buffer from the
+    /// iterator and decodes it as a byte string.  Without buffers, or when
+    /// the iterator is exhausted, decoding fails with `MissingBuffer`.
+    ///
+    /// A following `READONLY_BUFFER` marks the buffer as read-only, which is
+    /// reported by `readonly_buffers`, since `Value` doesn't distinguish
+    /// between mutable and immutable bytes.
+    pub fn set_buffers<I>(&mut self, buffers: I)
+        where I: IntoIterator<Item = Vec<u8>>, I::IntoIter: Send + 'static
+    {
+        self.buffers = Some(Box::new(buffers.into_iter()));
+        self.readonly.clear();
+    }
+
+    /// Return, for each out-of-band buffer taken so far from the ones set with
+    /// `set_buffers`, whether the pickle marked it as read-only.
+    ///
+    /// Python marks the buffers of `bytes` objects as read-only, and those of
+    /// `bytearray`s as writable, see `SerOptions::buffer_callback`.
+    pub fn readonly_buffers(&self) -> &[bool] {
+        &self.readonly
+    }
+
+    /// Set a callback that is called for every opcode that is executed.
+    ///
+    /// The `Trace` passed to it reports the opcode, its argument and offset,
+    /// and the state of the stack and memo before and after the opcode.  It
+    /// is also called for an opcode that fails, before the error is returned.
+    /// When containers are streamed (see `DeOptions::stream_containers`),
+    /// opcodes that end a batch of items are not reported.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use std::sync::{Arc, Mutex};
+    /// # use serde_pickle::{Deserializer, DeOptions};
+    /// let data = serde_pickle::to_vec(&vec![1, 2], Default::default()).unwrap();
+    /// let names = Arc::new(Mutex::new(Vec::new()));
+    /// let mut de = Deserializer::new(&data[..], DeOptions::new());
+    /// let names2 = names.clone();
+    /// de.set_trace(move |trace| names2.lock().unwrap().push(trace.name.unwrap()));
+    /// de.deserialize_value().unwrap();
+    /// assert_eq!(names.lock().unwrap()[..2], ["PROTO", "EMPTY_LIST"]);
+    /// ```
+    pub fn set_trace<F>(&mut self, callback: F)
+        where F: FnMut(&Trace) + Send + 'static
+    {
+        self.tracer = Some(Box::new(callback));
+    }
+
+    /// Execute the next opcode of the pickle.
+    ///
+    /// This drives the pickle machine one opcode at a time, and reports each
+    /// opcode like `set_trace`.  When the `STOP` opcode is reached, the value
+    /// of the pickle is returned as well, and the next step starts a new
+    /// pickle.  This can't be mixed with deserializing a value in the same
+    /// pickle.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use serde_pickle::{Deserializer, DeOptions, Step, Value};
+    /// let data = serde_pickle::to_vec(&42, Default::default()).unwrap();
+    /// let mut de = Deserializer::new(&data[..], DeOptions::new());
+    /// loop {
+    ///     match de.step().unwrap() {
+    ///         Step::Opcode(trace) => println!("{}: {:?}", trace.offset, trace.name),
+    ///         Step::Stop(_, value) => {
+    ///             assert_eq!(value, Value::I64(42));
+    ///             break;
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn step(&mut self) -> Result<Step> {
+        let (result, trace) = self.trace_opcode();
+        let trace = match trace {
+            Some(trace) => trace,
+            None => return result.map(|_| unreachable!()),
+        };
+        match result? {
+            None => Ok(Step::Opcode(trace)),
+            Some(value) => {
+                let value = self.convert_value(value)?;
+                self.allocated = 0;
+                Ok(Step::Stop(trace, value))
+            }
         }
     }
 
+    /// Return the recovery report for the last pickle, if it was truncated or
+    /// corrupted and decoded with `DeOptions::recover`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use serde_pickle::{Deserializer, DeOptions, Value};
+    /// let data = serde_pickle::to_vec(&vec![1, 2, 3], Default::default()).unwrap();
+    /// let truncated = &data[..data.len() - 3];
+    /// let mut de = Deserializer::new(truncated, DeOptions::new().recover());
+    /// let value = de.deserialize_value().unwrap();
+    /// assert_eq!(value, Value::List(vec![Value::I64(1), Value::I64(2)]));
+    /// assert_eq!(de.recovery().unwrap().open_marks, 1);
+    /// ```
+    pub fn recovery(&self) -> Option<&Recovery> {
+        self.recovery.as_ref()
+    }
+
     /// Reset internal state, allowing reading multiple pickle dump calls from
     /// a single stream.
     ///
@@ -181,330 +1628,867 @@ impl<R: Read> Deserializer<R> {
     /// the generic serde `deserialize`, since it preserves some types that are
     /// not in the serde data model, such as big integers.
     pub fn deserialize_value(&mut self) -> Result<value::Value> {
-        let internal_value = self.parse_value()?;
-        self.convert_value(internal_value)
+        let internal_value = self.get_next_value()?;
+        self.convert_value(internal_value).map_err(|err| self.locate(err, None))
     }
 
     /// Get the next value to deserialize, either by parsing the pickle stream
     /// or from `self.value`.
     fn get_next_value(&mut self) -> Result<Value> {
-        match self.value.take() {
+        if let Some(v) = self.value.take() {
+            return Ok(v);
+        }
+        // Within a streamed container, take the next item of the batch.
+        let pending = match self.streams.last() {
+            Some(stream) => stream.pending.len(),
+            None => return self.parse_value(),
+        };
+        if pending == 0 {
+            self.buffer_items(false)?;
+        }
+        match self.streams.last_mut().and_then(|s| s.pending.pop_front()) {
             Some(v) => Ok(v),
-            None => self.parse_value(),
+            None => self.error(ErrorCode::StreamMismatch),
         }
     }
 
     /// Parse a value from the underlying stream.  This will consume the whole
     /// pickle until the STOP opcode.
     fn parse_value(&mut self) -> Result<Value> {
+        self.allocated = 0;
+        self.recovery = None;
+        match self.parse_opcodes() {
+            Err(Error::Eval(code, context)) if self.options.recover && Self::recoverable(&code) =>
+                self.recover(Error::Eval(code, context)),
+            Err(err) => {
+                self.clear_stack();
+                Err(err)
+            }
+            result => result,
+        }
+    }
+
+    // Drop everything on the stack after an error.
+    fn clear_stack(&mut self) {
+        for stack in self.stacks.drain(..).chain(Some(mem::take(&mut self.stack))) {
+            stack.into_iter().for_each(Value::dismantle);
+        }
+        if let Some(value) = self.value.take() {
+            value.dismantle();
+        }
+    }
+
+    // Check if decoding can be recovered after an error, which is the case
+    // for malformed input, but not for violated limits or policies.
+    // (`matches!` is too new for our MSRV.)
+    #[allow(clippy::match_like_matches_macro)]
+    fn recoverable(code: &ErrorCode) -> bool {
+        match *code {
+            ErrorCode::DepthLimit | ErrorCode::LengthLimit(_) | ErrorCode::MemoLimit |
+            ErrorCode::MarkLimit | ErrorCode::AllocationLimit | ErrorCode::DisallowedGlobal(..) |
+            ErrorCode::StreamMismatch => false,
+            _ => true,
+        }
+    }
+
+    // Build the top-level value from what is left on the stack after an error.
+    fn recover(&mut self, error: Error) -> Result<Value> {
+        let offset = match error.context() {
+            Some(&ErrorContext { opcode: Some((_, offset)), .. }) => offset,
+            _ => self.checkpoint.0,
+        };
+        let open_marks = self.stacks.len();
+        let mut dropped = Vec::new();
+        // Close the open MARKs, like APPENDS, SETITEMS or ADDITEMS would, or
+        // like TUPLE if there is no container.
+        while !self.stacks.is_empty() {
+            let mut items = self.pop_mark()?;
+            match self.top() {
+                Ok(&mut Value::List(ref mut list)) => list.extend(items),
+                Ok(&mut Value::Set(ref mut set)) => set.extend(items),
+                Ok(&mut Value::Dict(_)) => {
+                    if items.len() % 2 == 1 {
+                        let key = items.pop().unwrap_or(Value::None);
+                        dropped.push(self.describe(&key));
+                    }
+                    self.modify_dict(|dict| Self::extend_dict(dict, items))?;
+                }
+                _ => self.stack.push(Value::Tuple(items)),
+            }
+        }
+        // Add the remaining items to the container below them, like APPEND
+        // or SETITEM would.
+        while self.stack.len() > 1 {
+            let item = self.pop()?;
+            match self.top() {
+                Ok(&mut Value::List(ref mut list)) => { list.push(item); continue; }
+                Ok(&mut Value::Set(ref mut set)) => { set.push(item); continue; }
+                _ => {}
+            }
+            let key = self.pop()?;
+            if let Ok(&mut Value::Dict(ref mut dict)) = self.top() {
+                dict.push((key, item));
+                continue;
+            }
+            dropped.push(self.describe(&item));
+            item.dismantle();
+            self.stack.push(key);
+        }
+        match self.stack.pop() {
+            Some(value) => {
+                self.recovery = Some(Recovery { error, offset, open_marks, dropped });
+                Ok(value)
+            }
+            None => {
+                self.clear_stack();
+                Err(error)
+            }
+        }
+    }
+
+    // Execute opcodes until STOP.
+    fn parse_opcodes(&mut self) -> Result<Value> {
         loop {
-            match self.read_byte()? {
-                // Specials
-                PROTO => {
-                    // Ignore this, as it is only important for instances (read
-                    // the version byte).
-                    self.read_byte()?;
+            if let Some(value) = self.parse_opcode()? {
+                return Ok(value);
+            }
+        }
+    }
+
+    // Execute a single opcode, returning the result at STOP, and report it
+    // to the tracing callback if there is one.
+    fn parse_opcode(&mut self) -> Result<Option<Value>> {
+        if self.tracer.is_none() {
+            return self.execute_opcode();
+        }
+        let (result, trace) = self.trace_opcode();
+        if let (Some(trace), Some(tracer)) = (trace, self.tracer.as_mut()) {
+            tracer(&trace);
+        }
+        result
+    }
+
+    // Execute a single opcode, and record a trace of it.  No trace is
+    // recorded if no opcode could be read, or if the input ran dry.
+    fn trace_opcode(&mut self) -> (Result<Option<Value>>, Option<Trace>) {
+        let offset = self.pos;
+        let before = self.vm_state();
+        self.raw = Some(Vec::new());
+        let result = self.execute_opcode();
+        let raw = self.raw.take().unwrap_or_default();
+        if let Err(Error::Io(ref err)) = result {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return (result, None);
+            }
+        }
+        let opcode = match raw.first() {
+            Some(&opcode) => opcode,
+            None => return (result, None),
+        };
+        let after = if result.is_ok() { Some(self.vm_state()) } else { None };
+        let trace = Trace {
+            offset,
+            opcode,
+            name: opcode_name(opcode),
+            argument: trace::decode_argument(opcode, &raw[1..]),
+            before,
+            after,
+        };
+        (result, Some(trace))
+    }
+
+    // Summarize the stack and memo for tracing.
+    fn vm_state(&self) -> VmState {
+        // Items from the top down, with None for the marks between levels.
+        let levels = Some(&self.stack).into_iter().chain(self.stacks.iter().rev());
+        let items = levels.enumerate().flat_map(|(i, items)| {
+            let mark = if i > 0 { Some(None) } else { None };
+            mark.into_iter().chain(items.iter().rev().map(Some))
+        });
+        let mut stack_top: Vec<_> = items.take(VmState::SHOWN_ITEMS).map(|item| match item {
+            Some(value) => self.describe(value),
+            None => "MARK".into(),
+        }).collect();
+        stack_top.reverse();
+        VmState {
+            stack_len: self.stack.len() + self.stacks.iter().map(Vec::len).sum::<usize>(),
+            marks: self.stacks.len(),
+            stack_top,
+            memo_len: self.memo.len(),
+        }
+    }
+
+    // Describe a value on the stack for tracing.
+    fn describe(&self, value: &Value) -> String {
+        const SHORT: usize = 20;
+        let bytes = |b: &[u8]| if b.len() > SHORT {
+            format!("bytes({})", b.len())
+        } else {
+            let escaped: String = b.iter().flat_map(|&c| ascii::escape_default(c)).map(char::from).collect();
+            format!("b\"{}\"", escaped)
+        };
+        let string = |s: &str| if s.len() > SHORT {
+            format!("str({})", s.len())
+        } else {
+            format!("{:?}", s)
+        };
+        match *value {
+            Value::MemoRef(id) => match self.memo.get(&id) {
+                Some((value, _)) => format!("{} (memo {})", self.describe(value.get()), id),
+                None => format!("memo {}", id),
+            },
+            Value::Global(ref global) => match *global {
+                Global::Set => "global builtins.set".into(),
+                Global::Frozenset => "global builtins.frozenset".into(),
+                Global::Bytearray => "global builtins.bytearray".into(),
+                Global::List => "global builtins.list".into(),
+                Global::Int => "global builtins.int".into(),
+                Global::Encode => "global _codecs.encode".into(),
+                Global::Other(ref module, ref name) |
+                Global::Custom(ref module, ref name, _) => format!("global {}.{}", module, name),
+            },
+            Value::Object(ref object) => format!("object {}.{}", object.module, object.name),
+            Value::None => "None".into(),
+            Value::Bool(b) => if b { "True".into() } else { "False".into() },
+            Value::I64(i) => i.to_string(),
+            Value::Int(ref i) => i.to_string(),
+            Value::F64(f) => format!("{:?}", f),
+            Value::Bytes(ref b) => bytes(b),
+            Value::BorrowedBytes(start, end) => bytes(self.rdr.borrowed(start, end)),
+            Value::String(ref s) => string(s),
+            Value::BorrowedString(start, end) => string(self.borrowed_str(start, end)),
+            Value::List(ref v) => format!("list({})", v.len()),
+            Value::Tuple(ref v) => format!("tuple({})", v.len()),
+            Value::Set(ref v) => format!("set({})", v.len()),
+            Value::FrozenSet(ref v) => format!("frozenset({})", v.len()),
+            Value::Dict(ref v) => format!("dict({})", v.len()),
+            Value::Shared(ref value) => self.describe(value),
+            Value::Apply(ref global, ref value) => format!("{}({})", global.builtin_name(), self.describe(value)),
+        }
+    }
+
+    // Execute a single opcode, returning the result at STOP.  Every opcode
+    // reads all its arguments before changing any state, so that it can be
+    // retried from the checkpoint if the input ran dry (see `Feed`).
+    fn execute_opcode(&mut self) -> Result<Option<Value>> {
+        self.checkpoint = (self.pos, self.allocated);
+        // Every opcode creates at most one new value.
+        self.account(mem::size_of::<Value>())?;
+        let opcode = self.read_byte()?;
+        self.run_opcode(opcode).map_err(|err| self.locate(err, Some(opcode)))
+    }
+
+    #[inline]
+    fn run_opcode(&mut self, opcode: u8) -> Result<Option<Value>> {
+        match opcode {
+            // Specials
+            PROTO => {
+                // Ignore this, as it is only important for instances (read
+                // the version byte).
+                self.read_byte()?;
+            }
+            FRAME => {
+                // We'll ignore framing. But we still have to gobble up the length.
+                self.read_fixed_8_bytes()?;
+            }
+            STOP => {
+                let value = self.pop()?;
+                if !self.options.keep_memo {
+                    self.release_memo();
                 }
-                FRAME => {
-                    // We'll ignore framing. But we still have to gobble up the length.
-                    self.read_fixed_8_bytes()?;
+                return Ok(Some(value));
+            }
+            MARK => {
+                if let Some(max) = self.options.limits.marks {
+                    if self.stacks.len() >= max {
+                        return self.error(ErrorCode::MarkLimit);
+                    }
                 }
-                STOP => return self.pop(),
-                MARK => {
-                    let stack = mem::replace(&mut self.stack, Vec::with_capacity(128));
-                    self.stacks.push(stack);
+                self.account(128 * mem::size_of::<Value>())?;
+                let stack = mem::replace(&mut self.stack, Vec::with_capacity(128));
+                self.stacks.push(stack);
+            }
+            POP => {
+                if self.stack.is_empty() {
+                    self.pop_mark()?;
+                } else {
+                    self.pop()?;
                 }
-                POP => {
-                    if self.stack.is_empty() {
-                        self.pop_mark()?;
-                    } else {
-                        self.pop()?;
-                    }
-                },
-                POP_MARK => { self.pop_mark()?; },
-                DUP => { let top = self.top()?.clone(); self.stack.push(top); },
+            },
+            POP_MARK => { self.pop_mark()?; },
+            DUP => {
+                let top = match self.stack.last() {
+                    Some(&Value::MemoRef(id)) => return self.push_memo_ref(id).map(|_| None),
+                    Some(top) => top.clone(),
+                    None => return self.error(ErrorCode::StackUnderflow),
+                };
+                let top = self.copy_value(&top);
+                self.stack.push(top);
+            }
+
+            // Memo saving ops
+            PUT => {
+                let bytes = self.read_line()?;
+                let memo_id = self.parse_ascii(bytes)?;
+                self.memoize(memo_id)?;
+            }
+            BINPUT => {
+                let memo_id = self.read_byte()?;
+                self.memoize(memo_id.into())?;
+            }
+            LONG_BINPUT => {
+                let bytes = self.read_fixed_4_bytes()?;
+                let memo_id = LittleEndian::read_u32(&bytes);
+                self.memoize(memo_id)?;
+            }
+            MEMOIZE => {
+                let memo_id = self.memo.len();
+                self.memoize(memo_id as MemoId)?;
+            }
+
+            // Memo getting ops
+            GET => {
+                let bytes = self.read_line()?;
+                let memo_id = self.parse_ascii(bytes)?;
+                self.push_memo_ref(memo_id)?;
+            }
+            BINGET => {
+                let memo_id = self.read_byte()?;
+                self.push_memo_ref(memo_id.into())?;
+            }
+            LONG_BINGET => {
+                let bytes = self.read_fixed_4_bytes()?;
+                let memo_id = LittleEndian::read_u32(&bytes);
+                self.push_memo_ref(memo_id)?;
+            }
+
+            // Singletons
+            NONE => self.stack.push(Value::None),
+            NEWFALSE => self.stack.push(Value::Bool(false)),
+            NEWTRUE => self.stack.push(Value::Bool(true)),
+
+            // ASCII-formatted numbers
+            INT => {
+                let line = self.read_line()?;
+                let val = self.decode_text_int(line)?;
+                self.stack.push(val);
+            }
+            LONG => {
+                let line = self.read_line()?;
+                let long = self.decode_text_long(line)?;
+                self.stack.push(long);
+            }
+            FLOAT => {
+                let line = self.read_line()?;
+                let f = self.parse_ascii(line)?;
+                self.stack.push(Value::F64(f));
+            }
+
+            // ASCII-formatted strings
+            STRING => {
+                let line = self.read_line()?;
+                let string = self.decode_escaped_string(&line)?;
+                self.stack.push(string);
+            }
+            UNICODE => {
+                let line = self.read_line()?;
+                let string = self.decode_escaped_unicode(&line)?;
+                self.stack.push(string);
+            }
+
+            // Binary-coded numbers
+            BINFLOAT => {
+                let bytes = self.read_fixed_8_bytes()?;
+                self.stack.push(Value::F64(BigEndian::read_f64(&bytes)));
+            }
+            BININT => {
+                let bytes = self.read_fixed_4_bytes()?;
+                self.stack.push(Value::I64(LittleEndian::read_i32(&bytes).into()));
+            }
+            BININT1 => {
+                let byte = self.read_byte()?;
+                self.stack.push(Value::I64(byte.into()));
+            }
+            BININT2 => {
+                let bytes = self.read_fixed_2_bytes()?;
+                self.stack.push(Value::I64(LittleEndian::read_u16(&bytes).into()));
+            }
+            LONG1 => {
+                let bytes = self.read_u8_prefixed_bytes()?;
+                let long = self.decode_binary_long(bytes);
+                self.stack.push(long);
+            }
+            LONG4 => {
+                let bytes = self.read_i32_prefixed_bytes()?;
+                let long = self.decode_binary_long(bytes);
+                self.stack.push(long);
+            }
 
-                // Memo saving ops
-                PUT => {
-                    let bytes = self.read_line()?;
-                    let memo_id = self.parse_ascii(bytes)?;
-                    self.memoize(memo_id)?;
-                }
-                BINPUT => {
-                    let memo_id = self.read_byte()?;
-                    self.memoize(memo_id.into())?;
-                }
-                LONG_BINPUT => {
-                    let bytes = self.read_fixed_4_bytes()?;
-                    let memo_id = LittleEndian::read_u32(&bytes);
-                    self.memoize(memo_id)?;
-                }
-                MEMOIZE => {
-                    let memo_id = self.memo.len();
-                    self.memoize(memo_id as MemoId)?;
-                }
-
-                // Memo getting ops
-                GET => {
-                    let bytes = self.read_line()?;
-                    let memo_id = self.parse_ascii(bytes)?;
-                    self.push_memo_ref(memo_id)?;
-                }
-                BINGET => {
-                    let memo_id = self.read_byte()?;
-                    self.push_memo_ref(memo_id.into())?;
-                }
-                LONG_BINGET => {
-                    let bytes = self.read_fixed_4_bytes()?;
-                    let memo_id = LittleEndian::read_u32(&bytes);
-                    self.push_memo_ref(memo_id)?;
-                }
-
-                // Singletons
-                NONE => self.stack.push(Value::None),
-                NEWFALSE => self.stack.push(Value::Bool(false)),
-                NEWTRUE => self.stack.push(Value::Bool(true)),
-
-                // ASCII-formatted numbers
-                INT => {
-                    let line = self.read_line()?;
-                    let val = self.decode_text_int(line)?;
-                    self.stack.push(val);
-                }
-                LONG => {
-                    let line = self.read_line()?;
-                    let long = self.decode_text_long(line)?;
-                    self.stack.push(long);
-                }
-                FLOAT => {
-                    let line = self.read_line()?;
-                    let f = self.parse_ascii(line)?;
-                    self.stack.push(Value::F64(f));
-                }
-
-                // ASCII-formatted strings
-                STRING => {
-                    let line = self.read_line()?;
-                    let string = self.decode_escaped_string(&line)?;
-                    self.stack.push(string);
-                }
-                UNICODE => {
-                    let line = self.read_line()?;
-                    let string = self.decode_escaped_unicode(&line)?;
-                    self.stack.push(string);
-                }
-
-                // Binary-coded numbers
-                BINFLOAT => {
-                    let bytes = self.read_fixed_8_bytes()?;
-                    self.stack.push(Value::F64(BigEndian::read_f64(&bytes)));
-                }
-                BININT => {
-                    let bytes = self.read_fixed_4_bytes()?;
-                    self.stack.push(Value::I64(LittleEndian::read_i32(&bytes).into()));
-                }
-                BININT1 => {
-                    let byte = self.read_byte()?;
-                    self.stack.push(Value::I64(byte.into()));
-                }
-                BININT2 => {
-                    let bytes = self.read_fixed_2_bytes()?;
-                    self.stack.push(Value::I64(LittleEndian::read_u16(&bytes).into()));
-                }
-                LONG1 => {
-                    let bytes = self.read_u8_prefixed_bytes()?;
-                    let long = self.decode_binary_long(bytes);
-                    self.stack.push(long);
-                }
-                LONG4 => {
-                    let bytes = self.read_i32_prefixed_bytes()?;
-                    let long = self.decode_binary_long(bytes);
-                    self.stack.push(long);
-                }
-
-                // Length-prefixed (byte)strings
-                SHORT_BINBYTES => {
-                    let string = self.read_u8_prefixed_bytes()?;
-                    self.stack.push(Value::Bytes(string));
-                }
-                BINBYTES => {
-                    let string = self.read_u32_prefixed_bytes()?;
-                    self.stack.push(Value::Bytes(string));
-                }
-                BINBYTES8 => {
-                    let string = self.read_u64_prefixed_bytes()?;
-                    self.stack.push(Value::Bytes(string));
-                }
-                SHORT_BINSTRING => {
-                    let string = self.read_u8_prefixed_bytes()?;
-                    let decoded = self.decode_string(string)?;
-                    self.stack.push(decoded);
-                }
-                BINSTRING => {
-                    let string = self.read_i32_prefixed_bytes()?;
-                    let decoded = self.decode_string(string)?;
-                    self.stack.push(decoded);
-                }
-                SHORT_BINUNICODE => {
-                    let string = self.read_u8_prefixed_bytes()?;
-                    let decoded = self.decode_unicode(string)?;
-                    self.stack.push(decoded);
-                }
-                BINUNICODE => {
-                    let string = self.read_u32_prefixed_bytes()?;
-                    let decoded = self.decode_unicode(string)?;
-                    self.stack.push(decoded);
-                }
-                BINUNICODE8 => {
-                    let string = self.read_u64_prefixed_bytes()?;
-                    let decoded = self.decode_unicode(string)?;
-                    self.stack.push(decoded);
-                }
-                BYTEARRAY8 => {
-                    let string = self.read_u64_prefixed_bytes()?;
-                    self.stack.push(Value::Bytes(string));
-                }
-
-                // Tuples
-                EMPTY_TUPLE => self.stack.push(Value::Tuple(Vec::new())),
-                TUPLE1 => {
-                    let item = self.pop()?;
-                    self.stack.push(Value::Tuple(vec![item]));
-                 }
-                 TUPLE2 => {
-                    let item2 = self.pop()?;
-                    let item1 = self.pop()?;
-                    self.stack.push(Value::Tuple(vec![item1, item2]));
-                 }
-                 TUPLE3 => {
-                    let item3 = self.pop()?;
-                    let item2 = self.pop()?;
-                    let item1 = self.pop()?;
-                    self.stack.push(Value::Tuple(vec![item1, item2, item3]));
-                }
-                TUPLE => {
-                    let items = self.pop_mark()?;
-                    self.stack.push(Value::Tuple(items));
-                }
-
-                // Lists
-                EMPTY_LIST => self.stack.push(Value::List(Vec::new())),
-                LIST => {
-                    let items = self.pop_mark()?;
-                    self.stack.push(Value::List(items));
-                }
-                APPEND => {
-                    let value = self.pop()?;
-                    self.modify_list(|list| list.push(value))?;
-                }
-                APPENDS => {
-                    let items = self.pop_mark()?;
-                    self.modify_list(|list| list.extend(items))?;
-                }
-
-                // Dicts
-                EMPTY_DICT => self.stack.push(Value::Dict(Vec::new())),
-                DICT => {
-                    let items = self.pop_mark()?;
-                    let mut dict = Vec::with_capacity(items.len() / 2);
-                    Self::extend_dict(&mut dict, items);
-                    self.stack.push(Value::Dict(dict));
+            // Length-prefixed (byte)strings
+            SHORT_BINBYTES => {
+                let len = self.read_u8_length()?;
+                let string = self.read_string(len, false)?;
+                self.stack.push(string);
+            }
+            BINBYTES => {
+                let len = self.read_u32_length()?;
+                let string = self.read_string(len, false)?;
+                self.stack.push(string);
+            }
+            BINBYTES8 => {
+                let len = self.read_u64_length()?;
+                let string = self.read_string(len, false)?;
+                self.stack.push(string);
+            }
+            SHORT_BINSTRING => {
+                let string = self.read_u8_prefixed_bytes()?;
+                let decoded = self.decode_string(string)?;
+                self.stack.push(decoded);
+            }
+            BINSTRING => {
+                let string = self.read_i32_prefixed_bytes()?;
+                let decoded = self.decode_string(string)?;
+                self.stack.push(decoded);
+            }
+            SHORT_BINUNICODE => {
+                let len = self.read_u8_length()?;
+                let string = self.read_string(len, true)?;
+                self.stack.push(string);
+            }
+            BINUNICODE => {
+                let len = self.read_u32_length()?;
+                let string = self.read_string(len, true)?;
+                self.stack.push(string);
+            }
+            BINUNICODE8 => {
+                let len = self.read_u64_length()?;
+                let string = self.read_string(len, true)?;
+                self.stack.push(string);
+            }
+            BYTEARRAY8 => {
+                let string = self.read_u64_prefixed_bytes()?;
+                self.stack.push(Value::Bytes(string));
+            }
+            NEXT_BUFFER => {
+                match self.buffers.as_mut().and_then(Iterator::next) {
+                    Some(buffer) => self.stack.push(Value::Bytes(buffer)),
+                    None => return self.error(ErrorCode::MissingBuffer),
                 }
-                SETITEM => {
-                    let value = self.pop()?;
-                    let key = self.pop()?;
-                    self.modify_dict(|dict| dict.push((key, value)))?;
+                self.readonly.push(false);
+                self.buffer_end = Some(self.pos);
+            }
+            READONLY_BUFFER => {
+                let pos = self.pos;
+                match *self.top()? {
+                    Value::Bytes(_) | Value::BorrowedBytes(..) => {}
+                    ref other => return Self::stack_error("buffer", other, pos),
                 }
-                SETITEMS => {
-                    let items = self.pop_mark()?;
-                    self.modify_dict(|dict| Self::extend_dict(dict, items))?;
+                // In-band byte strings are read-only anyway.
+                if self.buffer_end.take() == Some(self.checkpoint.0) {
+                    if let Some(readonly) = self.readonly.last_mut() {
+                        *readonly = true;
+                    }
+                }
+            }
+
+            // Tuples
+            EMPTY_TUPLE => self.stack.push(Value::Tuple(Vec::new())),
+            TUPLE1 => {
+                let item = self.pop()?;
+                self.stack.push(Value::Tuple(vec![item]));
+             }
+             TUPLE2 => {
+                let item2 = self.pop()?;
+                let item1 = self.pop()?;
+                self.stack.push(Value::Tuple(vec![item1, item2]));
+             }
+             TUPLE3 => {
+                let item3 = self.pop()?;
+                let item2 = self.pop()?;
+                let item1 = self.pop()?;
+                self.stack.push(Value::Tuple(vec![item1, item2, item3]));
+            }
+            TUPLE => {
+                let items = self.pop_mark()?;
+                self.stack.push(Value::Tuple(items));
+            }
+
+            // Lists
+            EMPTY_LIST => self.stack.push(Value::List(Vec::new())),
+            LIST => {
+                let items = self.pop_mark()?;
+                self.stack.push(Value::List(items));
+            }
+            APPEND => {
+                let value = self.pop()?;
+                self.modify_list(|list| list.push(value))?;
+            }
+            APPENDS => {
+                let items = self.pop_mark()?;
+                self.modify_list(|list| list.extend(items))?;
+            }
+
+            // Dicts
+            EMPTY_DICT => self.stack.push(Value::Dict(Vec::new())),
+            DICT => {
+                let items = self.pop_mark()?;
+                let mut dict = Vec::with_capacity(items.len() / 2);
+                Self::extend_dict(&mut dict, items);
+                self.stack.push(Value::Dict(dict));
+            }
+            SETITEM => {
+                let value = self.pop()?;
+                let key = self.pop()?;
+                self.modify_dict(|dict| dict.push((key, value)))?;
+            }
+            SETITEMS => {
+                let items = self.pop_mark()?;
+                self.modify_dict(|dict| Self::extend_dict(dict, items))?;
+            }
+
+            // Sets and frozensets
+            EMPTY_SET => self.stack.push(Value::Set(Vec::new())),
+            FROZENSET => {
+                let items = self.pop_mark()?;
+                self.stack.push(Value::FrozenSet(items));
+            }
+            ADDITEMS => {
+                let items = self.pop_mark()?;
+                self.modify_set(|set| set.extend(items))?;
+            }
+
+            // Arbitrary module globals, used here for unpickling set and frozenset
+            // from protocols < 4
+            GLOBAL => {
+                let modname = self.read_line()?;
+                let globname = self.read_line()?;
+                let value = self.decode_global(modname, globname)?;
+                self.stack.push(value);
+            }
+            STACK_GLOBAL => {
+                let globname = match self.pop_resolve()? {
+                    Value::String(string) => string.into_bytes(),
+                    other => return Self::stack_error("string", &other, self.pos),
+                };
+                let modname = match self.pop_resolve()? {
+                    Value::String(string) => string.into_bytes(),
+                    other => return Self::stack_error("string", &other, self.pos),
+                };
+                let value = self.decode_global(modname, globname)?;
+                self.stack.push(value);
+            }
+            EXT1 => {
+                let code = self.read_byte()?;
+                self.decode_extension(code.into())?;
+            }
+            EXT2 => {
+                let bytes = self.read_fixed_2_bytes()?;
+                self.decode_extension(LittleEndian::read_u16(&bytes).into())?;
+            }
+            EXT4 => {
+                let bytes = self.read_fixed_4_bytes()?;
+                self.decode_extension(LittleEndian::read_u32(&bytes))?;
+            }
+            REDUCE => {
+                let args = self.pop_shared()?;
+                if let Value::Tuple(_) = *args.shared() {} else {
+                    return Self::stack_error("tuple", args.shared(), self.pos);
                 }
+                let global = self.pop_resolve()?;
+                self.reduce_global(global, args)?;
+            }
 
-                // Sets and frozensets
-                EMPTY_SET => self.stack.push(Value::Set(Vec::new())),
-                FROZENSET => {
-                    let items = self.pop_mark()?;
-                    self.stack.push(Value::FrozenSet(items));
+            // Arbitrary classes - make a best effort attempt to recover some data
+            INST => {
+                // read module name and class name
+                let modname = self.read_line()?;
+                let globname = self.read_line()?;
+                // pop arguments to init
+                let args = self.pop_mark()?;
+                let cls = self.decode_global(modname, globname)?;
+                self.instantiate(cls, Value::Tuple(args), None)?;
+            }
+            OBJ => {
+                // pop class object and arguments to init
+                let mut args = self.pop_mark()?;
+                if args.is_empty() {
+                    return self.error(ErrorCode::StackUnderflow);
+                }
+                let cls = args.remove(0);
+                let cls = self.resolve_value(cls)?;
+                let cls = self.unshare(cls);
+                self.instantiate(cls, Value::Tuple(args), None)?;
+            }
+            NEWOBJ => {
+                // pop arguments and class object
+                let args = self.pop_shared()?;
+                if let Value::Tuple(_) = *args.shared() {} else {
+                    return Self::stack_error("tuple", args.shared(), self.pos);
                 }
-                ADDITEMS => {
-                    let items = self.pop_mark()?;
-                    self.modify_set(|set| set.extend(items))?;
+                let cls = self.pop_resolve()?;
+                self.instantiate(cls, args, Some(Value::Dict(Vec::new())))?;
+            }
+            NEWOBJ_EX => {
+                // pop keyword args, arguments and class object
+                let kwargs = self.pop_shared()?;
+                if let Value::Dict(_) = *kwargs.shared() {} else {
+                    return Self::stack_error("dict", kwargs.shared(), self.pos);
+                }
+                let args = self.pop_shared()?;
+                if let Value::Tuple(_) = *args.shared() {} else {
+                    return Self::stack_error("tuple", args.shared(), self.pos);
+                }
+                let cls = self.pop_resolve()?;
+                self.instantiate(cls, args, Some(kwargs))?;
+            }
+            BUILD => {
+                // The top-of-stack for BUILD is used either as the instance __dict__,
+                // or an argument for __setstate__, in which case it can be *any* type
+                // of object.  Objects keep it, otherwise we just replace the standin.
+                let state = self.pop()?;
+                if let Value::Object(ref mut object) = *self.top()? {
+                    object.state = Some(state);
+                    return Ok(None);
                 }
+                self.pop()?;  // remove the object standin
+                let state = self.merge_slot_state(state);
+                self.stack.push(state);
+            }
 
-                // Arbitrary module globals, used here for unpickling set and frozenset
-                // from protocols < 4
-                GLOBAL => {
-                    let modname = self.read_line()?;
-                    let globname = self.read_line()?;
-                    let value = self.decode_global(modname, globname)?;
-                    self.stack.push(value);
+            // Persistent ids, resolved by a user callback
+            PERSID => {
+                let line = self.read_line()?;
+                let pid = self.decode_unicode(line)?;
+                self.load_persistent(PERSID, pid)?;
+            }
+            BINPERSID => {
+                let pid = self.pop()?;
+                self.load_persistent(BINPERSID, pid)?;
+            }
+
+            // Unsupported opcodes
+            code => return self.error(ErrorCode::Unsupported(code as char))
+        }
+        Ok(None)
+    }
+
+    // Return the next opcode without consuming it.  Frames are skipped.
+    fn peek_opcode(&mut self) -> Result<u8> {
+        loop {
+            let next = match self.input().fill_buf() {
+                Ok(buf) => buf.first().cloned(),
+                Err(err) => return Err(Error::Io(err)),
+            };
+            match next {
+                Some(FRAME) => { self.parse_opcode()?; }
+                Some(op) => return Ok(op),
+                None => return self.error(ErrorCode::EOFWhileParsing),
+            }
+        }
+    }
+
+    // Check if the next value can be streamed: it has to be a list or dict at
+    // the start of the pickle, or the next item of a streamed container, and
+    // found streamable by `plan_streaming`.
+    fn at_streamable(&mut self) -> Result<bool> {
+        if !self.options.stream_containers || self.value.is_some() {
+            return Ok(false);
+        }
+        match self.streams.last() {
+            None => {
+                if !self.stack.is_empty() || !self.stacks.is_empty() {
+                    return Ok(false);
                 }
-                STACK_GLOBAL => {
-                    let globname = match self.pop_resolve()? {
-                        Value::String(string) => string.into_bytes(),
-                        other => return Self::stack_error("string", &other, self.pos),
-                    };
-                    let modname = match self.pop_resolve()? {
-                        Value::String(string) => string.into_bytes(),
-                        other => return Self::stack_error("string", &other, self.pos),
-                    };
-                    let value = self.decode_global(modname, globname)?;
-                    self.stack.push(value);
+                self.allocated = 0;
+                while self.peek_opcode()? == PROTO {
+                    self.parse_opcode()?;
                 }
-                REDUCE => {
-                    let argtuple = match self.pop_resolve()? {
-                        Value::Tuple(args) => args,
-                        other => return Self::stack_error("tuple", &other, self.pos),
-                    };
-                    let global = self.pop_resolve()?;
-                    self.reduce_global(global, argtuple)?;
+                let op = self.peek_opcode()?;
+                if op != EMPTY_LIST && op != EMPTY_DICT {
+                    return Ok(false);
+                }
+                if self.streamable.as_ref().map(|s| s.0) != Some(self.pos) {
+                    self.plan_streaming()?;
                 }
+            }
+            Some(stream) => {
+                if !stream.pending.is_empty() || !self.stack.is_empty() ||
+                    stream.marks != Some(self.stacks.len())
+                {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(self.is_streamable())
+    }
+
+    // Check the rest of the pickle for containers that can be streamed.  From
+    // a reader, the rest of the pickle is read ahead for this, up to its STOP
+    // opcode, unless it exceeds `MAX_READ_AHEAD`.
+    fn plan_streaming(&mut self) -> Result<()> {
+        let memoize = !self.options.keep_memo && self.memo.is_empty();
+        let offsets = match self.rdr.rest() {
+            Some(data) => plan_streaming(&mut SliceScan { data, pos: 0 }, memoize),
+            None => {
+                let limits = &self.options.limits;
+                let max_buffered = match limits.allocation {
+                    Some(max) => cmp::min(max.saturating_sub(self.allocated), MAX_READ_AHEAD),
+                    None => MAX_READ_AHEAD,
+                };
+                let start = self.ahead.pos;
+                let mut scan = ReaderScan {
+                    rdr: self.rdr.reader(),
+                    ahead: &mut self.ahead,
+                    start,
+                    pos: start,
+                    max_length: limits.length,
+                    max_buffered,
+                };
+                plan_streaming(&mut scan, memoize)
+            }
+        };
+        let pos = self.pos;
+        self.streamable = Some((pos, offsets.into_iter().map(|offset| pos + offset).collect()));
+        Ok(())
+    }
+
+    // Check if the container at the current position can be streamed.
+    fn is_streamable(&self) -> bool {
+        match self.streamable {
+            Some((_, ref offsets)) => offsets.binary_search(&self.pos).is_ok(),
+            None => false,
+        }
+    }
+
+    // Visit the list or dict at the current position while decoding it.
+    fn deserialize_streamed<'a, V: Visitor<'de>>(&'a mut self, visitor: V) -> Result<V::Value>
+        where 'de: 'a
+    {
+        let top = self.streams.is_empty();
+        let (offset, opcode) = (self.pos, self.peek_opcode()?);
+        let dict = opcode == EMPTY_DICT;
+        self.parse_opcode()?;
+        if let PUT | BINPUT | LONG_BINPUT | MEMOIZE = self.peek_opcode()? {
+            // The container is never referenced, so its memo entry stays
+            // empty.
+            self.parse_opcode()?;
+        }
+        let container = self.pop()?;
+        if let Err(mut err) = self.enter_value(&container) {
+            container.dismantle();
+            // Report the opcode that created the container.
+            if let Some(context) = err.context_mut() {
+                context.opcode = Some((opcode_name(opcode).unwrap_or("unknown"), offset));
+            }
+            return Err(err);
+        }
+        // Memo entries can't be dropped after use, since references to them
+        // further in the stream haven't been counted yet.
+        let keep_memo = mem::replace(&mut self.keep_memo, true);
+        self.streams.push(Stream {
+            pending: VecDeque::new(),
+            marks: None,
+            close: if dict { SETITEMS } else { APPENDS },
+        });
+        let result = if dict {
+            visitor.visit_map(StreamAccess { de: &mut *self, key: None, index: 0 })
+        } else {
+            visitor.visit_seq(StreamAccess { de: &mut *self, key: None, index: 0 })
+        };
+        // Skip the items the visitor didn't want.
+        let result = result.and_then(|value| {
+            while self.stream_next()? {
+                de::Deserialize::deserialize(&mut *self).map(|_: de::IgnoredAny| ())?;
+            }
+            Ok(value)
+        });
+        self.streams.pop();
+        self.keep_memo = keep_memo;
+        self.depth -= 1;
+        if top {
+            self.streamable = None;
+        }
+        let value = result?;
+        if top {
+            if self.peek_opcode()? != STOP {
+                return self.error(ErrorCode::StreamMismatch);
+            }
+            self.read_byte()?;
+        }
+        Ok(value)
+    }
 
-                // Arbitrary classes - make a best effort attempt to recover some data
-                INST => {
-                    // pop module name and class name
-                    for _ in 0..2 {
-                        self.read_line()?;
+    // Advance to the next item of the streamed container.  Returns false at
+    // its end.
+    fn stream_next(&mut self) -> Result<bool> {
+        loop {
+            let (marks, close) = match self.streams.last() {
+                Some(stream) if stream.pending.is_empty() => (stream.marks, stream.close),
+                Some(_) => return Ok(true),
+                None => return self.error(ErrorCode::StreamMismatch),
+            };
+            let op = self.peek_opcode()?;
+            match marks {
+                None => {
+                    // Between batches: another one may follow.
+                    if op != MARK {
+                        return Ok(false);
                     }
-                    // pop arguments to init
-                    self.pop_mark()?;
-                    // push empty dictionary instead of the class instance
-                    self.stack.push(Value::Dict(Vec::new()));
-                }
-                OBJ => {
-                    // pop arguments to init
-                    self.pop_mark()?;
-                    // pop class object
-                    self.pop()?;
-                    self.stack.push(Value::Dict(Vec::new()));
-                }
-                NEWOBJ => {
-                    // pop arguments and class object
-                    for _ in 0..2 {
-                        self.pop()?;
+                    self.parse_opcode()?;
+                    let marks = self.stacks.len();
+                    if let Some(stream) = self.streams.last_mut() {
+                        stream.marks = Some(marks);
                     }
-                    self.stack.push(Value::Dict(Vec::new()));
                 }
-                NEWOBJ_EX => {
-                    // pop keyword args, arguments and class object
-                    for _ in 0..3 {
-                        self.pop()?;
+                Some(marks) if self.stack.is_empty() && self.stacks.len() == marks => {
+                    if op == close {
+                        self.read_byte()?;
+                        self.pop_mark()?;
+                        if let Some(stream) = self.streams.last_mut() {
+                            stream.marks = None;
+                        }
+                    } else if close == APPENDS && self.is_streamable() {
+                        // A list item that can be streamed itself.
+                        return Ok(true);
+                    } else {
+                        self.buffer_items(close == SETITEMS)?;
                     }
-                    self.stack.push(Value::Dict(Vec::new()));
-                }
-                BUILD => {
-                    // The top-of-stack for BUILD is used either as the instance __dict__,
-                    // or an argument for __setstate__, in which case it can be *any* type
-                    // of object.  In both cases, we just replace the standin.
-                    let state = self.pop()?;
-                    self.pop()?;  // remove the object standin
-                    self.stack.push(state);
                 }
+                Some(_) => return self.error(ErrorCode::StreamMismatch),
+            }
+        }
+    }
 
-                // Unsupported opcodes
-                code => return self.error(ErrorCode::Unsupported(code as char))
+    // Decode the rest of the current batch onto the stack, and queue the
+    // items.  With `key`, stop early if a dict key is followed by a value
+    // that can be streamed.
+    fn buffer_items(&mut self, key: bool) -> Result<()> {
+        let (marks, close) = match self.streams.last() {
+            Some(&Stream { marks: Some(marks), close, .. }) => (marks, close),
+            _ => return self.error(ErrorCode::StreamMismatch),
+        };
+        loop {
+            if self.stacks.len() < marks {
+                return self.error(ErrorCode::StreamMismatch);
+            }
+            if self.stacks.len() == marks {
+                let op = self.peek_opcode()?;
+                if op == close {
+                    break;
+                }
+                if key && self.stack.len() == 1 && self.is_streamable() {
+                    break;
+                }
             }
+            if self.parse_opcode()?.is_some() {
+                return self.error(ErrorCode::StreamMismatch);
+            }
+        }
+        let items = mem::take(&mut self.stack);
+        if let Some(stream) = self.streams.last_mut() {
+            stream.pending.extend(items);
         }
+        Ok(())
     }
 
     // Pop the stack top item.
@@ -517,6 +2501,12 @@ impl<R: Read> Deserializer<R> {
 
     // Pop the stack top item, and resolve it if it is a memo reference.
     fn pop_resolve(&mut self) -> Result<Value> {
+        let value = self.pop_shared()?;
+        Ok(self.unshare(value))
+    }
+
+    // Like `pop_resolve`, but a memoized value stays shared with the memo.
+    fn pop_shared(&mut self) -> Result<Value> {
         let top = self.stack.pop();
         match self.resolve(top) {
             Some(v) => Ok(v),
@@ -532,18 +2522,54 @@ impl<R: Read> Deserializer<R> {
         }
     }
 
-    // Mutably view the stack top item.
+    // Mutably view the stack top item.  Values that are shared with the memo
+    // are copied first, so that only this reference sees the change.
     fn top(&mut self) -> Result<&mut Value> {
-        match self.stack.last_mut() {
+        match self.stack.last() {
             // Since some operations like APPEND do things to the stack top, we
             // need to provide the reference to the "real" object here, not the
             // MemoRef variant.
-            Some(&mut Value::MemoRef(n)) =>
-                self.memo.get_mut(&n)
-                         .map(|&mut (ref mut v, _)| v)
-                         .ok_or_else(|| Error::Syntax(ErrorCode::MissingMemo(n))),
-            Some(other_value) => Ok(other_value),
-            None => Err(Error::Eval(ErrorCode::StackUnderflow, self.pos)),
+            Some(&Value::MemoRef(n)) => {
+                let inner = match self.memo.get(&n) {
+                    Some((value, _)) => match *value.get() {
+                        ref inner @ Value::Shared(_) | ref inner @ Value::Apply(..) => Some(inner.clone()),
+                        _ => None,
+                    },
+                    None => return self.error(ErrorCode::MissingMemo(n)),
+                };
+                if let Some(inner) = inner {
+                    let value = self.unshare(inner);
+                    if let Some(entry) = self.memo.get_mut(&n) {
+                        mem::replace(&mut entry.0, Memoized::Value(value)).dismantle();
+                    }
+                }
+                let shared = match self.memo.get(&n) {
+                    Some(&(Memoized::Shared(ref value), _)) if Arc::strong_count(value) > 1 =>
+                        Some(value.clone()),
+                    Some(_) => None,
+                    None => return self.error(ErrorCode::MissingMemo(n)),
+                };
+                if let Some(value) = shared {
+                    let copy = self.copy_value(&value);
+                    if let Some(entry) = self.memo.get_mut(&n) {
+                        entry.0 = Memoized::Value(copy);
+                    }
+                }
+                match self.memo.get_mut(&n) {
+                    Some(&mut (ref mut v, _)) => Ok(v.get_mut()),
+                    None => Err(Error::eval(ErrorCode::MissingMemo(n), self.pos)),
+                }
+            }
+            Some(&Value::Shared(_)) | Some(&Value::Apply(..)) => {
+                let value = self.pop()?;
+                let value = self.unshare(value);
+                self.stack.push(value);
+                self.top()
+            }
+            _ => match self.stack.last_mut() {
+                Some(other_value) => Ok(other_value),
+                None => Err(Error::eval(ErrorCode::StackUnderflow, self.pos)),
+            },
         }
     }
 
@@ -552,68 +2578,239 @@ impl<R: Read> Deserializer<R> {
         self.stack.push(Value::MemoRef(memo_id));
         match self.memo.get_mut(&memo_id) {
             Some(&mut (_, ref mut count)) => { *count += 1; Ok(()) }
-            None => Err(Error::Eval(ErrorCode::MissingMemo(memo_id), self.pos)),
+            None => Err(Error::eval(ErrorCode::MissingMemo(memo_id), self.pos)),
         }
     }
 
     // Memoize the current stack top with the given ID.  Moves the actual
     // object into the memo, and saves a reference on the stack instead.
     fn memoize(&mut self, memo_id: MemoId) -> Result<()> {
-        let mut item = self.pop()?;
-        if let Value::MemoRef(id) = item {
+        if let Some(max) = self.options.limits.memo {
+            if self.memo.len() >= max && !self.memo.contains_key(&memo_id) {
+                return self.error(ErrorCode::MemoLimit);
+            }
+        }
+        let item = match self.pop()? {
             // TODO: is this even possible?
-            item = match self.memo.get(&id) {
-                Some(&(ref v, _)) => v.clone(),
-                None => return Err(Error::Eval(ErrorCode::MissingMemo(id), self.pos)),
-            };
+            Value::MemoRef(id) => match self.memo.get_mut(&id) {
+                Some(&mut (ref mut v, ref mut count)) => {
+                    *count -= 1;
+                    Memoized::Shared(v.share())
+                }
+                None => return Err(Error::eval(ErrorCode::MissingMemo(id), self.pos)),
+            },
+            item => Memoized::Value(item),
+        };
+        if let Some((old, _)) = self.memo.insert(memo_id, (item, 1)) {
+            old.dismantle();
         }
-        self.memo.insert(memo_id, (item, 1));
         self.stack.push(Value::MemoRef(memo_id));
         Ok(())
     }
 
-    // Resolve memo reference during stream decoding.
+    // Drop the memo entries that are not referenced anymore once the pickle
+    // has been parsed, so that values shared with them need not be copied.
+    fn release_memo(&mut self) {
+        let unused = self.memo.iter().filter(|&(_, &(_, count))| count <= 0)
+                                     .map(|(&id, _)| id).collect::<Vec<_>>();
+        for id in unused {
+            if let Some((value, _)) = self.memo.remove(&id) {
+                value.dismantle();
+            }
+        }
+    }
+
+    // Resolve memo reference during stream decoding.  Memoized values are
+    // shared with the memo instead of copied, see `unshare`.  Strings
+    // borrowed from the input are copied, since the value is consumed by the
+    // decoder.
     fn resolve(&mut self, maybe_memo: Option<Value>) -> Option<Value> {
         match maybe_memo {
             Some(Value::MemoRef(id)) => {
-                self.memo.get_mut(&id).map(|&mut (ref val, ref mut count)| {
+                self.memo.get_mut(&id).map(|&mut (ref mut val, ref mut count)| {
                     // We can't remove it from the memo here, since we haven't
                     // decoded the whole stream yet and there may be further
                     // references to the value.
                     *count -= 1;
-                    val.clone()
+                    match *val.get() {
+                        // Results of builtins are shared with their argument.
+                        Value::Shared(ref value) => Value::Shared(value.clone()),
+                        Value::Apply(ref global, ref value) => Value::Apply(global.clone(), value.clone()),
+                        _ => Value::Shared(val.share()),
+                    }
                 })
             },
-            other => other
+            other => other.map(|v| self.make_owned(v)),
+        }
+    }
+
+    // Resolve a value taken from the stack, which fails if it refers to a
+    // missing memo entry.
+    fn resolve_value(&mut self, value: Value) -> Result<Value> {
+        match value {
+            Value::MemoRef(id) => match self.resolve(Some(value)) {
+                Some(value) => Ok(value),
+                None => self.error(ErrorCode::MissingMemo(id)),
+            },
+            value => Ok(self.make_owned(value)),
+        }
+    }
+
+    // Take a value that may be shared with the memo, copying it only if
+    // there are further references to it.
+    fn unshare(&mut self, value: Value) -> Value {
+        match value {
+            Value::Shared(value) => match Arc::try_unwrap(value) {
+                Ok(value) => self.make_owned(value),
+                Err(value) => {
+                    let value = self.copy_value(&value);
+                    self.make_owned(value)
+                }
+            },
+            Value::Apply(global, value) => {
+                let value = self.unshare(Value::Shared(value));
+                self.apply_builtin(global, value)
+            }
+            value => self.make_owned(value),
+        }
+    }
+
+    // Copy a value without recursion.  Memo references within it are
+    // counted again, and values shared with the memo stay shared.
+    fn copy_value(&mut self, value: &Value) -> Value {
+        enum Task<'v> {
+            Copy(&'v Value),
+            Collect(&'v Value),  // build a container from its copied parts
+        }
+        fn copy_pairs<'v>(tasks: &mut Vec<Task<'v>>, pairs: &'v [(Value, Value)]) {
+            for (key, value) in pairs.iter().rev() {
+                tasks.push(Task::Copy(value));
+                tasks.push(Task::Copy(key));
+            }
+        }
+        fn collect_pairs(parts: &mut vec::IntoIter<Value>, len: usize) -> Vec<(Value, Value)> {
+            (0..len).map(|_| (parts.next().unwrap_or(Value::None),
+                              parts.next().unwrap_or(Value::None))).collect()
+        }
+        let mut tasks = vec![Task::Copy(value)];
+        let mut copied = Vec::new();
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Copy(value) => match *value {
+                    Value::List(ref items) | Value::Tuple(ref items) |
+                    Value::Set(ref items) | Value::FrozenSet(ref items) => {
+                        tasks.push(Task::Collect(value));
+                        tasks.extend(items.iter().rev().map(Task::Copy));
+                    }
+                    Value::Dict(ref pairs) => {
+                        tasks.push(Task::Collect(value));
+                        copy_pairs(&mut tasks, pairs);
+                    }
+                    Value::Object(ref object) => {
+                        tasks.push(Task::Collect(value));
+                        copy_pairs(&mut tasks, &object.dictitems);
+                        tasks.extend(object.listitems.iter().rev().map(Task::Copy));
+                        tasks.extend(object.state.iter().map(Task::Copy));
+                        tasks.extend(object.kwargs.iter().map(Task::Copy));
+                        tasks.push(Task::Copy(&object.args));
+                    }
+                    Value::MemoRef(id) => {
+                        if let Some(&mut (_, ref mut count)) = self.memo.get_mut(&id) {
+                            *count += 1;
+                        }
+                        copied.push(Value::MemoRef(id));
+                    }
+                    ref value => copied.push(value.clone()),
+                },
+                Task::Collect(value) => {
+                    let len = match *value {
+                        Value::List(ref items) | Value::Tuple(ref items) |
+                        Value::Set(ref items) | Value::FrozenSet(ref items) => items.len(),
+                        Value::Dict(ref pairs) => 2 * pairs.len(),
+                        Value::Object(ref object) => {
+                            1 + object.kwargs.iter().count() + object.state.iter().count() +
+                                object.listitems.len() + 2 * object.dictitems.len()
+                        }
+                        _ => 0,
+                    };
+                    let parts = copied.split_off(copied.len() - len);
+                    let copy = match *value {
+                        Value::List(_) => Value::List(parts),
+                        Value::Tuple(_) => Value::Tuple(parts),
+                        Value::Set(_) => Value::Set(parts),
+                        Value::FrozenSet(_) => Value::FrozenSet(parts),
+                        Value::Dict(ref pairs) => Value::Dict(collect_pairs(&mut parts.into_iter(), pairs.len())),
+                        Value::Object(ref object) => {
+                            let mut parts = parts.into_iter();
+                            let args = parts.next().unwrap_or(Value::None);
+                            let kwargs = object.kwargs.as_ref().and_then(|_| parts.next());
+                            let state = object.state.as_ref().and_then(|_| parts.next());
+                            let listitems = parts.by_ref().take(object.listitems.len()).collect();
+                            let dictitems = collect_pairs(&mut parts, object.dictitems.len());
+                            Value::Object(Box::new(Object {
+                                module: object.module.clone(), name: object.name.clone(),
+                                args, kwargs, state, listitems, dictitems,
+                                reconstruct: object.reconstruct.clone(),
+                            }))
+                        }
+                        _ => Value::None,
+                    };
+                    copied.push(copy);
+                }
+            }
         }
+        copied.pop().unwrap_or(Value::None)
     }
 
-    // Resolve memo reference during Value deserializing.
+    // Resolve memo reference during Value deserializing.  The value stays
+    // shared with the memo if there are further references to it.
     fn resolve_recursive<T, U, F>(&mut self, id: MemoId, u: U, f: F) -> Result<T>
-        where F: FnOnce(&mut Self, U, Value) -> Result<T>
+        where F: FnOnce(&mut Self, U, Memoized) -> Result<T>
     {
-        // Take the value from the memo while visiting it.  This prevents us
-        // from trying to depickle recursive structures, which we can't do
-        // because our Values aren't references.
-        let (value, mut count) = match self.memo.remove(&id) {
-            Some(entry) => entry,
-            None => return Err(Error::Syntax(ErrorCode::Recursive)),
+        // While a shared value is visited, its ID is recorded, and a value
+        // that is moved out is removed from the memo.  This prevents us from
+        // trying to depickle recursive structures, which we can't do because
+        // our Values aren't references.
+        if self.visiting.contains(&id) {
+            return Err(Error::Syntax(ErrorCode::Recursive, None));
+        }
+        let value = match self.memo.entry(id) {
+            Entry::Occupied(mut entry) => {
+                if !self.keep_memo {
+                    entry.get_mut().1 -= 1;
+                }
+                if entry.get().1 > 0 || self.keep_memo {
+                    Memoized::Shared(entry.get_mut().0.share())
+                } else {
+                    // No need to keep it.
+                    entry.remove().0
+                }
+            }
+            Entry::Vacant(_) => return Err(Error::Syntax(ErrorCode::Recursive, None)),
         };
-        count -= 1;
-        if count <= 0 {
-            f(self, u, value)
-            // No need to put it back.
-        } else {
-            let result = f(self, u, value.clone());
-            self.memo.insert(id, (value, count));
-            result
+        if let Memoized::Value(_) = value {
+            return f(self, u, value);
         }
+        // The value will be visited again, so the references within it must
+        // not be used up.
+        let keep_memo = mem::replace(&mut self.keep_memo, true);
+        self.visiting.insert(id);
+        let result = f(self, u, value);
+        self.visiting.remove(&id);
+        self.keep_memo = keep_memo;
+        result
     }
 
     /// Assert that we reached the end of the stream.
+    ///
+    /// After a pickle was recovered (see `DeOptions::recover`), the rest of
+    /// the input is not checked.
     pub fn end(&mut self) -> Result<()> {
+        if self.recovery.is_some() {
+            return Ok(());
+        }
         let mut buf = [0];
-        match self.rdr.read(&mut buf) {
+        match self.input().read(&mut buf) {
             Err(err) => Err(Error::Io(err)),
             Ok(1) => self.error(ErrorCode::TrailingBytes),
             _ => Ok(())
@@ -622,42 +2819,103 @@ impl<R: Read> Deserializer<R> {
 
     fn read_line(&mut self) -> Result<Vec<u8>> {
         let mut buf = Vec::with_capacity(16);
-        match self.rdr.read_until(b'\n', &mut buf) {
+        let result = match self.options.limits.length {
+            // Don't read further than the line ending after the limit.
+            Some(max) => self.input().take((max as u64).saturating_add(2))
+                                             .read_until(b'\n', &mut buf),
+            None => self.input().read_until(b'\n', &mut buf),
+        };
+        match result {
             Ok(_) => {
                 self.pos += buf.len();
-                buf.pop(); // remove newline
+                self.capture(&buf);
+                if buf.last() == Some(&b'\n') { buf.pop(); } // remove newline
                 if buf.last() == Some(&b'\r') { buf.pop(); }
+                self.check_length(buf.len() as u64)?;
                 Ok(buf)
             },
             Err(err) => Err(Error::Io(err))
         }
     }
 
+    // Check the length of a string or similar against the length limit, and
+    // account for its memory.
+    fn check_length(&mut self, len: u64) -> Result<()> {
+        if let Some(max) = self.options.limits.length {
+            if len > max as u64 {
+                return self.error(ErrorCode::LengthLimit(len));
+            }
+        }
+        self.account(len as usize)
+    }
+
+    // Account for memory allocated while decoding, checking the allocation limit.
+    #[inline]
+    fn account(&mut self, bytes: usize) -> Result<()> {
+        self.allocated = self.allocated.saturating_add(bytes);
+        match self.options.limits.allocation {
+            Some(max) if self.allocated > max => self.error(ErrorCode::AllocationLimit),
+            _ => Ok(()),
+        }
+    }
+
+    // Check the nesting depth and account for the memory of a value that is
+    // about to be converted or visited.
+    fn enter_value(&mut self, value: &Value) -> Result<()> {
+        if self.depth >= self.options.limits.depth.unwrap_or(DEFAULT_MAX_DEPTH) {
+            return self.error(ErrorCode::DepthLimit);
+        }
+        let data = match *value {
+            Value::Bytes(ref b) => b.len(),
+            Value::String(ref s) => s.len(),
+            Value::BorrowedBytes(start, end) | Value::BorrowedString(start, end) => end - start,
+            _ => 0,
+        };
+        self.account(mem::size_of::<value::Value>() + data)?;
+        self.depth += 1;
+        Ok(())
+    }
+
+    // Record bytes read from the input while tracing.
+    #[inline]
+    fn capture(&mut self, bytes: &[u8]) {
+        if let Some(ref mut raw) = self.raw {
+            raw.extend_from_slice(bytes);
+        }
+    }
+
     #[inline]
     fn read_byte(&mut self) -> Result<u8> {
         let mut buf = [0];
-        match self.rdr.read(&mut buf) {
-            Ok(1) => { self.pos += 1; Ok(buf[0]) },
+        match self.input().read(&mut buf) {
+            Ok(1) => { self.pos += 1; self.capture(&buf); Ok(buf[0]) },
             Ok(_) => self.error(ErrorCode::EOFWhileParsing),
             Err(err) => Err(Error::Io(err)),
         }
     }
 
     #[inline]
-    fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+    fn read_bytes(&mut self, n: u64) -> Result<Vec<u8>> {
+        self.check_length(n)?;
         let mut buf = Vec::new();
-        match self.rdr.by_ref().take(n as u64).read_to_end(&mut buf) {
-            Ok(m) if n == m => { self.pos += n; Ok(buf) },
+        match self.input().take(n).read_to_end(&mut buf) {
+            Ok(m) if n == m as u64 => { self.pos += m; self.capture(&buf); Ok(buf) },
             Ok(_) => self.error(ErrorCode::EOFWhileParsing),
-            Err(err) => Err(Error::Io(err)),
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    // Don't retry before all of the data has arrived.
+                    self.wanted = self.pos.saturating_add(n as usize);
+                }
+                Err(Error::Io(err))
+            }
         }
     }
 
     #[inline]
     fn read_fixed_2_bytes(&mut self) -> Result<[u8; 2]> {
         let mut buf = [0; 2];
-        match self.rdr.by_ref().take(2).read_exact(&mut buf) {
-            Ok(()) => { self.pos += 2; Ok(buf) },
+        match self.input().take(2).read_exact(&mut buf) {
+            Ok(()) => { self.pos += 2; self.capture(&buf); Ok(buf) },
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::UnexpectedEof {
                     self.error(ErrorCode::EOFWhileParsing)
@@ -671,8 +2929,8 @@ impl<R: Read> Deserializer<R> {
     #[inline]
     fn read_fixed_4_bytes(&mut self) -> Result<[u8; 4]> {
         let mut buf = [0; 4];
-        match self.rdr.by_ref().take(4).read_exact(&mut buf) {
-            Ok(()) => { self.pos += 4; Ok(buf) },
+        match self.input().take(4).read_exact(&mut buf) {
+            Ok(()) => { self.pos += 4; self.capture(&buf); Ok(buf) },
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::UnexpectedEof {
                     self.error(ErrorCode::EOFWhileParsing)
@@ -686,8 +2944,8 @@ impl<R: Read> Deserializer<R> {
     #[inline]
     fn read_fixed_8_bytes(&mut self) -> Result<[u8; 8]> {
         let mut buf = [0; 8];
-        match self.rdr.by_ref().take(8).read_exact(&mut buf) {
-            Ok(()) => { self.pos += 8; Ok(buf) },
+        match self.input().take(8).read_exact(&mut buf) {
+            Ok(()) => { self.pos += 8; self.capture(&buf); Ok(buf) },
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::UnexpectedEof {
                     self.error(ErrorCode::EOFWhileParsing)
@@ -703,23 +2961,81 @@ impl<R: Read> Deserializer<R> {
         match LittleEndian::read_i32(&lenbytes) {
             0          => Ok(vec![]),
             l if l < 0 => self.error(ErrorCode::NegativeLength),
-            l          => self.read_bytes(l as usize)
+            l          => self.read_bytes(l as u64)
         }
     }
 
     fn read_u64_prefixed_bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_u64_length()?;
+        self.read_bytes(len)
+    }
+
+    fn read_u8_prefixed_bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_u8_length()?;
+        self.read_bytes(len)
+    }
+
+    fn read_u64_length(&mut self) -> Result<u64> {
         let lenbytes = self.read_fixed_8_bytes()?;
-        self.read_bytes(LittleEndian::read_u64(&lenbytes) as usize)
+        Ok(LittleEndian::read_u64(&lenbytes))
     }
 
-    fn read_u32_prefixed_bytes(&mut self) -> Result<Vec<u8>> {
+    fn read_u32_length(&mut self) -> Result<u64> {
         let lenbytes = self.read_fixed_4_bytes()?;
-        self.read_bytes(LittleEndian::read_u32(&lenbytes) as usize)
+        Ok(LittleEndian::read_u32(&lenbytes).into())
     }
 
-    fn read_u8_prefixed_bytes(&mut self) -> Result<Vec<u8>> {
-        let lenbyte = self.read_byte()?;
-        self.read_bytes(lenbyte as usize)
+    fn read_u8_length(&mut self) -> Result<u64> {
+        Ok(self.read_byte()?.into())
+    }
+
+    // Read a byte string or a UTF-8 encoded Unicode string.  If the input
+    // allows it, the string is borrowed instead of copied.
+    fn read_string(&mut self, n: u64, unicode: bool) -> Result<Value> {
+        match self.rdr.read_borrowed(n) {
+            Some((start, end)) => {
+                self.pos += end - start;
+                if let Some(ref mut raw) = self.raw {
+                    raw.extend_from_slice(self.rdr.borrowed(start, end));
+                }
+                self.check_length(n)?;
+                if !unicode {
+                    Ok(Value::BorrowedBytes(start, end))
+                } else if str::from_utf8(self.rdr.borrowed(start, end)).is_ok() {
+                    Ok(Value::BorrowedString(start, end))
+                } else {
+                    self.error(ErrorCode::StringNotUTF8)
+                }
+            }
+            None => {
+                let bytes = self.read_bytes(n)?;
+                if unicode {
+                    self.decode_unicode(bytes)
+                } else {
+                    Ok(Value::Bytes(bytes))
+                }
+            }
+        }
+    }
+
+    // Replace a string borrowed from the input by an owned copy.
+    fn make_owned(&self, value: Value) -> Value {
+        match value {
+            Value::BorrowedBytes(start, end) =>
+                Value::Bytes(self.rdr.borrowed(start, end).to_vec()),
+            Value::BorrowedString(start, end) =>
+                Value::String(self.borrowed_str(start, end).to_owned()),
+            other => other,
+        }
+    }
+
+    // Return a string borrowed from the input, which `read_string` has
+    // already checked to be UTF-8.
+    fn borrowed_str(&self, start: usize, end: usize) -> &'de str {
+        let bytes = self.rdr.borrowed(start, end);
+        debug_assert!(str::from_utf8(bytes).is_ok());
+        // SAFETY: `BorrowedString` is only created for valid UTF-8.
+        unsafe { str::from_utf8_unchecked(bytes) }
     }
 
     // Parse an expected ASCII literal from the stream or raise an error.
@@ -832,10 +3148,19 @@ impl<R: Read> Deserializer<R> {
 
     // Decode a string - either as Unicode or as bytes.
     fn decode_string(&self, string: Vec<u8>) -> Result<Value> {
-        if self.options.decode_strings {
-            self.decode_unicode(string)
-        } else {
-            Ok(Value::Bytes(string))
+        let latin1 = |bytes: &[u8]| bytes.iter().map(|&b| b as char).collect();
+        match self.options.string_encoding {
+            StringEncoding::Bytes => Ok(Value::Bytes(string)),
+            StringEncoding::Utf8 => self.decode_unicode(string),
+            StringEncoding::Utf8Lossy => match String::from_utf8(string) {
+                Ok(v) => Ok(Value::String(v)),
+                Err(err) => Ok(Value::String(String::from_utf8_lossy(err.as_bytes()).into_owned())),
+            },
+            StringEncoding::Latin1 => Ok(Value::String(latin1(&string))),
+            StringEncoding::Utf8OrLatin1 => match String::from_utf8(string) {
+                Ok(v) => Ok(Value::String(v)),
+                Err(err) => Ok(Value::String(latin1(err.as_bytes()))),
+            },
         }
     }
 
@@ -865,12 +3190,12 @@ impl<R: Read> Deserializer<R> {
     {
         let pos = self.pos;
         let top = self.top()?;
-        if let Value::List(ref mut list) = *top {
-            f(list);
-            Ok(())
-        } else {
-            Self::stack_error("list", top, pos)
+        match *top {
+            Value::List(ref mut list) => f(list),
+            Value::Object(ref mut object) => f(&mut object.listitems),
+            _ => return Self::stack_error("list", top, pos),
         }
+        Ok(())
     }
 
     // Push items from a (key, value, key, value) flattened list onto a (key, value) vec.
@@ -890,143 +3215,459 @@ impl<R: Read> Deserializer<R> {
     {
         let pos = self.pos;
         let top = self.top()?;
-        if let Value::Dict(ref mut dict) = *top {
-            f(dict);
+        match *top {
+            Value::Dict(ref mut dict) => f(dict),
+            Value::Object(ref mut object) => f(&mut object.dictitems),
+            _ => return Self::stack_error("dict", top, pos),
+        }
+        Ok(())
+    }
+
+    // Modify the stack-top set.
+    fn modify_set<F>(&mut self, f: F) -> Result<()>
+        where F: FnOnce(&mut Vec<Value>)
+    {
+        let pos = self.pos;
+        let top = self.top()?;
+        if let Value::Set(ref mut set) = *top {
+            f(set);
             Ok(())
         } else {
-            Self::stack_error("dict", top, pos)
+            Self::stack_error("set", top, pos)
+        }
+    }
+
+    // Push the Value::Global referenced by modname and globname.
+    fn decode_global(&mut self, modname: Vec<u8>, globname: Vec<u8>) -> Result<Value> {
+        let builtin = match (&*modname, &*globname) {
+            (b"_codecs", b"encode") => Some(Global::Encode),
+            (b"__builtin__", b"set") | (b"builtins", b"set") => Some(Global::Set),
+            (b"__builtin__", b"frozenset") | (b"builtins", b"frozenset") =>
+                Some(Global::Frozenset),
+            (b"__builtin__", b"list") | (b"builtins", b"list") => Some(Global::List),
+            (b"__builtin__", b"bytearray") | (b"builtins", b"bytearray") =>
+                Some(Global::Bytearray),
+            (b"__builtin__", b"int") | (b"builtins", b"int") => Some(Global::Int),
+            _ => None,
+        };
+        let modname = String::from_utf8_lossy(&modname);
+        let globname = String::from_utf8_lossy(&globname);
+        // The builtins we decode into plain data are always allowed.
+        if builtin.is_none() && !self.options.global_policy.allows(&modname, &globname) {
+            return self.error(ErrorCode::DisallowedGlobal(modname.into_owned(),
+                                                          globname.into_owned()));
+        }
+        if let Some(ref resolver) = self.options.resolver {
+            if let Some(callback) = resolver.find_class(&modname, &globname) {
+                return Ok(Value::Global(Global::Custom(modname.into_owned(),
+                                                       globname.into_owned(),
+                                                       Hook(Arc::from(callback)))));
+            }
+        }
+        Ok(Value::Global(builtin.unwrap_or_else(|| {
+            Global::Other(modname.into_owned(), globname.into_owned())
+        })))
+    }
+
+    // Push the Value::Global registered with the given extension code.
+    fn decode_extension(&mut self, code: u32) -> Result<()> {
+        let (modname, globname) = match self.options.extensions.get(&code) {
+            Some((modname, globname)) =>
+                (modname.clone().into_bytes(), globname.clone().into_bytes()),
+            None => return self.error(ErrorCode::UnregisteredExtension(code)),
+        };
+        let value = self.decode_global(modname, globname)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    // Handle the REDUCE opcode for the few Global objects we support.  The
+    // argument tuple may be shared with the memo.
+    fn reduce_global(&mut self, global: Value, mut args: Value) -> Result<()> {
+        if let Value::Global(Global::Other(ref module, ref name)) = global {
+            let len = match *args.shared() {
+                Value::Tuple(ref items) => items.len(),
+                _ => 0,
+            };
+            // Below protocol 4, Python writes classes nested in other classes
+            // as `getattr(outer, name)`.
+            if (module == "builtins" || module == "__builtin__") && name == "getattr" && len == 2 {
+                let items = self.tuple_items(args).into_iter().map(|v| {
+                    let v = self.resolve_value(v)?;
+                    Ok(self.unshare(v))
+                }).collect::<Result<Vec<_>>>()?;
+                if let (Value::Global(Global::Other(ref module, ref outer)),
+                        Value::String(ref name)) = (&items[0], &items[1])
+                {
+                    let modname = module.clone().into_bytes();
+                    let qualname = format!("{}.{}", outer, name).into_bytes();
+                    let value = self.decode_global(modname, qualname)?;
+                    self.stack.push(value);
+                    return Ok(());
+                }
+                args = Value::Tuple(items);
+            }
+            // Below protocol 2, Python creates instances as
+            // `copyreg._reconstructor(cls, base, state)`, which is
+            // `object.__new__(cls)` for most classes, and else like
+            // `cls(state)` for subclasses of builtin types.
+            if (module == "copy_reg" || module == "copyreg") && name == "_reconstructor" && len == 3 {
+                let mut items = self.tuple_items(args).into_iter().map(|v| self.resolve_value(v))
+                                    .collect::<Result<Vec<_>>>()?;
+                let state = items.pop().unwrap_or(Value::None);
+                let base = items.pop().unwrap_or(Value::None);
+                let cls = items.pop().unwrap_or(Value::None);
+                let plain = match (base.shared(), state.shared()) {
+                    (&Value::Global(Global::Other(ref module, ref name)), &Value::None) =>
+                        (module == "builtins" || module == "__builtin__") && name == "object",
+                    _ => false,
+                };
+                let cls = self.unshare(cls);
+                return if plain {
+                    self.instantiate(cls, Value::Tuple(Vec::new()), Some(Value::Dict(Vec::new())))
+                } else {
+                    self.instantiate(cls, Value::Tuple(vec![state]), None)
+                };
+            }
+            // `copyreg.__newobj__(cls, *args)` is `cls.__new__(cls, *args)`,
+            // like `NEWOBJ`.
+            if (module == "copy_reg" || module == "copyreg") && name == "__newobj__" && len > 0 {
+                let mut items = self.tuple_items(args);
+                let rest = items.split_off(1);
+                let cls = self.resolve_value(items.pop().unwrap_or(Value::None))?;
+                let cls = self.unshare(cls);
+                return self.instantiate(cls, Value::Tuple(rest), Some(Value::Dict(Vec::new())));
+            }
+            // `copyreg.__newobj_ex__(cls, args, kwargs)` is like `NEWOBJ_EX`.
+            if (module == "copy_reg" || module == "copyreg") && name == "__newobj_ex__" && len == 3 {
+                let mut items = self.tuple_items(args);
+                let kwargs = self.resolve_value(items.pop().unwrap_or(Value::None))?;
+                if let Value::Dict(_) = *kwargs.shared() {} else {
+                    return Self::stack_error("dict", kwargs.shared(), self.pos);
+                }
+                let args = self.resolve_value(items.pop().unwrap_or(Value::None))?;
+                if let Value::Tuple(_) = *args.shared() {} else {
+                    return Self::stack_error("tuple", args.shared(), self.pos);
+                }
+                let cls = self.resolve_value(items.pop().unwrap_or(Value::None))?;
+                let cls = self.unshare(cls);
+                return self.instantiate(cls, args, Some(kwargs));
+            }
+        }
+        let global = match global {
+            Value::Global(global) => global,
+            other => return Self::stack_error("global reference", &other, self.pos),
+        };
+        match global {
+            Global::Set | Global::Frozenset | Global::List | Global::Int => {
+                let arg = self.tuple_items(args).pop();
+                self.reduce_builtin(global, arg)
+            }
+            Global::Bytearray => {
+                // On Py2, the call is encoded as bytearray(u"foo", "latin-1").
+                let mut items = self.tuple_items(args);
+                items.truncate(1);
+                self.reduce_builtin(global, items.pop())
+            }
+            Global::Encode => {
+                // Byte object encoded as _codecs.encode(x, 'latin1')
+                let mut items = self.tuple_items(args);
+                let encoding = self.resolve(items.pop());  // Encoding, always latin1
+                match encoding.as_ref().map(Value::shared) {
+                    Some(&Value::String(_)) | Some(&Value::BorrowedString(..)) => { }
+                    _ => return self.error(ErrorCode::InvalidValue("encode() arg".into())),
+                }
+                self.reduce_builtin(global, items.pop())
+            }
+            Global::Custom(module, name, reconstruct) => {
+                self.stack.push(Value::new_object(module, name, args, None, Some(reconstruct)));
+                Ok(())
+            }
+            Global::Other(module, name) => {
+                if self.options.decode_objects {
+                    self.stack.push(Value::new_object(module, name, args, None, None));
+                } else {
+                    // Anything else; just keep it on the stack as an opaque object.
+                    // If it is a class object, it will get replaced later when the
+                    // class is instantiated.
+                    self.stack.push(Value::Global(Global::Other(module, name)));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Take the items out of an argument tuple, copying them if it is shared.
+    fn tuple_items(&mut self, args: Value) -> Vec<Value> {
+        match self.unshare(args) {
+            Value::Tuple(items) => items,
+            _ => Vec::new(),
+        }
+    }
+
+    // Call one of the builtins that are applied to a single argument.  If the
+    // argument is still shared with the memo, the call is deferred until the
+    // result is converted, so that the argument is copied only if needed.
+    #[allow(clippy::match_like_matches_macro)]
+    fn reduce_builtin(&mut self, global: Global, arg: Option<Value>) -> Result<()> {
+        let arg = match self.resolve(arg) {
+            // Builtins of builtins are applied right away.
+            Some(arg @ Value::Apply(..)) => self.unshare(arg),
+            Some(arg) => arg,
+            None => return self.error(ErrorCode::InvalidValue(format!("{}() arg", global.builtin_name()))),
+        };
+        let valid = match (&global, arg.shared()) {
+            (&Global::Set, &Value::List(_)) | (&Global::Frozenset, &Value::List(_)) |
+            (&Global::List, &Value::List(_)) | (&Global::Int, &Value::Int(_)) |
+            (&Global::Bytearray, &Value::Bytes(_)) | (&Global::Bytearray, &Value::BorrowedBytes(..)) |
+            (&Global::Bytearray, &Value::String(_)) | (&Global::Bytearray, &Value::BorrowedString(..)) |
+            (&Global::Encode, &Value::String(_)) | (&Global::Encode, &Value::BorrowedString(..)) => true,
+            _ => false,
+        };
+        if !valid {
+            return self.error(ErrorCode::InvalidValue(format!("{}() arg", global.builtin_name())));
+        }
+        let value = match arg {
+            Value::Shared(arg) => match Arc::try_unwrap(arg) {
+                Ok(arg) => self.apply_builtin(global, arg),
+                Err(arg) => Value::Apply(global, arg),
+            },
+            arg => self.apply_builtin(global, arg),
+        };
+        self.stack.push(value);
+        Ok(())
+    }
+
+    // Compute the result of a builtin for an argument checked by
+    // `reduce_builtin`.
+    fn apply_builtin(&self, global: Global, arg: Value) -> Value {
+        match (global, self.make_owned(arg)) {
+            (Global::Set, Value::List(items)) => Value::Set(items),
+            (Global::Frozenset, Value::List(items)) => Value::FrozenSet(items),
+            (Global::Bytearray, Value::String(string)) => {
+                // The code points in the string are actually bytes values.
+                // So we need to collect them individually.
+                Value::Bytes(string.chars().map(|ch| ch as u32 as u8).collect())
+            }
+            (Global::Encode, Value::String(string)) => {
+                // Now we have to convert the string to latin-1 encoded bytes.
+                // It never contains codepoints above 0xff.
+                Value::Bytes(string.chars().map(|ch| ch as u8).collect())
+            }
+            // list(), int() and bytearray() of bytes return their argument.
+            (_, arg) => arg,
+        }
+    }
+
+    // Handle the INST, OBJ, NEWOBJ and NEWOBJ_EX opcodes.  Unless we keep
+    // objects, push an empty dictionary instead of the class instance.
+    fn instantiate(&mut self, cls: Value, args: Value, kwargs: Option<Value>) -> Result<()> {
+        match cls {
+            Value::Global(Global::Custom(module, name, reconstruct)) => {
+                self.stack.push(Value::new_object(module, name, args, kwargs,
+                                                  Some(reconstruct)));
+            }
+            Value::Global(Global::Other(module, name)) if self.options.decode_objects => {
+                self.stack.push(Value::new_object(module, name, args, kwargs, None));
+            }
+            _ => self.stack.push(Value::Dict(Vec::new())),
+        }
+        Ok(())
+    }
+
+    // Instances of classes with `__slots__` have a `(dict, slots)` tuple as
+    // state, and both parts are set as attributes.  Merge them into one dict,
+    // like the state of other instances.
+    #[allow(clippy::match_like_matches_macro)]
+    fn merge_slot_state(&mut self, state: Value) -> Value {
+        fn is_pair(value: &Value) -> bool {
+            match *value.shared() {
+                Value::Tuple(ref items) => items.len() == 2,
+                _ => false,
+            }
+        }
+        let pair = match state {
+            Value::MemoRef(id) => self.memo.get(&id).map(|(value, _)| is_pair(value.get())),
+            ref value => Some(is_pair(value)),
+        };
+        if pair != Some(true) {
+            return state;
+        }
+        let state = self.resolve(Some(state)).unwrap_or(Value::None);
+        let mut items = self.tuple_items(state);
+        let slots = items.pop().and_then(|v| self.resolve(Some(v))).unwrap_or(Value::None);
+        let dict = items.pop().and_then(|v| self.resolve(Some(v))).unwrap_or(Value::None);
+        let slots_dict = match *slots.shared() {
+            Value::Dict(_) => true,
+            _ => false,
+        };
+        match *dict.shared() {
+            // The slots are kept shared, only merging copies them.
+            Value::None if slots_dict => slots,
+            Value::Dict(_) if slots_dict => match (self.unshare(dict), self.unshare(slots)) {
+                (Value::Dict(mut dict), Value::Dict(slots)) => {
+                    dict.extend(slots);
+                    Value::Dict(dict)
+                }
+                (dict, slots) => Value::Tuple(vec![dict, slots]),
+            },
+            _ => Value::Tuple(vec![dict, slots]),
+        }
+    }
+
+    // Replace a persistent id by the object it refers to.
+    fn load_persistent(&mut self, opcode: u8, pid: Value) -> Result<()> {
+        let callback = match self.options.persistent_load {
+            Some(ref callback) => callback.clone(),
+            None => return self.error(ErrorCode::Unsupported(opcode as char)),
+        };
+        let pid = self.convert_value_in_place(pid)?;
+        let value = callback(pid)?;
+        self.stack.push(Value::try_from(value)?);
+        Ok(())
+    }
+
+    fn stack_error<T>(what: &'static str, value: &Value, pos: usize) -> Result<T> {
+        let it = format!("{:?}", value);
+        Err(Error::eval(ErrorCode::InvalidStackTop(what, it), pos))
+    }
+
+    fn error<T>(&self, reason: ErrorCode) -> Result<T> {
+        Err(Error::eval(reason, self.pos))
+    }
+
+    // Add the current position to an error, and the opcode that was executed
+    // when it occurred.  Context that is already there is kept.
+    #[cold]
+    fn locate(&self, mut err: Error, opcode: Option<u8>) -> Error {
+        if let Error::Syntax(_, ref mut context) = err {
+            if context.is_none() {
+                *context = Some(Box::new(ErrorContext::new(self.pos)));
+            }
+        }
+        if let (Some(opcode), Some(context)) = (opcode, err.context_mut()) {
+            if context.opcode.is_none() {
+                context.opcode = Some((opcode_name(opcode).unwrap_or("unknown"), self.checkpoint.0));
+            }
+        }
+        err
+    }
+
+    // Add the position and a path component to an error from an item of a
+    // container.  The component is a list index or the dict key.
+    #[cold]
+    fn locate_item(&self, err: Error, index: usize, key: Option<&Value>) -> Error {
+        let mut err = self.locate(err, None);
+        if let Some(context) = err.context_mut() {
+            match key {
+                None => context.prepend_path(&index.to_string(), false),
+                Some(key) => match self.path_key(key) {
+                    Some(key) => context.prepend_path(&key, true),
+                    None => context.prepend_path(&self.describe(key), false),
+                },
+            }
         }
+        err
     }
 
-    // Modify the stack-top set.
-    fn modify_set<F>(&mut self, f: F) -> Result<()>
-        where F: FnOnce(&mut Vec<Value>)
-    {
-        let pos = self.pos;
-        let top = self.top()?;
-        if let Value::Set(ref mut set) = *top {
-            f(set);
-            Ok(())
+    // Return a dict key as a path component, if it is a string.
+    fn path_key(&self, key: &Value) -> Option<String> {
+        match *key {
+            Value::String(ref s) => Some(s.clone()),
+            Value::BorrowedString(start, end) => Some(self.borrowed_str(start, end).to_owned()),
+            Value::MemoRef(id) => self.memo.get(&id).and_then(|(value, _)| self.path_key(value.get())),
+            _ => None,
+        }
+    }
+
+    // Convert a value while the stream is still being parsed.  Unlike
+    // `convert_value`, this leaves all memo entries in place, since later
+    // opcodes may still refer to them.
+    fn convert_value_in_place(&mut self, value: Value) -> Result<value::Value> {
+        let keep_memo = mem::replace(&mut self.keep_memo, true);
+        let result = self.convert_value(value);
+        self.keep_memo = keep_memo;
+        result
+    }
+
+    // Convert a memoized value, turning references to it from within itself
+    // into `Ref`s instead of failing.
+    fn convert_shared(&mut self, memo_id: MemoId) -> Result<value::Value> {
+        if let Some(referenced) = self.converting.get_mut(&memo_id) {
+            *referenced = true;
+            return Ok(value::Value::Ref(memo_id));
+        }
+        self.converting.insert(memo_id, false);
+        let result = self.resolve_recursive(memo_id, (), |slf, (), value| slf.convert_shared_value(value));
+        let referenced = self.converting.remove(&memo_id) == Some(true);
+        let value = result?;
+        if referenced {
+            Ok(value::Value::Shared(memo_id, Box::new(value)))
         } else {
-            Self::stack_error("set", top, pos)
+            Ok(value)
         }
     }
 
-    // Push the Value::Global referenced by modname and globname.
-    fn decode_global(&mut self, modname: Vec<u8>, globname: Vec<u8>) -> Result<Value> {
-        let value = match (&*modname, &*globname) {
-            (b"_codecs", b"encode") => Value::Global(Global::Encode),
-            (b"__builtin__", b"set") | (b"builtins", b"set") =>
-                Value::Global(Global::Set),
-            (b"__builtin__", b"frozenset") | (b"builtins", b"frozenset") =>
-                Value::Global(Global::Frozenset),
-            (b"__builtin__", b"list") | (b"builtins", b"list") =>
-                Value::Global(Global::List),
-            (b"__builtin__", b"bytearray") | (b"builtins", b"bytearray") =>
-                Value::Global(Global::Bytearray),
-            (b"__builtin__", b"int") | (b"builtins", b"int") =>
-                Value::Global(Global::Int),
-            _ => Value::Global(Global::Other),
-        };
-        Ok(value)
+    fn convert_value(&mut self, value: Value) -> Result<value::Value> {
+        if let Err(err) = self.enter_value(&value) {
+            value.dismantle();
+            return Err(err);
+        }
+        let result = self.convert_value_inner(value);
+        self.depth -= 1;
+        result
     }
 
-    // Handle the REDUCE opcode for the few Global objects we support.
-    fn reduce_global(&mut self, global: Value, mut argtuple: Vec<Value>) -> Result<()> {
-        match global {
-            Value::Global(Global::Set) => {
-                match self.resolve(argtuple.pop()) {
-                    Some(Value::List(items)) => {
-                        self.stack.push(Value::Set(items));
-                        Ok(())
-                    }
-                    _ => self.error(ErrorCode::InvalidValue("set() arg".into())),
-                }
-            }
-            Value::Global(Global::Frozenset) => {
-                match self.resolve(argtuple.pop()) {
-                    Some(Value::List(items)) => {
-                        self.stack.push(Value::FrozenSet(items));
-                        Ok(())
-                    }
-                    _ => self.error(ErrorCode::InvalidValue("frozenset() arg".into())),
-                }
-            }
-            Value::Global(Global::Bytearray) => {
-                // On Py2, the call is encoded as bytearray(u"foo", "latin-1").
-                argtuple.truncate(1);
-                match self.resolve(argtuple.pop()) {
-                    Some(Value::Bytes(bytes)) => {
-                        self.stack.push(Value::Bytes(bytes));
-                        Ok(())
-                    }
-                    Some(Value::String(string)) => {
-                        // The code points in the string are actually bytes values.
-                        // So we need to collect them individually.
-                        self.stack.push(Value::Bytes(
-                            string.chars().map(|ch| ch as u32 as u8).collect()));
-                        Ok(())
-                    }
-                    _ => self.error(ErrorCode::InvalidValue("bytearray() arg".into())),
-                }
-            }
-            Value::Global(Global::List) => {
-                match self.resolve(argtuple.pop()) {
-                    Some(Value::List(items)) => {
-                        self.stack.push(Value::List(items));
-                        Ok(())
-                    }
-                    _ => self.error(ErrorCode::InvalidValue("list() arg".into())),
-                }
-            }
-            Value::Global(Global::Int) => {
-                match self.resolve(argtuple.pop()) {
-                    Some(Value::Int(integer)) => {
-                        self.stack.push(Value::Int(integer));
-                        Ok(())
-                    }
-                    _ => self.error(ErrorCode::InvalidValue("int() arg".into())),
-                }
-            }
-            Value::Global(Global::Encode) => {
-                // Byte object encoded as _codecs.encode(x, 'latin1')
-                match self.resolve(argtuple.pop()) {  // Encoding, always latin1
-                    Some(Value::String(_)) => { }
-                    _ => return self.error(ErrorCode::InvalidValue("encode() arg".into())),
-                }
-                match self.resolve(argtuple.pop()) {
-                    Some(Value::String(s)) => {
-                        // Now we have to convert the string to latin-1
-                        // encoded bytes.  It never contains codepoints
-                        // above 0xff.
-                        let bytes = s.chars().map(|ch| ch as u8).collect();
-                        self.stack.push(Value::Bytes(bytes));
-                        Ok(())
-                    }
-                    _ => self.error(ErrorCode::InvalidValue("encode() arg".into())),
-                }
-            }
-            Value::Global(Global::Other) => {
-                // Anything else; just keep it on the stack as an opaque object.
-                // If it is a class object, it will get replaced later when the
-                // class is instantiated.
-                self.stack.push(Value::Global(Global::Other));
-                Ok(())
-            }
-            other => Self::stack_error("global reference", &other, self.pos),
+    // Convert a value from the memo, which is copied if it is still shared.
+    fn convert_shared_value(&mut self, value: Memoized) -> Result<value::Value> {
+        match value {
+            Memoized::Value(value) => self.convert_value(value),
+            Memoized::Shared(value) => match Arc::try_unwrap(value) {
+                Ok(value) => self.convert_value(value),
+                Err(shared) => self.convert_ref(&shared),
+            },
         }
     }
 
-    fn stack_error<T>(what: &'static str, value: &Value, pos: usize) -> Result<T> {
-        let it = format!("{:?}", value);
-        Err(Error::Eval(ErrorCode::InvalidStackTop(what, it), pos))
+    // Like `convert_value`, but copy the contents of containers item by item
+    // instead of cloning the whole value first.
+    fn convert_ref(&mut self, value: &Value) -> Result<value::Value> {
+        self.enter_value(value)?;
+        let result = self.convert_ref_inner(value);
+        self.depth -= 1;
+        result
     }
 
-    fn error<T>(&self, reason: ErrorCode) -> Result<T> {
-        Err(Error::Eval(reason, self.pos))
+    fn convert_ref_inner(&mut self, value: &Value) -> Result<value::Value> {
+        match *value {
+            Value::List(ref v) => {
+                v.iter().map(|v| self.convert_ref(v)).collect::<Result<_>>().map(value::Value::List)
+            },
+            Value::Tuple(ref v) => {
+                v.iter().map(|v| self.convert_ref(v)).collect::<Result<_>>().map(value::Value::Tuple)
+            },
+            Value::Set(ref v) => {
+                v.iter().map(|v| self.convert_ref(v).and_then(|rv| rv.into_hashable()))
+                        .collect::<Result<_>>().map(value::Value::Set)
+            },
+            Value::FrozenSet(ref v) => {
+                v.iter().map(|v| self.convert_ref(v).and_then(|rv| rv.into_hashable()))
+                        .collect::<Result<_>>().map(value::Value::FrozenSet)
+            },
+            Value::Dict(ref v) => {
+                let mut map = BTreeMap::new();
+                for (key, value) in v {
+                    let real_key = self.convert_ref(key).and_then(|rv| rv.into_hashable())?;
+                    let real_value = self.convert_ref(value)?;
+                    map.insert(real_key, real_value);
+                }
+                Ok(value::Value::Dict(map))
+            },
+            _ => self.convert_value_inner(value.clone()),
+        }
     }
 
-    fn convert_value(&mut self, value: Value) -> Result<value::Value> {
+    fn convert_value_inner(&mut self, value: Value) -> Result<value::Value> {
         match value {
             Value::None => Ok(value::Value::None),
             Value::Bool(v) => Ok(value::Value::Bool(v)),
@@ -1041,57 +3682,236 @@ impl<R: Read> Deserializer<R> {
             Value::F64(v) => Ok(value::Value::F64(v)),
             Value::Bytes(v) => Ok(value::Value::Bytes(v)),
             Value::String(v) => Ok(value::Value::String(v)),
-            Value::List(v) => {
-                let new = v.into_iter().map(|v| self.convert_value(v))
-                                       .collect::<Result<_>>();
-                Ok(value::Value::List(new?))
-            },
-            Value::Tuple(v) => {
-                let new = v.into_iter().map(|v| self.convert_value(v))
-                                       .collect::<Result<_>>();
-                Ok(value::Value::Tuple(new?))
-            },
-            Value::Set(v) => {
-                let new = v.into_iter().map(|v| self.convert_value(v)
-                                            .and_then(|rv| rv.into_hashable()))
-                                       .collect::<Result<_>>();
-                Ok(value::Value::Set(new?))
-            },
-            Value::FrozenSet(v) => {
-                let new = v.into_iter().map(|v| self.convert_value(v)
-                                            .and_then(|rv| rv.into_hashable()))
-                                       .collect::<Result<_>>();
-                Ok(value::Value::FrozenSet(new?))
-            },
-            Value::Dict(v) => {
-                let mut map = BTreeMap::new();
-                for (key, value) in v {
-                    let real_key = self.convert_value(key).and_then(|rv| rv.into_hashable())?;
-                    let real_value = self.convert_value(value)?;
-                    map.insert(real_key, real_value);
-                }
-                Ok(value::Value::Dict(map))
+            v @ Value::BorrowedBytes(..) | v @ Value::BorrowedString(..) => {
+                let owned = self.make_owned(v);
+                self.convert_value_inner(owned)
             },
+            Value::List(v) => self.convert_items(v).map(value::Value::List),
+            Value::Tuple(v) => self.convert_items(v).map(value::Value::Tuple),
+            Value::Set(v) => self.convert_hashable_items(v).map(value::Value::Set),
+            Value::FrozenSet(v) => self.convert_hashable_items(v).map(value::Value::FrozenSet),
+            Value::Dict(v) => self.convert_pairs(v).map(value::Value::Dict),
             Value::MemoRef(memo_id) => {
-                self.resolve_recursive(memo_id, (), |slf, (), value| slf.convert_value(value))
+                if self.options.decode_recursive {
+                    self.convert_shared(memo_id)
+                } else {
+                    self.resolve_recursive(memo_id, (), |slf, (), value| slf.convert_shared_value(value))
+                }
             },
             Value::Global(_) => {
                 if self.options.replace_unresolved_globals {
                     Ok(value::Value::None)
                 } else {
-                    Err(Error::Syntax(ErrorCode::UnresolvedGlobal))
+                    Err(Error::Syntax(ErrorCode::UnresolvedGlobal, None))
+                }
+            },
+            Value::Shared(value) => match Arc::try_unwrap(value) {
+                Ok(value) => self.convert_value_inner(value),
+                Err(shared) => {
+                    // The references within it will be converted again.
+                    let keep_memo = mem::replace(&mut self.keep_memo, true);
+                    let result = self.convert_ref_inner(&shared);
+                    self.keep_memo = keep_memo;
+                    result
+                }
+            },
+            value @ Value::Apply(..) => {
+                let value = self.unshare(value);
+                self.convert_value_inner(value)
+            },
+            Value::Object(mut object) => {
+                let converted = self.convert_object(&mut object);
+                let reconstruct = object.reconstruct.take();
+                // After an error, the parts not converted yet are left over.
+                Value::Object(object).dismantle();
+                match reconstruct {
+                    Some(reconstruct) => reconstruct(converted?),
+                    None => Ok(value::Value::Object(Box::new(converted?))),
                 }
             },
         }
     }
+
+    // Convert the items of a container, dropping the rest after an error.
+    fn convert_items(&mut self, items: Vec<Value>) -> Result<Vec<value::Value>> {
+        let mut converted = Vec::with_capacity(items.len());
+        let mut items = items.into_iter();
+        while let Some(item) = items.next() {
+            match self.convert_value(item) {
+                Ok(item) => converted.push(item),
+                Err(err) => {
+                    items.for_each(Value::dismantle);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(converted)
+    }
+
+    fn convert_hashable_items(&mut self, items: Vec<Value>) -> Result<BTreeSet<value::HashableValue>> {
+        self.convert_items(items)?.into_iter().map(|item| item.into_hashable()).collect()
+    }
+
+    fn convert_pairs(&mut self, pairs: Vec<(Value, Value)>)
+                     -> Result<BTreeMap<value::HashableValue, value::Value>> {
+        let mut map = BTreeMap::new();
+        let mut pairs = pairs.into_iter();
+        while let Some((key, value)) = pairs.next() {
+            let converted = self.convert_value(key).and_then(|rv| rv.into_hashable());
+            let converted = match converted {
+                Ok(key) => self.convert_value(value).map(|value| (key, value)),
+                Err(err) => {
+                    value.dismantle();
+                    Err(err)
+                }
+            };
+            match converted {
+                Ok((key, value)) => { map.insert(key, value); }
+                Err(err) => {
+                    for (key, value) in pairs {
+                        key.dismantle();
+                        value.dismantle();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    // Convert the parts of an object, taking them out of it one by one.
+    fn convert_object(&mut self, object: &mut Object) -> Result<value::Object> {
+        let args = match self.convert_value_inner(mem::replace(&mut object.args, Value::None))? {
+            value::Value::Tuple(args) => args,
+            _ => Vec::new(),
+        };
+        let kwargs = match object.kwargs.take() {
+            None => None,
+            Some(kwargs) => {
+                let mut map = BTreeMap::new();
+                if let value::Value::Dict(kwargs) = self.convert_value_inner(kwargs)? {
+                    for (key, value) in kwargs {
+                        match key {
+                            value::HashableValue::String(key) => { map.insert(key, value); }
+                            _ => return Err(Error::Syntax(ErrorCode::InvalidValue(
+                                "keyword argument name".into()), None)),
+                        }
+                    }
+                }
+                Some(map)
+            }
+        };
+        let state = match object.state.take() {
+            None => None,
+            Some(state) => Some(self.convert_value(state)?),
+        };
+        let listitems = self.convert_items(mem::take(&mut object.listitems))?;
+        let dictitems = self.convert_pairs(mem::take(&mut object.dictitems))?;
+        Ok(value::Object {
+            module: mem::take(&mut object.module), name: mem::take(&mut object.name),
+            args, kwargs, state, listitems, dictitems,
+        })
+    }
+}
+
+impl TryFrom<value::Value> for Value {
+    type Error = Error;
+
+    fn try_from(value: value::Value) -> Result<Value> {
+        Ok(match value {
+            value::Value::None => Value::None,
+            value::Value::Bool(v) => Value::Bool(v),
+            value::Value::I64(v) => Value::I64(v),
+            value::Value::Int(v) => Value::Int(v),
+            value::Value::F64(v) => Value::F64(v),
+            value::Value::Bytes(v) => Value::Bytes(v),
+            value::Value::String(v) => Value::String(v),
+            value::Value::List(v) =>
+                Value::List(v.into_iter().map(TryFrom::try_from).collect::<Result<_>>()?),
+            value::Value::Tuple(v) =>
+                Value::Tuple(v.into_iter().map(TryFrom::try_from).collect::<Result<_>>()?),
+            value::Value::Set(v) =>
+                Value::Set(v.into_iter().map(hashable_into_internal).collect()),
+            value::Value::FrozenSet(v) =>
+                Value::FrozenSet(v.into_iter().map(hashable_into_internal).collect()),
+            value::Value::Dict(v) => {
+                let mut items = Vec::with_capacity(v.len());
+                for (key, value) in v {
+                    items.push((hashable_into_internal(key), Value::try_from(value)?));
+                }
+                Value::Dict(items)
+            },
+            value::Value::Object(object) => {
+                let object = *object;
+                let kwargs = match object.kwargs {
+                    None => None,
+                    Some(kwargs) => Some(Value::Dict(kwargs.into_iter().map(|(k, v)| {
+                        Value::try_from(v).map(|v| (Value::String(k), v))
+                    }).collect::<Result<_>>()?)),
+                };
+                let state = match object.state {
+                    None => None,
+                    Some(state) => Some(Value::try_from(state)?),
+                };
+                let mut dictitems = Vec::with_capacity(object.dictitems.len());
+                for (key, value) in object.dictitems {
+                    dictitems.push((hashable_into_internal(key), Value::try_from(value)?));
+                }
+                Value::Object(Box::new(Object {
+                    module: object.module,
+                    name: object.name,
+                    args: Value::Tuple(object.args.into_iter().map(TryFrom::try_from)
+                                                               .collect::<Result<_>>()?),
+                    kwargs,
+                    state,
+                    listitems: object.listitems.into_iter().map(TryFrom::try_from)
+                                                           .collect::<Result<_>>()?,
+                    dictitems,
+                    reconstruct: None,
+                }))
+            },
+            // Values with cycles can't be put back into the pickle stack.
+            value::Value::Shared(..) | value::Value::Ref(_) =>
+                return Err(Error::Syntax(ErrorCode::Recursive, None)),
+        })
+    }
+}
+
+fn hashable_into_internal(value: value::HashableValue) -> Value {
+    match value {
+        value::HashableValue::None => Value::None,
+        value::HashableValue::Bool(v) => Value::Bool(v),
+        value::HashableValue::I64(v) => Value::I64(v),
+        value::HashableValue::Int(v) => Value::Int(v),
+        value::HashableValue::F64(v) => Value::F64(v),
+        value::HashableValue::Bytes(v) => Value::Bytes(v),
+        value::HashableValue::String(v) => Value::String(v),
+        value::HashableValue::Tuple(v) =>
+            Value::Tuple(v.into_iter().map(hashable_into_internal).collect()),
+        value::HashableValue::FrozenSet(v) =>
+            Value::FrozenSet(v.into_iter().map(hashable_into_internal).collect()),
+    }
 }
 
-impl<'de: 'a, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
+impl<'de: 'a, 'a, R: Read, B: Input<'de>> de::Deserializer<'de> for &'a mut Deserializer<R, B> {
     type Error = Error;
 
     fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value> {
-        let value = self.get_next_value()?;
-        match value {
+        let mut value = self.get_next_value()?;
+        // Like without keeping objects, visit the state of objects that are
+        // not reconstructed, at the object's depth.
+        while let Value::Object(object) = value {
+            if object.reconstruct.is_some() {
+                value = Value::Object(object);
+                break;
+            }
+            value = object.state.unwrap_or_else(|| Value::Dict(Vec::new()));
+        }
+        if let Err(err) = self.enter_value(&value) {
+            value.dismantle();
+            return Err(err);
+        }
+        let result = match value {
             Value::None => visitor.visit_unit(),
             Value::Bool(v) => visitor.visit_bool(v),
             Value::I64(v) => visitor.visit_i64(v),
@@ -1099,18 +3919,21 @@ impl<'de: 'a, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
                 if let Some(i) = v.to_i64() {
                     visitor.visit_i64(i)
                 } else {
-                    return Err(Error::Syntax(ErrorCode::InvalidValue("integer too large".into())));
+                    Err(Error::Syntax(ErrorCode::InvalidValue("integer too large".into()), None))
                 }
             },
             Value::F64(v) => visitor.visit_f64(v),
             Value::Bytes(v) => visitor.visit_byte_buf(v),
             Value::String(v) => visitor.visit_string(v),
+            Value::BorrowedBytes(start, end) => visitor.visit_borrowed_bytes(self.rdr.borrowed(start, end)),
+            Value::BorrowedString(start, end) => visitor.visit_borrowed_str(self.borrowed_str(start, end)),
             Value::List(v) => {
                 let len = v.len();
                 visitor.visit_seq(SeqAccess {
                     de: &mut self,
                     iter: v.into_iter(),
                     len,
+                    index: 0,
                 })
             },
             Value::Tuple(v) => {
@@ -1118,6 +3941,7 @@ impl<'de: 'a, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
                     len: v.len(),
                     iter: v.into_iter(),
                     de: &mut self,
+                    index: 0,
                 })
             }
             Value::Set(v) | Value::FrozenSet(v) => {
@@ -1125,6 +3949,7 @@ impl<'de: 'a, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
                     de: &mut self,
                     len: v.len(),
                     iter: v.into_iter(),
+                    index: 0,
                 })
             },
             Value::Dict(v) => {
@@ -1132,28 +3957,44 @@ impl<'de: 'a, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
                 visitor.visit_map(MapAccess {
                     de: &mut self,
                     iter: v.into_iter(),
+                    key: None,
                     value: None,
                     len,
+                    index: 0,
                 })
             },
             Value::MemoRef(memo_id) => {
                 self.resolve_recursive(memo_id, visitor, |slf, visitor, value| {
-                    slf.value = Some(value);
+                    slf.value = Some(value.into_value());
                     slf.deserialize_any(visitor)
                 })
             },
+            value @ Value::Shared(_) | value @ Value::Apply(..) => {
+                self.value = Some(self.unshare(value));
+                self.deserialize_any(visitor)
+            },
             Value::Global(_) => {
                 if self.options.replace_unresolved_globals {
                     visitor.visit_unit()
                 } else {
-                    Err(Error::Syntax(ErrorCode::UnresolvedGlobal))
+                    Err(Error::Syntax(ErrorCode::UnresolvedGlobal, None))
                 }
             },
-        }
+            Value::Object(object) => {
+                self.convert_value_inner(Value::Object(object)).and_then(|value| {
+                    value_impls::Deserializer::new(value).deserialize_any(visitor)
+                })
+            },
+        };
+        self.depth -= 1;
+        result.map_err(|err| self.locate(err, None))
     }
 
     #[inline]
     fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.at_streamable()? {
+            return visitor.visit_some(self);
+        }
         let value = self.get_next_value()?;
         match value {
             Value::None => visitor.visit_none(),
@@ -1164,6 +4005,25 @@ impl<'de: 'a, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
         }
     }
 
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.at_streamable()? {
+            self.deserialize_streamed(visitor)
+        } else {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    #[inline]
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    #[inline]
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str],
+                                           visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
     #[inline]
     fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
         visitor.visit_newtype_struct(self)
@@ -1172,21 +4032,21 @@ impl<'de: 'a, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
     #[inline]
     fn deserialize_enum<V: Visitor<'de>>(mut self, _name: &'static str, _variants: &'static [&'static str],
                                     visitor: V) -> Result<V::Value> {
-        visitor.visit_enum(VariantAccess { de: &mut self })
+        visitor.visit_enum(VariantAccess { de: &mut self }).map_err(|err| self.locate(err, None))
     }
 
     forward_to_deserialize_any! {
-        bool u8 u16 u32 u64 i8 i16 i32 i64 f32 f64 char str string unit seq
-        bytes byte_buf map tuple_struct struct identifier
+        bool u8 u16 u32 u64 i8 i16 i32 i64 f32 f64 char str string unit
+        bytes byte_buf tuple_struct identifier
         tuple ignored_any unit_struct
     }
 }
 
-struct VariantAccess<'a, R: Read + 'a> {
-    de: &'a mut Deserializer<R>,
+struct VariantAccess<'a, R: Read + 'a, B: 'a> {
+    de: &'a mut Deserializer<R, B>,
 }
 
-impl<'de: 'a, 'a, R: Read + 'a> de::EnumAccess<'de> for VariantAccess<'a, R> {
+impl<'de: 'a, 'a, R: Read + 'a, B: Input<'de> + 'a> de::EnumAccess<'de> for VariantAccess<'a, R, B> {
     type Error = Error;
     type Variant = Self;
 
@@ -1209,7 +4069,7 @@ impl<'de: 'a, 'a, R: Read + 'a> de::EnumAccess<'de> for VariantAccess<'a, R> {
             Value::Dict(mut v) => {
                 if v.len() != 1 {
                     Err(Error::Syntax(ErrorCode::Structure("enum variants must \
-                                                            have one dict entry".into())))
+                                                            have one dict entry".into()), None))
                 } else {
                     let (name, args) = v.pop().unwrap();
                     self.de.value = Some(name);
@@ -1220,24 +4080,28 @@ impl<'de: 'a, 'a, R: Read + 'a> de::EnumAccess<'de> for VariantAccess<'a, R> {
             }
             Value::MemoRef(memo_id) => {
                 self.de.resolve_recursive(memo_id, (), |slf, (), value| {
-                    slf.value = Some(value);
+                    slf.value = Some(value.into_value());
                     Ok(())
                 })?;
                 // retry with memo resolved
                 self.variant_seed(seed)
             }
-            s @ Value::String(_) => {
+            value @ Value::Shared(_) | value @ Value::Apply(..) => {
+                self.de.value = Some(self.de.unshare(value));
+                self.variant_seed(seed)
+            }
+            s @ Value::String(_) | s @ Value::BorrowedString(..) => {
                 self.de.value = Some(s);
                 let val = seed.deserialize(&mut *self.de)?;
                 Ok((val, self))
             }
             _ => Err(Error::Syntax(ErrorCode::Structure("enums must be represented as \
-                                                         dicts or tuples".into())))
+                                                         dicts or tuples".into()), None))
         }
     }
 }
 
-impl<'de: 'a, 'a, R: Read + 'a> de::VariantAccess<'de> for VariantAccess<'a, R> {
+impl<'de: 'a, 'a, R: Read + 'a, B: Input<'de> + 'a> de::VariantAccess<'de> for VariantAccess<'a, R, B> {
     type Error = Error;
 
     fn unit_variant(self) -> Result<()> {
@@ -1257,21 +4121,26 @@ impl<'de: 'a, 'a, R: Read + 'a> de::VariantAccess<'de> for VariantAccess<'a, R>
     }
 }
 
-struct SeqAccess<'a, R: Read + 'a> {
-    de: &'a mut Deserializer<R>,
+struct SeqAccess<'a, R: Read + 'a, B: 'a> {
+    de: &'a mut Deserializer<R, B>,
     iter: vec::IntoIter<Value>,
     len: usize,
+    index: usize,
 }
 
-impl<'de: 'a, 'a, R: Read> de::SeqAccess<'de> for SeqAccess<'a, R> {
+impl<'de: 'a, 'a, R: Read, B: Input<'de>> de::SeqAccess<'de> for SeqAccess<'a, R, B> {
     type Error = Error;
 
     fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
         match self.iter.next() {
             Some(value) => {
                 self.len -= 1;
+                self.index += 1;
                 self.de.value = Some(value);
-                Ok(Some(seed.deserialize(&mut *self.de)?))
+                match seed.deserialize(&mut *self.de) {
+                    Ok(value) => Ok(Some(value)),
+                    Err(err) => Err(self.de.locate_item(err, self.index - 1, None)),
+                }
             }
             None => Ok(None),
         }
@@ -1282,23 +4151,37 @@ impl<'de: 'a, 'a, R: Read> de::SeqAccess<'de> for SeqAccess<'a, R> {
     }
 }
 
-struct MapAccess<'a, R: Read + 'a> {
-    de: &'a mut Deserializer<R>,
+// Items that weren't visited are dropped without recursion.
+impl<'a, R: Read + 'a, B: 'a> Drop for SeqAccess<'a, R, B> {
+    fn drop(&mut self) {
+        self.iter.by_ref().for_each(Value::dismantle);
+    }
+}
+
+struct MapAccess<'a, R: Read + 'a, B: 'a> {
+    de: &'a mut Deserializer<R, B>,
     iter: vec::IntoIter<(Value, Value)>,
+    key: Option<Value>,    // kept for the error path, see `Value::path_key`
     value: Option<Value>,
     len: usize,
+    index: usize,
 }
 
-impl<'de: 'a, 'a, R: Read> de::MapAccess<'de> for MapAccess<'a, R> {
+impl<'de: 'a, 'a, R: Read, B: Input<'de>> de::MapAccess<'de> for MapAccess<'a, R, B> {
     type Error = Error;
 
     fn next_key_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
         match self.iter.next() {
             Some((key, value)) => {
                 self.len -= 1;
+                self.index += 1;
                 self.value = Some(value);
+                self.key = key.path_key();
                 self.de.value = Some(key);
-                Ok(Some(seed.deserialize(&mut *self.de)?))
+                match seed.deserialize(&mut *self.de) {
+                    Ok(key) => Ok(Some(key)),
+                    Err(err) => Err(self.de.locate_item(err, self.index - 1, None)),
+                }
             }
             None => Ok(None),
         }
@@ -1307,7 +4190,9 @@ impl<'de: 'a, 'a, R: Read> de::MapAccess<'de> for MapAccess<'a, R> {
     fn next_value_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value> {
         let value = self.value.take().unwrap();
         self.de.value = Some(value);
-        Ok(seed.deserialize(&mut *self.de)?)
+        seed.deserialize(&mut *self.de).map_err(|err| {
+            self.de.locate_item(err, self.index - 1, self.key.as_ref())
+        })
     }
 
     fn size_hint(&self) -> Option<usize> {
@@ -1315,19 +4200,297 @@ impl<'de: 'a, 'a, R: Read> de::MapAccess<'de> for MapAccess<'a, R> {
     }
 }
 
+impl<'a, R: Read + 'a, B: 'a> Drop for MapAccess<'a, R, B> {
+    fn drop(&mut self) {
+        for (key, value) in self.iter.by_ref() {
+            key.dismantle();
+            value.dismantle();
+        }
+        if let Some(value) = self.value.take() {
+            value.dismantle();
+        }
+    }
+}
+
+// Items of a streamed list or dict, see `Deserializer::deserialize_streamed`.
+struct StreamAccess<'a, R: Read + 'a, B: 'a> {
+    de: &'a mut Deserializer<R, B>,
+    key: Option<Value>,  // kept for the error path, see `Value::path_key`
+    index: usize,
+}
+
+impl<'de: 'a, 'a, R: Read, B: Input<'de>> de::SeqAccess<'de> for StreamAccess<'a, R, B> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if !self.de.stream_next()? {
+            return Ok(None);
+        }
+        self.index += 1;
+        match seed.deserialize(&mut *self.de) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => Err(self.de.locate_item(err, self.index - 1, None)),
+        }
+    }
+}
+
+impl<'de: 'a, 'a, R: Read, B: Input<'de>> de::MapAccess<'de> for StreamAccess<'a, R, B> {
+    type Error = Error;
+
+    fn next_key_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if !self.de.stream_next()? {
+            return Ok(None);
+        }
+        self.index += 1;
+        self.key = self.de.streams.last().and_then(|s| s.pending.front()).and_then(Value::path_key);
+        match seed.deserialize(&mut *self.de) {
+            Ok(key) => Ok(Some(key)),
+            Err(err) => Err(self.de.locate_item(err, self.index - 1, None)),
+        }
+    }
+
+    fn next_value_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value> {
+        seed.deserialize(&mut *self.de).map_err(|err| {
+            self.de.locate_item(err, self.index - 1, self.key.as_ref())
+        })
+    }
+}
+
+/// An iterator over the pickles concatenated in a stream.
+///
+/// Each pickle is decoded independently (with its own memo), and yields either
+/// a `T` via serde (see `StreamDeserializer::new`), or a `value::Value` (see
+/// `StreamDeserializer::values`).  The iterator ends when the stream is
+/// exhausted after a complete pickle.  A truncated pickle, or any other error,
+/// is returned as the last item.
+///
+/// # Example
+///
+/// ```
+/// # use serde_pickle::{StreamDeserializer, DeOptions, Value};
+/// let mut data = serde_pickle::to_vec(&"Hello", Default::default()).unwrap();
+/// data.extend(serde_pickle::to_vec(&"pickle", Default::default()).unwrap());
+/// let mut stream = StreamDeserializer::<_, String>::new(&data[..], DeOptions::new());
+/// assert_eq!(stream.next().unwrap().unwrap(), "Hello");
+/// assert_eq!(stream.next().unwrap().unwrap(), "pickle");
+/// assert_eq!(stream.byte_offset(), 13);
+/// assert!(stream.next().is_none());
+/// ```
+pub struct StreamDeserializer<R: Read, T> {
+    de: Deserializer<R>,
+    decode: fn(&mut Deserializer<R>) -> Result<T>,
+    offset: usize,
+    failed: bool,
+}
+
+impl<R: Read, T: de::DeserializeOwned> StreamDeserializer<R, T> {
+    /// Construct a stream deserializer that decodes each pickle into `T`.
+    pub fn new(rdr: R, options: DeOptions) -> Self {
+        StreamDeserializer::with_decoder(rdr, options, |de| de::Deserialize::deserialize(de))
+    }
+}
+
+impl<R: Read> StreamDeserializer<R, value::Value> {
+    /// Construct a stream deserializer that decodes each pickle into a
+    /// `value::Value`, like the `value_from_*` functions.
+    pub fn values(rdr: R, options: DeOptions) -> Self {
+        StreamDeserializer::with_decoder(rdr, options, Deserializer::deserialize_value)
+    }
+}
+
+impl<R: Read, T> StreamDeserializer<R, T> {
+    fn with_decoder(rdr: R, options: DeOptions,
+                    decode: fn(&mut Deserializer<R>) -> Result<T>) -> Self {
+        StreamDeserializer {
+            de: Deserializer::new(rdr, options),
+            decode,
+            offset: 0,
+            failed: false,
+        }
+    }
+
+    /// Return the byte offset in the stream at which the pickle last returned
+    /// by the iterator (successfully or not) starts.
+    pub fn byte_offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<R: Read, T> Iterator for StreamDeserializer<R, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        if self.failed {
+            return None;
+        }
+        // Check for a clean end of stream between pickles.
+        match self.de.input().fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => { }
+            Err(err) => {
+                self.failed = true;
+                return Some(Err(Error::Io(err)));
+            }
+        }
+        self.offset = self.de.pos;
+        if !self.de.options.keep_memo {
+            self.de.reset_memo();
+        }
+        let result = (self.decode)(&mut self.de);
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+impl<R: Read, T> FusedIterator for StreamDeserializer<R, T> {}
+
+/// The result of feeding data to a `PickleParser`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseStatus {
+    /// The pickle is incomplete, more data needs to be fed.
+    NeedMoreData,
+    /// A complete pickle was decoded.
+    Done(value::Value),
+}
+
+/// A push-based parser for pickles that arrive in chunks.
+///
+/// The parser doesn't do any I/O: data is supplied using `feed` whenever it
+/// becomes available, and the parser keeps its state between calls.  This is
+/// useful in event loops, or for callers that can't provide a blocking
+/// `std::io::Read`.
+///
+/// Several pickles can be fed in succession.  One chunk of data can complete
+/// more than one of them, but `feed` returns only the first; the others are
+/// taken by calling `poll` until it returns `NeedMoreData`.  At the end of
+/// the input, `finish` returns the pickles that are still left.
+///
+/// # Example
+///
+/// ```
+/// # use serde_pickle::{PickleParser, ParseStatus, DeOptions, Value};
+/// let mut data = serde_pickle::to_vec(&"Hello", Default::default()).unwrap();
+/// data.extend(serde_pickle::to_vec(&1, Default::default()).unwrap());
+/// data.extend(serde_pickle::to_vec(&2, Default::default()).unwrap());
+/// let mut parser = PickleParser::new(DeOptions::new());
+/// let mut values = vec![];
+/// for chunk in data.chunks(16) {
+///     let mut status = parser.feed(chunk).unwrap();
+///     while let ParseStatus::Done(value) = status {
+///         values.push(value);
+///         status = parser.poll().unwrap();
+///     }
+/// }
+/// values.extend(parser.finish().unwrap());
+/// assert_eq!(values, [Value::String("Hello".into()), Value::I64(1), Value::I64(2)]);
+/// ```
+pub struct PickleParser {
+    de: Deserializer<Feed, Feed>,
+    start: usize,  // stream position of the current pickle
+}
+
+impl Drop for PickleParser {
+    fn drop(&mut self) {
+        self.de.clear_stack();
+    }
+}
+
+impl PickleParser {
+    /// Construct a new parser.
+    pub fn new(options: DeOptions) -> PickleParser {
+        PickleParser {
+            de: Deserializer::from_feed(options),
+            start: 0,
+        }
+    }
+
+    /// Supply the out-of-band buffers referenced by the pickles, see
+    /// `Deserializer::set_buffers`.
+    pub fn set_buffers<I>(&mut self, buffers: I)
+        where I: IntoIterator<Item = Vec<u8>>, I::IntoIter: Send + 'static
+    {
+        self.de.set_buffers(buffers);
+    }
+
+    /// Return which of the buffers taken so far are read-only, see
+    /// `Deserializer::readonly_buffers`.
+    pub fn readonly_buffers(&self) -> &[bool] {
+        self.de.readonly_buffers()
+    }
+
+    /// Append data to the input, and decode as far as possible.
+    ///
+    /// After an error, the state of the parser is undefined, and it should
+    /// not be used anymore.
+    pub fn feed(&mut self, data: &[u8]) -> Result<ParseStatus> {
+        self.de.feed(data);
+        self.parse()
+    }
+
+    /// Decode the next pickle from the data fed so far, without adding any.
+    ///
+    /// This returns the pickles that were completed by the same call to
+    /// `feed`, after the first one.
+    pub fn poll(&mut self) -> Result<ParseStatus> {
+        self.parse()
+    }
+
+    /// Signal the end of the input, and return the pickles that were not
+    /// returned yet.
+    ///
+    /// This fails with `EOFWhileParsing` if the input ends with an incomplete
+    /// pickle.
+    pub fn finish(&mut self) -> Result<Vec<value::Value>> {
+        self.de.feed_eof();
+        let mut values = Vec::new();
+        while self.de.pos != self.start || !self.de.rdr.is_exhausted() {
+            match self.parse()? {
+                ParseStatus::Done(value) => values.push(value),
+                ParseStatus::NeedMoreData => unreachable!("all input is available"),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Return the stream position up to which data has been decoded.
+    pub fn position(&self) -> usize {
+        self.de.pos
+    }
+
+    fn parse(&mut self) -> Result<ParseStatus> {
+        if !self.de.parse_available()? {
+            return Ok(ParseStatus::NeedMoreData);
+        }
+        let value = self.de.deserialize_value()?;
+        // Prepare for the next pickle.
+        if !self.de.options.keep_memo {
+            self.de.reset_memo();
+        }
+        self.de.allocated = 0;
+        self.start = self.de.pos;
+        Ok(ParseStatus::Done(value))
+    }
+}
+
 
 /// Decodes a value from a `std::io::Read`.
 pub fn from_reader<'de, R: io::Read, T: de::Deserialize<'de>>(rdr: R, options: DeOptions) -> Result<T> {
     let mut de = Deserializer::new(rdr, options);
-    let value = de::Deserialize::deserialize(&mut de)?;
+    let value = de::Deserialize::deserialize(&mut de).map_err(|err| de.locate(err, None))?;
     // Make sure the whole stream has been consumed.
     de.end()?;
     Ok(value)
 }
 
 /// Decodes a value from a byte slice `&[u8]`.
-pub fn from_slice<'de, T: de::Deserialize<'de>>(v: &[u8], options: DeOptions) -> Result<T> {
-    from_reader(io::Cursor::new(v), options)
+///
+/// Strings and bytes are borrowed from the slice where possible, see
+/// `Deserializer::from_slice`.
+pub fn from_slice<'de, T: de::Deserialize<'de>>(v: &'de [u8], options: DeOptions) -> Result<T> {
+    let mut de = Deserializer::from_slice(v, options);
+    let value = de::Deserialize::deserialize(&mut de).map_err(|err| de.locate(err, None))?;
+    de.end()?;
+    Ok(value)
 }
 
 /// Decodes a value from any iterator supported as a reader.
@@ -1347,7 +4510,10 @@ pub fn value_from_reader<R: io::Read>(rdr: R, options: DeOptions) -> Result<valu
 
 /// Decodes a value from a byte slice `&[u8]`.
 pub fn value_from_slice(v: &[u8], options: DeOptions) -> Result<value::Value> {
-    value_from_reader(io::Cursor::new(v), options)
+    let mut de = Deserializer::from_slice(v, options);
+    let value = de.deserialize_value()?;
+    de.end()?;
+    Ok(value)
 }
 
 /// Decodes a value from any iterator supported as a reader.
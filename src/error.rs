@@ -34,12 +34,32 @@ pub enum ErrorCode {
     UnresolvedGlobal,
     /// A "module global" isn't supported
     UnsupportedGlobal(Vec<u8>, Vec<u8>),
+    /// A "module global" was rejected by the global policy
+    DisallowedGlobal(String, String),
     /// A value was missing from the memo
     MissingMemo(u32),
+    /// An extension code wasn't found in the registry
+    UnregisteredExtension(u32),
+    /// Out-of-band buffer referenced, but none supplied
+    MissingBuffer,
+    /// Nesting depth limit exceeded
+    DepthLimit,
+    /// Length limit exceeded by a string or literal of the given length
+    /// (for text lines, the length read until giving up)
+    LengthLimit(u64),
+    /// Memo size limit exceeded
+    MemoLimit,
+    /// MARK depth limit exceeded
+    MarkLimit,
+    /// Allocation limit exceeded
+    AllocationLimit,
     /// Invalid literal found
     InvalidLiteral(Vec<u8>),
     /// Found trailing bytes after STOP opcode
     TrailingBytes,
+    /// A streamed container doesn't have the structure it was checked for
+    /// (see `DeOptions::stream_containers`)
+    StreamMismatch,
     /// Invalid value in pickle stream
     InvalidValue(String),
     /// Structure deserialization error (e.g., unknown variant)
@@ -62,16 +82,78 @@ impl fmt::Display for ErrorCode {
             ErrorCode::UnsupportedGlobal(ref m, ref g) =>
                 write!(fmt, "unsupported global: {}.{}",
                        String::from_utf8_lossy(m), String::from_utf8_lossy(g)),
+            ErrorCode::DisallowedGlobal(ref m, ref g) =>
+                write!(fmt, "global not allowed: {}.{}", m, g),
             ErrorCode::MissingMemo(n) => write!(fmt, "missing memo with id {}", n),
+            ErrorCode::UnregisteredExtension(n) =>
+                write!(fmt, "unregistered extension code {}", n),
+            ErrorCode::MissingBuffer => write!(fmt, "not enough out-of-band buffers"),
+            ErrorCode::DepthLimit => write!(fmt, "nesting depth limit exceeded"),
+            ErrorCode::LengthLimit(n) => write!(fmt, "length {} exceeds limit", n),
+            ErrorCode::MemoLimit => write!(fmt, "memo size limit exceeded"),
+            ErrorCode::MarkLimit => write!(fmt, "MARK depth limit exceeded"),
+            ErrorCode::AllocationLimit => write!(fmt, "allocation limit exceeded"),
             ErrorCode::InvalidLiteral(ref l) =>
                 write!(fmt, "literal is invalid: {}", String::from_utf8_lossy(l)),
             ErrorCode::TrailingBytes => write!(fmt, "trailing bytes found"),
+            ErrorCode::StreamMismatch => write!(fmt, "streamed container has unexpected structure"),
             ErrorCode::InvalidValue(ref s) => write!(fmt, "invalid value: {}", s),
             ErrorCode::Structure(ref s) => fmt.write_str(s),
         }
     }
 }
 
+/// Information about where in a pickle an error occurred.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ErrorContext {
+    /// Offset in the input at which the error was detected.
+    pub offset: usize,
+    /// Name and offset of the opcode that failed, if the error occurred while
+    /// executing one.
+    pub opcode: Option<(&'static str, usize)>,
+    /// Path to the part of the value that failed to deserialize, e.g.
+    /// `foo[3].bar`, or empty if the error isn't within a container.  Dict
+    /// items are given by their key if it is a string or a scalar, and by
+    /// their index otherwise.
+    pub path: String,
+}
+
+impl ErrorContext {
+    pub(crate) fn new(offset: usize) -> Self {
+        ErrorContext { offset, opcode: None, path: String::new() }
+    }
+
+    // Add an outer path component, which is a dict key or a list index.
+    pub(crate) fn prepend_path(&mut self, component: &str, key: bool) {
+        let mut path = String::with_capacity(component.len() + self.path.len() + 3);
+        if key {
+            path.push_str(component);
+        } else {
+            path.push('[');
+            path.push_str(component);
+            path.push(']');
+        }
+        if !self.path.is_empty() && !self.path.starts_with('[') {
+            path.push('.');
+        }
+        path.push_str(&self.path);
+        self.path = path;
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "offset {}", self.offset)?;
+        if let Some((name, offset)) = self.opcode {
+            write!(fmt, " (opcode {} at {})", name, offset)?;
+        }
+        if !self.path.is_empty() {
+            write!(fmt, " in {}", self.path)?;
+        }
+        Ok(())
+    }
+}
+
 /// This type represents all possible errors that can occur when serializing or
 /// deserializing a value.
 #[derive(Debug)]
@@ -79,9 +161,93 @@ pub enum Error {
     /// Some IO error occurred when serializing or deserializing a value.
     Io(io::Error),
     /// The pickle had some error while interpreting.
-    Eval(ErrorCode, usize),
-    /// Syntax error while transforming into Rust values.
-    Syntax(ErrorCode),
+    Eval(ErrorCode, Box<ErrorContext>),
+    /// Syntax error while transforming into Rust values, with the context if
+    /// it occurred while deserializing a pickle.
+    Syntax(ErrorCode, Option<Box<ErrorContext>>),
+}
+
+impl Error {
+    pub(crate) fn eval(code: ErrorCode, offset: usize) -> Error {
+        Error::Eval(code, Box::new(ErrorContext::new(offset)))
+    }
+
+    /// Return the error code, unless this is an I/O error.
+    pub fn code(&self) -> Option<&ErrorCode> {
+        match *self {
+            Error::Io(_) => None,
+            Error::Eval(ref code, _) | Error::Syntax(ref code, _) => Some(code),
+        }
+    }
+
+    /// Return the information where in the pickle the error occurred.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match *self {
+            Error::Io(_) | Error::Syntax(_, None) => None,
+            Error::Eval(_, ref context) | Error::Syntax(_, Some(ref context)) => Some(context),
+        }
+    }
+
+    pub(crate) fn context_mut(&mut self) -> Option<&mut ErrorContext> {
+        match *self {
+            Error::Io(_) | Error::Syntax(_, None) => None,
+            Error::Eval(_, ref mut context) | Error::Syntax(_, Some(ref mut context)) =>
+                Some(context),
+        }
+    }
+
+    /// Render a hexdump of the input around the location of the error.
+    ///
+    /// The dump shows up to two lines of 16 bytes before and after the line
+    /// with the failing opcode (or the offset where the error was detected),
+    /// which is marked below.  Returns `None` if the error has no context.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use serde_pickle::DeOptions;
+    /// let data = b"\x80\x02K\x01a.";
+    /// let err = serde_pickle::value_from_slice(data, DeOptions::new()).unwrap_err();
+    /// assert_eq!(err.hexdump(data).unwrap(), "\
+    /// 00000000  80 02 4b 01 61 2e                                 |..K.a.|
+    ///                       ^^
+    /// ");
+    /// ```
+    pub fn hexdump(&self, input: &[u8]) -> Option<String> {
+        let context = self.context()?;
+        let offset = context.opcode.map_or(context.offset, |(_, offset)| offset);
+        let line = offset / 16;
+        let mut dump = String::new();
+        for start in (line.saturating_sub(2)..line + 3).map(|line| line * 16) {
+            if start >= input.len() && start > offset {
+                break;
+            }
+            let bytes = &input[start.min(input.len())..(start + 16).min(input.len())];
+            dump.push_str(&format!("{:08x}  ", start));
+            for i in 0..16 {
+                match bytes.get(i) {
+                    Some(byte) => dump.push_str(&format!("{:02x} ", byte)),
+                    None => dump.push_str("   "),
+                }
+                if i == 7 {
+                    dump.push(' ');
+                }
+            }
+            dump.push_str(" |");
+            dump.extend(bytes.iter().map(|&b| if b == b' ' || b.is_ascii_graphic() {
+                b as char
+            } else {
+                '.'
+            }));
+            dump.push_str("|\n");
+            if start / 16 == line {
+                let column = 10 + (offset % 16) * 3 + if offset % 16 > 7 { 1 } else { 0 };
+                dump.push_str(&" ".repeat(column));
+                dump.push_str("^^\n");
+            }
+        }
+        Some(dump)
+    }
 }
 
 impl From<io::Error> for Error {
@@ -96,9 +262,11 @@ impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Io(ref error) => error.fmt(fmt),
-            Error::Eval(ref code, offset) => write!(fmt, "eval error at offset {}: {}",
-                                                    offset, code),
-            Error::Syntax(ref code) => write!(fmt, "decoding error: {}", code)
+            Error::Eval(ref code, ref context) => write!(fmt, "eval error at {}: {}",
+                                                         context, code),
+            Error::Syntax(ref code, Some(ref context)) =>
+                write!(fmt, "decoding error at {}: {}", context, code),
+            Error::Syntax(ref code, None) => write!(fmt, "decoding error: {}", code)
         }
     }
 }
@@ -107,12 +275,12 @@ impl error::Error for Error {}
 
 impl de::Error for Error {
     fn custom<T: fmt::Display>(msg: T) -> Error {
-        Error::Syntax(ErrorCode::Structure(msg.to_string()))
+        Error::Syntax(ErrorCode::Structure(msg.to_string()), None)
     }
 }
 
 impl ser::Error for Error {
     fn custom<T: fmt::Display>(msg: T) -> Error {
-        Error::Syntax(ErrorCode::Structure(msg.to_string()))
+        Error::Syntax(ErrorCode::Structure(msg.to_string()), None)
     }
 }
@@ -12,8 +12,10 @@
 //! details on the Pickle format.
 //!
 //! This crate supports all Pickle protocols (0 to 5) when reading, and writing
-//! protocol 2 (compatible with Python 2 and 3), or protocol 3 (compatible with
-//! Python 3 only).
+//! the legacy protocols 0 (text) and 1, protocol 2 (compatible with Python 2
+//! and 3), protocol 3 (compatible with Python 3 only), protocol 4 (compatible
+//! with Python 3.4 and later), or protocol 5 (compatible with Python 3.8 and
+//! later).
 //!
 //! # Supported types
 //!
@@ -34,7 +36,18 @@
 //!
 //! When deserializing, arbitrary Python objects saved using a pickled instance
 //! dictionary or `__setstate__` are replaced by that state, since version
-//! 0.5 of this library.
+//! 0.5 of this library.  With `DeOptions::decode_objects`, they are instead
+//! decoded as `Value::Object`, which keeps the class and constructor arguments
+//! and can be written back.  Instances of specific classes can also be
+//! reconstructed into arbitrary values by a `Resolver`, set with
+//! `DeOptions::resolver`.  To restrict which globals a pickle may reference
+//! at all, set a `GlobalPolicy` with `DeOptions::global_policy`.  Pickles from
+//! untrusted sources should also be decoded with resource limits, see the
+//! `DeOptions::max_*` methods.  Truncated or corrupted pickles can be decoded
+//! on a best-effort basis with `DeOptions::recover`.
+//!
+//! Conversely, structs can be written as instances of Python classes, see
+//! `SerOptions::class`.
 //!
 //! *Note on enums:* Enum variants are serialized as Python tuples `(name,
 //! [data])` instead of mappings (or a plain string for unit variants), which is
@@ -50,10 +63,29 @@
 //! serializing.  This means that they will be serialized as a tuple or list of
 //! integers unless you use one of the wrappers in `serde_bytes`.
 //!
+//! Strings pickled by Python 2 are also decoded as bytes objects by default,
+//! since their encoding is unknown.  Use `DeOptions::string_encoding` to decode
+//! them as text.
+//!
+//! Types that borrow strings or bytes from the input (e.g. `&str` fields, or
+//! `#[serde(borrow)]`) can be deserialized using `Deserializer::from_slice`.
+//! With `DeOptions::stream_containers`, lists and dicts are visited while they
+//! are decoded, instead of after the whole pickle has been decoded.
+//!
+//! Persistent ids (the `PERSID` opcodes) can be read and written by setting
+//! `DeOptions::persistent_load` and `SerOptions::persistent_id`, which work like
+//! the methods of the same name on Python's `Unpickler` and `Pickler`.
+//! Likewise, the extension registry (the `EXT` opcodes) can be populated using
+//! `DeOptions::extension` and `SerOptions::extension`.
+//!
+//! Out-of-band buffers (protocol 5) are written using
+//! `SerOptions::buffer_callback`, and supplied for reading with
+//! `Deserializer::set_buffers`.
+//!
 //! # Unsupported features
 //!
-//! - Recursive objects using the `PERSID` and `EXT` type opcodes.
-//! - Out-of-band data as introduced in Pickle protocol 5.
+//! - Recursive objects, except when decoding into `Value` with
+//!   `DeOptions::decode_recursive` enabled.
 //!
 //! # Exported API
 //!
@@ -64,15 +96,38 @@
 //! handle).  These functions, called `value_from_*` and `value_to_*`, will
 //! correctly (un)pickle these types.
 //!
+//! To make pickles smaller, repeated strings and other values can be written
+//! only once with `SerOptions::memoize`.  With `SerOptions::canonical`, equal
+//! values always produce the same pickle, even if they contain `HashMap`s.
+//!
+//! Streams of several pickles can be written with `Serializer::dump`, and
+//! decoded with `StreamDeserializer`.  For input that arrives in chunks and
+//! can't be read blockingly, `PickleParser` decodes pickles from data pushed
+//! into it.
+//!
+//! For debugging, the opcodes executed by a `Deserializer` can be traced with
+//! `Deserializer::set_trace`, or executed one by one with `Deserializer::step`.
+//!
+//! # Async I/O
+//!
+//! With the `tokio` feature enabled, the functions `from_async_reader`,
+//! `value_from_async_reader` and `to_async_writer` work with tokio's
+//! `AsyncBufRead` and `AsyncWrite` traits.  Decoding proceeds as data
+//! arrives, without waiting for the whole pickle first, and stops at its end,
+//! so several pickles can be read from one connection.  Encoding does not
+//! stream, the pickle is written from a buffer.
+//!
 //! # Minimum Supported Rust Version
 //!
-//! The minimum supported version of the toolchain is 1.41.1.
+//! The minimum supported version of the toolchain is 1.41.1.  The `tokio`
+//! feature requires the minimum version supported by tokio.
 
 #![cfg_attr(feature = "unstable", feature(test))]
 
 pub use self::ser::{
     Serializer,
     SerOptions,
+    BufferMode,
     to_writer,
     to_vec,
     value_to_writer,
@@ -82,6 +137,14 @@ pub use self::ser::{
 pub use self::de::{
     Deserializer,
     DeOptions,
+    Recovery,
+    StreamDeserializer,
+    PickleParser,
+    ParseStatus,
+    GlobalPolicy,
+    StringEncoding,
+    Resolver,
+    Reconstruct,
     from_reader,
     from_slice,
     from_iter,
@@ -90,20 +153,36 @@ pub use self::de::{
     value_from_iter,
 };
 
+#[cfg(feature = "tokio")]
+pub use self::async_io::{
+    from_async_reader,
+    value_from_async_reader,
+    to_async_writer,
+};
+
+pub use self::trace::{Trace, VmState, Step};
+
 pub use self::value::{
     Value,
     HashableValue,
+    Object,
+    ValueRef,
     to_value,
     from_value,
+    from_value_with,
 };
 
-pub use self::error::{Error, ErrorCode, Result};
+pub use self::error::{Error, ErrorCode, ErrorContext, Result};
 
 pub mod ser;
 pub mod de;
 pub mod error;
 pub mod value;
 mod consts;
+mod hooks;
+mod trace;
+#[cfg(feature = "tokio")]
+mod async_io;
 mod value_impls;
 
 #[cfg(test)]
@@ -6,23 +6,32 @@
 
 //! Pickle serialization
 
-use std::io;
-use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::result::Result as StdResult;
 use serde::ser;
 use serde::ser::Serialize;
-use byteorder::{LittleEndian, BigEndian, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, BigEndian, WriteBytesExt};
 use num_bigint::BigInt;
-use num_traits::Signed;
+use num_traits::{Signed, ToPrimitive};
 
 use super::consts::*;
-use super::error::{Error, Result};
-use super::value::{Value, HashableValue};
+use super::de::DEFAULT_MAX_DEPTH;
+use super::error::{Error, ErrorCode, Result};
+use super::hooks::Hook;
+use super::value::{Value, HashableValue, Object, ValueRef};
+use super::value_impls::to_value_with;
 
 /// Supported pickle protocols for writing.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum PickleProto {
+    V0,
+    V1,
     V2,
     V3,
+    V4,
+    V5,
 }
 
 impl Default for PickleProto {
@@ -31,11 +40,46 @@ impl Default for PickleProto {
     }
 }
 
+/// Callback type for producing persistent ids, see `SerOptions::persistent_id`.
+type PersistentId = dyn Fn(ValueRef) -> Option<Value> + Send + Sync;
+
+/// Callback type for taking buffers out-of-band, see `SerOptions::buffer_callback`.
+type BufferCallback = dyn Fn(&[u8]) -> BufferMode + Send + Sync;
+
+/// How a byte string is written, as chosen by `SerOptions::buffer_callback`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferMode {
+    /// Copy the data into the pickle stream
+    InBand,
+    /// Out-of-band buffer that is loaded read-only, like the buffer of a
+    /// Python `bytes` object
+    ReadOnly,
+    /// Out-of-band buffer that is loaded writable, like the buffer of a
+    /// Python `bytearray`
+    Writable,
+}
+
 /// Options for serializing.
 #[derive(Clone, Debug, Default)]
 pub struct SerOptions {
     proto: PickleProto,
-    compat_enum_repr: bool,
+    pub(crate) compat_enum_repr: bool,
+    persistent_id: Option<Hook<PersistentId>>,
+    extensions: BTreeMap<(String, String), u32>,
+    buffer_callback: Option<Hook<BufferCallback>>,
+    pub(crate) max_depth: Option<usize>,
+    memoize: bool,
+    memoize_tuples: bool,
+    canonical: bool,
+    pub(crate) classes: BTreeMap<String, PyClass>,
+}
+
+/// Python class that a struct is written as, see `SerOptions::class`.
+#[derive(Clone, Debug)]
+pub(crate) struct PyClass {
+    pub(crate) module: String,
+    pub(crate) name: String,
+    pub(crate) slots: bool,
 }
 
 impl SerOptions {
@@ -43,16 +87,61 @@ impl SerOptions {
     ///
     /// - use pickle protocol v3
     /// - use the serde-standard Enum representation
+    /// - limit the nesting depth to 256
     pub fn new() -> Self {
         Default::default()
     }
 
+    /// Set the used pickle protocol to v0.
+    ///
+    /// This is the original text protocol, which is readable by all versions
+    /// of Python and mostly consists of printable ASCII.  Bytes objects are
+    /// written as calls to `_codecs.encode`, as done by Python 3.  Class
+    /// instances created by `__new__` are written as calls to
+    /// `copyreg._reconstructor` or `copyreg.__newobj__`, which is what `NEWOBJ`
+    /// does from protocol 2; passing keyword arguments to `__new__` is not
+    /// supported.
+    pub fn proto_v0(mut self) -> Self {
+        self.proto = PickleProto::V0;
+        self
+    }
+
+    /// Set the used pickle protocol to v1.
+    ///
+    /// This is the binary protocol before v2, without the `PROTO` header and
+    /// opcodes added in v2 (booleans, long integers and small tuples).  The
+    /// same notes as for `proto_v0` apply.
+    pub fn proto_v1(mut self) -> Self {
+        self.proto = PickleProto::V1;
+        self
+    }
+
     /// Set the used pickle protocol to v2.
     pub fn proto_v2(mut self) -> Self {
         self.proto = PickleProto::V2;
         self
     }
 
+    /// Set the used pickle protocol to v4.
+    ///
+    /// This adds framing, compact opcodes for short strings and module
+    /// globals, native sets, and support for strings and bytes objects of 4
+    /// GiB and more.
+    pub fn proto_v4(mut self) -> Self {
+        self.proto = PickleProto::V4;
+        self
+    }
+
+    /// Set the used pickle protocol to v5.
+    ///
+    /// In addition to v4, this is required for writing out-of-band buffers,
+    /// see `buffer_callback`.  Bytearrays are decoded as bytes objects, so
+    /// they are written as such.
+    pub fn proto_v5(mut self) -> Self {
+        self.proto = PickleProto::V5;
+        self
+    }
+
     /// Switch Enum serialization to the representation used up to serde-pickle 0.6.
     ///
     /// "serde standard" representation (now default):
@@ -76,48 +165,585 @@ impl SerOptions {
         self.compat_enum_repr = true;
         self
     }
+
+    /// Set a callback that can replace values by persistent ids.
+    ///
+    /// This corresponds to `Pickler.persistent_id` in Python: the callback is
+    /// called for every value written by the `value_to_*` functions (and
+    /// `Serializer::dump_value`), and if it returns an id, the id is pickled
+    /// in place of the value, to be resolved by the unpickler's
+    /// `persistent_load`.  The id itself is written as is.  Dictionary keys
+    /// and set items are passed as `ValueRef::Hashable`, the other values as
+    /// `ValueRef::Value`.
+    ///
+    /// Types written through serde's `Serialize` (with `to_vec`, `to_writer`
+    /// or `Serializer::dump`) are never passed to the callback.
+    pub fn persistent_id<F>(mut self, callback: F) -> Self
+        where F: Fn(ValueRef) -> Option<Value> + Send + Sync + 'static
+    {
+        self.persistent_id = Some(Hook(Arc::new(callback)));
+        self
+    }
+
+    /// Register an extension code for a module global.
+    ///
+    /// This corresponds to `copyreg.add_extension` in Python: references to
+    /// the global `module.name` are written with one of the compact `EXT`
+    /// opcodes instead of spelling out the names.  The unpickler must have the
+    /// same registration.  Like in Python, this is only effective with
+    /// protocol v2 and later.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the code is not in the range `1..=0x7fff_ffff`, like Python.
+    pub fn extension(mut self, module: &str, name: &str, code: u32) -> Self {
+        assert!((1..=0x7fff_ffff).contains(&code), "extension code out of range");
+        self.extensions.insert((module.into(), name.into()), code);
+        self
+    }
+
+    /// Set a callback that can take byte strings out-of-band.
+    ///
+    /// This is similar to the `buffer_callback` of Python's `Pickler`: it is
+    /// called with the contents of every byte string to be written, and unless
+    /// it returns `BufferMode::InBand`, the data is not copied into the pickle
+    /// stream, but referenced by a `NEXT_BUFFER` opcode.  The callback is
+    /// responsible for keeping the buffers, in order, so that they can be
+    /// handed to the unpickler later.
+    ///
+    /// `BufferMode::ReadOnly` buffers are marked read-only, like those of
+    /// `bytes` objects.  Python loads `BufferMode::Writable` ones as writable,
+    /// and `Deserializer::readonly_buffers` reports the difference.
+    ///
+    /// Byte strings used as dictionary keys or set elements are always written
+    /// in-band, since buffers are not hashable.
+    ///
+    /// Only effective with protocol v5.
+    pub fn buffer_callback<F>(mut self, callback: F) -> Self
+        where F: Fn(&[u8]) -> BufferMode + Send + Sync + 'static
+    {
+        self.buffer_callback = Some(Hook(Arc::new(callback)));
+        self
+    }
+
+    /// Memoize strings, bytes objects and module globals.
+    ///
+    /// Like with Python's `Pickler`, each of these values is written only
+    /// once, and referenced from the memo when it is repeated.  This makes
+    /// pickles with many repeated strings, such as dict keys or struct field
+    /// names, much smaller.  Unlike in Python, values are memoized if they
+    /// are equal, not only if they are the same object.
+    ///
+    /// The memo is kept for all pickles written by `Serializer::dump`, so
+    /// they have to be read by the same `Deserializer`, with
+    /// `DeOptions::keep_memo`.
+    pub fn memoize(mut self) -> Self {
+        self.memoize = true;
+        self
+    }
+
+    /// Also memoize equal tuples and frozensets.
+    ///
+    /// This implies `memoize`.  Only tuples that contain no mutable values
+    /// (such as lists) are memoized, since the unpickled tuples are shared.
+    /// Tuples and frozensets are compared by their pickled form, which makes
+    /// writing deeply nested ones slower.
+    pub fn memoize_tuples(mut self) -> Self {
+        self.memoize = true;
+        self.memoize_tuples = true;
+        self
+    }
+
+    /// Write canonical pickles, which are the same for equal values.
+    ///
+    /// Maps written through serde, such as `HashMap`s (and also structs), are
+    /// collected and written in the order of their keys as `HashableValue`s,
+    /// like `Value::Dict`s.  Integers are written in the shortest form, no
+    /// matter their Rust type, and shared values are memoized under
+    /// consecutive indices.  Frames and memo indices then only depend on the
+    /// value and the other options.
+    ///
+    /// Serde doesn't tell sets apart from sequences, so the items of a
+    /// `HashSet` are not sorted; use a `BTreeSet`, `Value::Set`, or write the
+    /// field with `#[serde(serialize_with = "serde_pickle::ser::sorted_set")]`.
+    /// The entries of a map are collected with `to_value`, so they are not
+    /// passed to the `persistent_id` callback.
+    pub fn canonical(mut self) -> Self {
+        self.canonical = true;
+        self
+    }
+
+    /// Write structs with the given type name as instances of a Python class.
+    ///
+    /// Instead of a dict, such structs are written like Python's `Pickler`
+    /// writes instances of plain classes such as dataclasses: an instance of
+    /// the class `module.name` (where `name` can be a qualified name) is
+    /// created with `__new__`, and the fields are set as its `__dict__`.  The
+    /// class must be importable when the pickle is loaded.  The type name is
+    /// the one seen by serde, i.e. the struct's name unless it is renamed.
+    ///
+    /// When deserializing, class instances are replaced by their fields, so
+    /// the structs can be read back.
+    pub fn class(mut self, type_name: &str, module: &str, name: &str) -> Self {
+        let class = PyClass { module: module.into(), name: name.into(), slots: false };
+        self.classes.insert(type_name.into(), class);
+        self
+    }
+
+    /// Like `class`, for Python classes that use `__slots__`.
+    ///
+    /// Since such instances have no `__dict__`, the fields are written as the
+    /// state `(None, slots)`, which Python sets as attributes one by one.
+    pub fn class_with_slots(mut self, type_name: &str, module: &str, name: &str) -> Self {
+        let class = PyClass { module: module.into(), name: name.into(), slots: true };
+        self.classes.insert(type_name.into(), class);
+        self
+    }
+
+    /// Limit the nesting depth of serialized values (`ErrorCode::DepthLimit`).
+    ///
+    /// Like with `DeOptions::max_depth`, the default limit is 256.  Values
+    /// are written recursively, so a higher limit may need a larger stack.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+}
+
+/// Frames are committed once they reach this size, like in Python.
+const FRAME_SIZE_TARGET: usize = 64 * 1024;
+
+/// Frames smaller than this are written without a `FRAME` opcode.
+const FRAME_SIZE_MIN: usize = 4;
+
+/// Writer that collects the output into frames (protocol 4 and later).
+struct Framer<W> {
+    writer: W,
+    framing: bool,
+    frame: Vec<u8>,  // current frame, empty if not framing
+}
+
+impl<W: io::Write> Framer<W> {
+    fn new(writer: W) -> Self {
+        Framer { writer, framing: false, frame: Vec::new() }
+    }
+
+    fn start_framing(&mut self) {
+        self.framing = true;
+    }
+
+    fn end_framing(&mut self) -> io::Result<()> {
+        self.commit_frame(true)?;
+        self.framing = false;
+        Ok(())
+    }
+
+    // Write out the current frame if it is large enough, or if forced.  This
+    // must only be called between opcodes.
+    #[inline]
+    fn commit_frame(&mut self, force: bool) -> io::Result<()> {
+        if self.frame.len() >= FRAME_SIZE_TARGET || (force && self.framing) {
+            self.write_frame()
+        } else {
+            Ok(())
+        }
+    }
+
+    #[cold]
+    fn write_frame(&mut self) -> io::Result<()> {
+        if self.frame.len() >= FRAME_SIZE_MIN {
+            self.writer.write_u8(FRAME)?;
+            self.writer.write_u64::<LittleEndian>(self.frame.len() as u64)?;
+        }
+        self.writer.write_all(&self.frame)?;
+        self.frame.clear();
+        Ok(())
+    }
+
+    // Write an opcode with a large payload, which is put outside of frames
+    // to avoid copying it.
+    #[cold]
+    fn write_large(&mut self, header: &[u8], payload: &[u8]) -> io::Result<()> {
+        self.commit_frame(true)?;
+        self.writer.write_all(header)?;
+        self.writer.write_all(payload)
+    }
+}
+
+impl<W: io::Write> io::Write for Framer<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.framing {
+            self.frame.extend_from_slice(buf);
+            Ok(buf.len())
+        } else {
+            self.writer.write(buf)
+        }
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.framing {
+            self.frame.extend_from_slice(buf);
+            Ok(())
+        } else {
+            self.writer.write_all(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Memo indices of the values written so far.
+#[derive(Default)]
+struct Memo {
+    len: u32,                               // number of entries written
+    shared: BTreeMap<u32, u32>,             // `Value::Shared` ids
+    strings: BTreeMap<String, u32>,
+    bytes: BTreeMap<Vec<u8>, u32>,
+    globals: BTreeMap<(String, String), u32>,
+    tuples: BTreeMap<u32, u32>,             // tuples and frozensets by `TupleIds` id
+    tuple_ids: TupleIds,
+}
+
+/// Ids of the distinct tuples and frozensets written, which are used to
+/// memoize equal ones.
+///
+/// The key of a tuple refers to nested tuples by their ids, so that the keys
+/// of a tuple and all nested ones are built in one pass.  While the tuple is
+/// written, the ids found in the pass are kept by the address of the values.
+#[derive(Default)]
+struct TupleIds {
+    ids: BTreeMap<Vec<u8>, u32>,
+    nodes: HashMap<usize, Option<u32>>,  // ids in the current pass, `None` if mutable
+    depth: usize,                        // number of tuples being written
+}
+
+impl TupleIds {
+    // Return the id of a tuple or frozenset, or `None` if it contains
+    // mutable values.
+    fn value_id(&mut self, value: &Value) -> Option<u32> {
+        self.encode_value(value, &mut Vec::new());
+        self.nodes.get(&(value as *const Value as usize)).cloned().unwrap_or(None)
+    }
+
+    fn hashable_id(&mut self, value: &HashableValue) -> Option<u32> {
+        self.encode_hashable(value, &mut Vec::new());
+        self.nodes.get(&(value as *const HashableValue as usize)).cloned().unwrap_or(None)
+    }
+
+    // Append the key of a value.  Returns false if the value is mutable.
+    fn encode_value(&mut self, value: &Value, key: &mut Vec<u8>) -> bool {
+        let addr = value as *const Value as usize;
+        let id = match *value {
+            Value::None => return encode_leaf(key, b'N', &[]),
+            Value::Bool(b) => return encode_leaf(key, b'B', &[b as u8]),
+            Value::I64(i) => return encode_leaf(key, b'I', &i.to_le_bytes()),
+            Value::Int(ref i) => return encode_leaf(key, b'L', &i.to_signed_bytes_le()),
+            Value::F64(f) => return encode_leaf(key, b'F', &f.to_bits().to_le_bytes()),
+            Value::Bytes(ref b) => return encode_leaf(key, b'b', b),
+            Value::String(ref s) => return encode_leaf(key, b's', s.as_bytes()),
+            Value::Tuple(ref items) => match self.nodes.get(&addr) {
+                Some(&id) => id,
+                None => {
+                    let mut inner = vec![b't'];
+                    let mut immutable = true;
+                    for item in items {
+                        immutable &= self.encode_value(item, &mut inner);
+                    }
+                    self.intern(addr, inner, immutable)
+                }
+            },
+            Value::FrozenSet(ref items) => match self.nodes.get(&addr) {
+                Some(&id) => id,
+                None => {
+                    let mut inner = vec![b'f'];
+                    for item in items {
+                        self.encode_hashable(item, &mut inner);
+                    }
+                    self.intern(addr, inner, true)
+                }
+            },
+            _ => return false,
+        };
+        encode_id(key, id)
+    }
+
+    fn encode_hashable(&mut self, value: &HashableValue, key: &mut Vec<u8>) -> bool {
+        let addr = value as *const HashableValue as usize;
+        let id = match *value {
+            HashableValue::None => return encode_leaf(key, b'N', &[]),
+            HashableValue::Bool(b) => return encode_leaf(key, b'B', &[b as u8]),
+            HashableValue::I64(i) => return encode_leaf(key, b'I', &i.to_le_bytes()),
+            HashableValue::Int(ref i) => return encode_leaf(key, b'L', &i.to_signed_bytes_le()),
+            HashableValue::F64(f) => return encode_leaf(key, b'F', &f.to_bits().to_le_bytes()),
+            HashableValue::Bytes(ref b) => return encode_leaf(key, b'b', b),
+            HashableValue::String(ref s) => return encode_leaf(key, b's', s.as_bytes()),
+            HashableValue::Tuple(ref items) => match self.nodes.get(&addr) {
+                Some(&id) => id,
+                None => {
+                    let mut inner = vec![b't'];
+                    for item in items {
+                        self.encode_hashable(item, &mut inner);
+                    }
+                    self.intern(addr, inner, true)
+                }
+            },
+            HashableValue::FrozenSet(ref items) => match self.nodes.get(&addr) {
+                Some(&id) => id,
+                None => {
+                    let mut inner = vec![b'f'];
+                    for item in items {
+                        self.encode_hashable(item, &mut inner);
+                    }
+                    self.intern(addr, inner, true)
+                }
+            },
+        };
+        encode_id(key, id)
+    }
+
+    fn intern(&mut self, addr: usize, key: Vec<u8>, immutable: bool) -> Option<u32> {
+        let id = if immutable {
+            let next = self.ids.len() as u32;
+            Some(*self.ids.entry(key).or_insert(next))
+        } else {
+            None
+        };
+        self.nodes.insert(addr, id);
+        id
+    }
+}
+
+fn encode_leaf(key: &mut Vec<u8>, tag: u8, data: &[u8]) -> bool {
+    key.push(tag);
+    key.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    key.extend_from_slice(data);
+    true
+}
+
+fn encode_id(key: &mut Vec<u8>, id: Option<u32>) -> bool {
+    match id {
+        Some(id) => {
+            key.push(b'T');
+            key.extend_from_slice(&id.to_le_bytes());
+            true
+        }
+        None => false,
+    }
 }
 
 /// A structure for serializing Rust values into a Pickle stream.
 pub struct Serializer<W> {
-    writer: W,
+    writer: Framer<W>,
     options: SerOptions,
+    depth: usize,
+    memo: Memo,
 }
 
 impl<W: io::Write> Serializer<W> {
     pub fn new(writer: W, options: SerOptions) -> Self {
-        Serializer { writer, options }
+        Serializer {
+            writer: Framer::new(writer),
+            options,
+            depth: 0,
+            memo: Memo::default(),
+        }
     }
 
     /// Unwrap the `Writer` from the `Serializer`.
     pub fn into_inner(self) -> W {
-        self.writer
+        self.writer.writer
+    }
+
+    /// Write a complete pickle of the specified struct.
+    ///
+    /// This can be called several times to write a stream of pickles, like
+    /// `Pickler.dump` in Python.  The memo is kept between the pickles.
+    pub fn dump<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.write_pickle(|ser| value.serialize(ser))
+    }
+
+    /// Write a complete pickle of the value.
+    ///
+    /// Like `dump`, this can be called several times.  The ids of
+    /// `Value::Shared` values only apply within one value, so a `Value::Ref`
+    /// can't refer to a value in an earlier pickle.
+    pub fn dump_value(&mut self, value: &Value) -> Result<()> {
+        self.write_pickle(|ser| ser.serialize_value(value))
+    }
+
+    /// Forget the values written so far, like `Pickler.clear_memo` in Python.
+    ///
+    /// The unpickler must likewise reset its memo, see
+    /// `Deserializer::reset_memo`.
+    pub fn clear_memo(&mut self) {
+        self.memo = Memo::default();
+    }
+
+    fn write_pickle<F>(&mut self, inner: F) -> Result<()>
+        where F: FnOnce(&mut Self) -> Result<()>
+    {
+        match self.options.proto {
+            // The PROTO opcode was only added in protocol 2.
+            PickleProto::V0 | PickleProto::V1 => (),
+            PickleProto::V2 => self.writer.write_all(&[PROTO, 2])?,
+            PickleProto::V3 => self.writer.write_all(&[PROTO, 3])?,
+            PickleProto::V4 => self.writer.write_all(&[PROTO, 4])?,
+            PickleProto::V5 => self.writer.write_all(&[PROTO, 5])?,
+        }
+        if self.options.proto >= PickleProto::V4 {
+            self.writer.start_framing();
+        }
+        inner(self)?;
+        self.write_opcode(STOP)?;
+        self.writer.end_framing().map_err(From::from)
     }
 
     #[inline]
     fn write_opcode(&mut self, opcode: u8) -> Result<()> {
+        self.writer.commit_frame(false)?;
         self.writer.write_all(&[opcode]).map_err(From::from)
     }
 
+    // Write a string or bytes opcode with a length prefix of the given size
+    // (1, 4 or 8 bytes), followed by the data.
+    #[inline]
+    fn write_counted(&mut self, opcode: u8, prefix: usize, data: &[u8]) -> Result<()> {
+        if data.len() >= FRAME_SIZE_TARGET {
+            let mut header = [opcode; 9];
+            LittleEndian::write_u64(&mut header[1..], data.len() as u64);
+            return self.writer.write_large(&header[..1 + prefix], data).map_err(From::from);
+        }
+        self.write_opcode(opcode)?;
+        match prefix {
+            1 => self.writer.write_u8(data.len() as u8)?,
+            4 => self.writer.write_u32::<LittleEndian>(data.len() as u32)?,
+            _ => self.writer.write_u64::<LittleEndian>(data.len() as u64)?,
+        }
+        self.writer.write_all(data).map_err(From::from)
+    }
+
+    // Write a text opcode, whose argument is terminated by a newline.
+    fn write_line(&mut self, opcode: u8, line: &[u8]) -> Result<()> {
+        self.write_opcode(opcode)?;
+        self.writer.write_all(line)?;
+        self.writer.write_all(b"\n").map_err(From::from)
+    }
+
+    // Write an integer as text: `INT` for 32-bit values (which is how bools
+    // are spelled, too), `LONG` for all others.
+    fn write_text_int<T: ToString>(&mut self, value: T, long: bool) -> Result<()> {
+        let mut line = value.to_string().into_bytes();
+        if long {
+            line.push(b'L');
+            self.write_line(LONG, &line)
+        } else {
+            self.write_line(INT, &line)
+        }
+    }
+
+    // Write an empty list, dict or tuple.  Protocol 0 has no opcodes for
+    // these, so they are built from an empty MARK.
+    #[inline]
+    fn write_empty(&mut self, opcode: u8, text_opcode: u8) -> Result<()> {
+        if self.options.proto == PickleProto::V0 {
+            self.write_opcode(MARK)?;
+            self.write_opcode(text_opcode)
+        } else {
+            self.write_opcode(opcode)
+        }
+    }
+
+    // Start and end a tuple of one to three items, which is built from a
+    // MARK before protocol 2.
+    #[inline]
+    fn start_tuple(&mut self) -> Result<()> {
+        if self.options.proto < PickleProto::V2 {
+            self.write_opcode(MARK)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn end_tuple(&mut self, opcode: u8) -> Result<()> {
+        if self.options.proto < PickleProto::V2 {
+            self.write_opcode(TUPLE)
+        } else {
+            self.write_opcode(opcode)
+        }
+    }
+
+    // Check the nesting depth before writing a value.  Every successful call
+    // must be paired with `leave`.
+    fn enter(&mut self) -> Result<()> {
+        if self.depth >= self.options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH) {
+            return Err(Error::Syntax(ErrorCode::DepthLimit, None));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    #[inline]
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
     fn serialize_hashable_value(&mut self, value: &HashableValue) -> Result<()> {
+        self.enter()?;
+        let result = self.serialize_hashable_value_inner(value);
+        self.leave();
+        result
+    }
+
+    fn serialize_hashable_value_inner(&mut self, value: &HashableValue) -> Result<()> {
         use serde::Serializer;
+        if let Some(pid) = self.persistent_id(ValueRef::Hashable(value)) {
+            return self.serialize_persistent_id(&pid);
+        }
         match *value {
             // Cases covered by the Serializer trait
             HashableValue::None    => self.serialize_unit(),
             HashableValue::Bool(b) => self.serialize_bool(b),
             HashableValue::I64(i)  => self.serialize_i64(i),
             HashableValue::F64(f)  => self.serialize_f64(f),
-            HashableValue::Bytes(ref b) => self.serialize_bytes(b),
+            HashableValue::Bytes(ref b) => self.serialize_bytes_in_band(b),
             HashableValue::String(ref s) => self.serialize_str(s),
             HashableValue::Int(ref i) => self.serialize_bigint(i),
-            HashableValue::FrozenSet(ref s) => self.serialize_set(s, b"frozenset"),
+            HashableValue::FrozenSet(ref s) if self.options.memoize_tuples => {
+                let id = self.memo.tuple_ids.hashable_id(value);
+                self.serialize_memoized_tuple(id, |slf| slf.serialize_set(s, "frozenset"))
+            }
+            HashableValue::Tuple(ref t) if self.options.memoize_tuples && !t.is_empty() => {
+                let id = self.memo.tuple_ids.hashable_id(value);
+                self.serialize_memoized_tuple(id, |slf| {
+                    slf.serialize_tuplevalue(t, |slf, v| slf.serialize_hashable_value(v))
+                })
+            }
+            HashableValue::FrozenSet(ref s) => self.serialize_set(s, "frozenset"),
             HashableValue::Tuple(ref t) =>
                 self.serialize_tuplevalue(t, |slf, v| slf.serialize_hashable_value(v)),
         }
     }
 
     fn serialize_value(&mut self, value: &Value) -> Result<()> {
+        // The ids of shared values are local to the value they are in.
+        if self.depth == 0 {
+            self.memo.shared.clear();
+        }
+        self.enter()?;
+        let result = self.serialize_value_inner(value);
+        self.leave();
+        result
+    }
+
+    fn serialize_value_inner(&mut self, value: &Value) -> Result<()> {
         use serde::Serializer;
+        if let Some(pid) = self.persistent_id(ValueRef::Value(value)) {
+            return self.serialize_persistent_id(&pid);
+        }
         match *value {
             // Cases covered by the Serializer trait
             Value::None    => self.serialize_unit(),
@@ -127,29 +753,12 @@ impl<W: io::Write> Serializer<W> {
             Value::Bytes(ref b) => self.serialize_bytes(b),
             Value::String(ref s) => self.serialize_str(s),
             Value::List(ref l) => {
-                self.write_opcode(EMPTY_LIST)?;
-                for chunk in l.chunks(1000) {
-                    self.write_opcode(MARK)?;
-                    for item in chunk {
-                        self.serialize_value(item)?;
-                    }
-                    self.write_opcode(APPENDS)?;
-                }
-                Ok(())
+                self.write_empty(EMPTY_LIST, LIST)?;
+                self.serialize_list_items(l)
             },
             Value::Dict(ref d) => {
-                self.write_opcode(EMPTY_DICT)?;
-                self.write_opcode(MARK)?;
-                for (n, (key, value)) in d.iter().enumerate() {
-                    if n % 1000 == 999 {
-                        self.write_opcode(SETITEMS)?;
-                        self.write_opcode(MARK)?;
-                    }
-                    self.serialize_hashable_value(key)?;
-                    self.serialize_value(value)?;
-                }
-                self.write_opcode(SETITEMS)?;
-                Ok(())
+                self.write_empty(EMPTY_DICT, DICT)?;
+                self.serialize_dict_items(d)
             }
 
             // Others
@@ -157,18 +766,382 @@ impl<W: io::Write> Serializer<W> {
                 self.serialize_bigint(i)
             }
             Value::Tuple(ref t) => {
+                if self.options.memoize_tuples && !t.is_empty() {
+                    let id = self.memo.tuple_ids.value_id(value);
+                    return self.serialize_memoized_tuple(id, |slf| {
+                        slf.serialize_tuplevalue(t, |slf, v| slf.serialize_value(v))
+                    });
+                }
                 self.serialize_tuplevalue(t, |slf, v| slf.serialize_value(v))
             },
             Value::Set(ref s) => {
-                self.serialize_set(s, b"set")
+                self.serialize_set(s, "set")
             },
             Value::FrozenSet(ref s) => {
-                self.serialize_set(s, b"frozenset")
+                if self.options.memoize_tuples {
+                    let id = self.memo.tuple_ids.value_id(value);
+                    return self.serialize_memoized_tuple(id, |slf| {
+                        slf.serialize_set(s, "frozenset")
+                    });
+                }
+                self.serialize_set(s, "frozenset")
+            }
+            Value::Shared(id, ref v) => {
+                self.serialize_shared(id, v)
+            }
+            Value::Ref(id) => match self.memo.shared.get(&id).cloned() {
+                Some(index) => self.write_memo_get(index),
+                None => Err(Error::Syntax(ErrorCode::Structure(
+                    "reference to unknown shared value".into()), None)),
+            },
+            Value::Object(ref o) => {
+                self.serialize_object_creation(o)?;
+                self.serialize_object_contents(o)
+            }
+        }
+    }
+
+    // Write the call that creates a class instance.
+    fn serialize_object_creation(&mut self, object: &Object) -> Result<()> {
+        match object.kwargs {
+            Some(ref kwargs) if self.options.proto < PickleProto::V2 => {
+                // Python 2 can't create instances with keyword arguments
+                // below protocol 2.
+                if !kwargs.is_empty() {
+                    return Err(Error::Syntax(ErrorCode::InvalidValue(
+                        "keyword arguments to __new__ need protocol 2".into()), None));
+                }
+                if object.args.is_empty() {
+                    return self.write_new_instance(&object.module, &object.name);
+                }
+                // `copyreg.__newobj__(cls, *args)` is what `NEWOBJ` does.
+                self.write_global("copy_reg", "__newobj__")?;
+                self.write_opcode(MARK)?;
+                self.write_class(&object.module, &object.name)?;
+                for arg in &object.args {
+                    self.serialize_value(arg)?;
+                }
+                self.write_opcode(TUPLE)?;
+                return self.write_opcode(REDUCE);
+            }
+            _ => {}
+        }
+        match object.kwargs {
+            Some(ref kwargs) if !kwargs.is_empty() && self.options.proto < PickleProto::V4 => {
+                // Keyword arguments need NEWOBJ_EX, which was added in
+                // protocol 4.  Below that, `copyreg.__newobj_ex__(cls, args,
+                // kwargs)` does the same.
+                if self.options.proto >= PickleProto::V3 {
+                    self.write_global("copyreg", "__newobj_ex__")?;
+                } else {
+                    self.write_global("copy_reg", "__newobj_ex__")?;
+                }
+                self.write_class(&object.module, &object.name)?;
+                self.serialize_tuplevalue(&object.args, |slf, v| slf.serialize_value(v))?;
+                self.write_kwargs(kwargs)?;
+                self.write_opcode(TUPLE3)?;
+                return self.write_opcode(REDUCE);
+            }
+            _ => {}
+        }
+        self.write_class(&object.module, &object.name)?;
+        self.serialize_tuplevalue(&object.args, |slf, v| slf.serialize_value(v))?;
+        match object.kwargs {
+            None => self.write_opcode(REDUCE),
+            Some(ref kwargs) if kwargs.is_empty() => self.write_opcode(NEWOBJ),
+            Some(ref kwargs) => {
+                self.write_kwargs(kwargs)?;
+                self.write_opcode(NEWOBJ_EX)
             }
         }
     }
 
+    fn write_kwargs(&mut self, kwargs: &BTreeMap<String, Value>) -> Result<()> {
+        use serde::Serializer;
+        self.write_empty(EMPTY_DICT, DICT)?;
+        self.write_opcode(MARK)?;
+        for (key, value) in kwargs {
+            self.serialize_str(key)?;
+            self.serialize_value(value)?;
+        }
+        self.write_opcode(SETITEMS)
+    }
+
+    // Create an instance with `cls.__new__(cls)`.  Below protocol 2, which
+    // added `NEWOBJ`, Python calls `copyreg._reconstructor(cls, object, None)`
+    // for this instead.
+    fn write_new_instance(&mut self, module: &str, name: &str) -> Result<()> {
+        if self.options.proto < PickleProto::V2 {
+            self.write_global("copy_reg", "_reconstructor")?;
+            self.start_tuple()?;
+            self.write_class(module, name)?;
+            self.write_global("__builtin__", "object")?;
+            self.write_opcode(NONE)?;
+            self.end_tuple(TUPLE3)?;
+            self.write_opcode(REDUCE)
+        } else {
+            self.write_class(module, name)?;
+            self.write_empty(EMPTY_TUPLE, TUPLE)?;
+            self.write_opcode(NEWOBJ)
+        }
+    }
+
+    // Write the items and state of a class instance that is on the stack.
+    fn serialize_object_contents(&mut self, object: &Object) -> Result<()> {
+        if !object.listitems.is_empty() {
+            self.serialize_list_items(&object.listitems)?;
+        }
+        if !object.dictitems.is_empty() {
+            self.serialize_dict_items(&object.dictitems)?;
+        }
+        if let Some(ref state) = object.state {
+            self.serialize_value(state)?;
+            self.write_opcode(BUILD)?;
+        }
+        Ok(())
+    }
+
+    fn serialize_list_items(&mut self, items: &[Value]) -> Result<()> {
+        if self.options.proto == PickleProto::V0 {
+            for item in items {
+                self.serialize_value(item)?;
+                self.write_opcode(APPEND)?;
+            }
+            return Ok(());
+        }
+        for chunk in items.chunks(1000) {
+            self.write_opcode(MARK)?;
+            for item in chunk {
+                self.serialize_value(item)?;
+            }
+            self.write_opcode(APPENDS)?;
+        }
+        Ok(())
+    }
+
+    fn serialize_dict_items(&mut self, items: &BTreeMap<HashableValue, Value>) -> Result<()> {
+        if self.options.proto == PickleProto::V0 {
+            for (key, value) in items {
+                self.serialize_hashable_value(key)?;
+                self.serialize_value(value)?;
+                self.write_opcode(SETITEM)?;
+            }
+            return Ok(());
+        }
+        self.write_opcode(MARK)?;
+        for (n, (key, value)) in items.iter().enumerate() {
+            if n % 1000 == 999 {
+                self.write_opcode(SETITEMS)?;
+                self.write_opcode(MARK)?;
+            }
+            self.serialize_hashable_value(key)?;
+            self.serialize_value(value)?;
+        }
+        self.write_opcode(SETITEMS)
+    }
+
+    // Write a value that is referenced from within itself.  The empty
+    // container or new instance is put into the memo under the value's id
+    // before its items are written, so that the references can be resolved by
+    // `GET`.  Other values can't be built before their contents, so they can't
+    // be written.
+    fn serialize_shared(&mut self, id: u32, value: &Value) -> Result<()> {
+        match *value {
+            Value::List(ref l) => {
+                self.write_empty(EMPTY_LIST, LIST)?;
+                self.memoize_shared(id)?;
+                self.serialize_list_items(l)
+            }
+            Value::Dict(ref d) => {
+                self.write_empty(EMPTY_DICT, DICT)?;
+                self.memoize_shared(id)?;
+                self.serialize_dict_items(d)
+            }
+            Value::Object(ref o) => {
+                self.serialize_object_creation(o)?;
+                self.memoize_shared(id)?;
+                self.serialize_object_contents(o)
+            }
+            _ => Err(Error::Syntax(ErrorCode::Recursive, None)),
+        }
+    }
+
+    // Put the value on top of the stack into the memo.  From protocol 4, the
+    // memo index is implicit, so the value's id is mapped to it.  The same is
+    // done when other values are memoized, so that the indices don't clash,
+    // and for canonical output.
+    fn memoize_shared(&mut self, id: u32) -> Result<()> {
+        let proto = self.options.proto;
+        if proto >= PickleProto::V4 || self.options.memoize || self.options.canonical {
+            let index = self.memoize()?;
+            self.memo.shared.insert(id, index);
+            Ok(())
+        } else {
+            self.memo.shared.insert(id, id);
+            self.write_memo_op(PUT, BINPUT, LONG_BINPUT, id)
+        }
+    }
+
+    // Write a tuple or frozenset with the given function, unless one with the
+    // same id is already in the memo.  Tuples without id are mutable, and are
+    // written without memoizing them.
+    fn serialize_memoized_tuple<F>(&mut self, id: Option<u32>, write: F) -> Result<()>
+        where F: FnOnce(&mut Self) -> Result<()>
+    {
+        let result = match id.and_then(|id| self.memo.tuples.get(&id).cloned()) {
+            Some(index) => self.write_memo_get(index),
+            None => {
+                self.memo.tuple_ids.depth += 1;
+                let result = write(self);
+                self.memo.tuple_ids.depth -= 1;
+                match (result, id) {
+                    (Ok(()), Some(id)) => self.memoize().map(|index| {
+                        self.memo.tuples.insert(id, index);
+                    }),
+                    (result, _) => result,
+                }
+            }
+        };
+        // The ids found by address are only valid while the outermost tuple
+        // is written.
+        if self.memo.tuple_ids.depth == 0 {
+            self.memo.tuple_ids.nodes.clear();
+        }
+        result
+    }
+
+    // Put the value on top of the stack into the memo under the next index.
+    fn memoize(&mut self) -> Result<u32> {
+        let index = self.memo.len;
+        self.memo.len += 1;
+        if self.options.proto >= PickleProto::V4 {
+            self.write_opcode(MEMOIZE)?;
+        } else {
+            self.write_memo_op(PUT, BINPUT, LONG_BINPUT, index)?;
+        }
+        Ok(index)
+    }
+
+    fn write_memo_get(&mut self, index: u32) -> Result<()> {
+        self.write_memo_op(GET, BINGET, LONG_BINGET, index)
+    }
+
+    fn write_memo_op(&mut self, text_op: u8, short_op: u8, long_op: u8, id: u32) -> Result<()> {
+        if self.options.proto == PickleProto::V0 {
+            self.write_line(text_op, id.to_string().as_bytes())
+        } else if id < 256 {
+            self.write_opcode(short_op)?;
+            self.writer.write_u8(id as u8).map_err(From::from)
+        } else {
+            self.write_opcode(long_op)?;
+            self.writer.write_u32::<LittleEndian>(id).map_err(From::from)
+        }
+    }
+
+    // Ask the persistent_id callback, if any, for an id to use for the value.
+    fn persistent_id(&self, value: ValueRef) -> Option<Value> {
+        self.options.persistent_id.as_ref().and_then(|callback| callback(value))
+    }
+
+    fn serialize_persistent_id(&mut self, pid: &Value) -> Result<()> {
+        if self.options.proto == PickleProto::V0 {
+            // The text opcode can only take a string, like in Python.
+            return match *pid {
+                Value::String(ref s) if s.is_ascii() && !s.contains('\n') =>
+                    self.write_line(PERSID, s.as_bytes()),
+                _ => Err(Error::Syntax(ErrorCode::InvalidValue(
+                    "persistent ids in protocol 0 must be ASCII strings".into()), None)),
+            };
+        }
+        // Don't ask the callback about the id itself.
+        let callback = self.options.persistent_id.take();
+        let result = self.serialize_value(pid);
+        self.options.persistent_id = callback;
+        result?;
+        self.write_opcode(BINPERSID)
+    }
+
+    // Write a reference to a class, which can be nested in other classes.
+    // `STACK_GLOBAL` looks up qualified names, but before protocol 4, Python
+    // only finds them when they are written as `getattr(outer, name)`.
+    fn write_class(&mut self, module: &str, name: &str) -> Result<()> {
+        use serde::Serializer;
+        match name.rfind('.') {
+            Some(dot) if self.options.proto < PickleProto::V4 => {
+                if self.options.proto >= PickleProto::V3 {
+                    self.write_global("builtins", "getattr")?;
+                } else {
+                    self.write_global("__builtin__", "getattr")?;
+                }
+                self.start_tuple()?;
+                self.write_class(module, &name[..dot])?;
+                self.serialize_str(&name[dot + 1..])?;
+                self.end_tuple(TUPLE2)?;
+                self.write_opcode(REDUCE)
+            }
+            _ => self.write_global(module, name),
+        }
+    }
+
+    // Write a reference to a module global, using the extension registry if
+    // the global is registered there, or else the memo if enabled.
+    fn write_global(&mut self, module: &str, name: &str) -> Result<()> {
+        let code = if self.options.extensions.is_empty() || self.options.proto < PickleProto::V2 {
+            None
+        } else {
+            self.options.extensions.get(&(module.to_owned(), name.to_owned())).cloned()
+        };
+        match code {
+            Some(code) if code <= 0xff => {
+                self.write_opcode(EXT1)?;
+                self.writer.write_u8(code as u8).map_err(From::from)
+            }
+            Some(code) if code <= 0xffff => {
+                self.write_opcode(EXT2)?;
+                self.writer.write_u16::<LittleEndian>(code as u16).map_err(From::from)
+            }
+            Some(code) => {
+                self.write_opcode(EXT4)?;
+                self.writer.write_u32::<LittleEndian>(code).map_err(From::from)
+            }
+            None if self.options.memoize => {
+                let key = (module.to_owned(), name.to_owned());
+                if let Some(&index) = self.memo.globals.get(&key) {
+                    return self.write_memo_get(index);
+                }
+                self.write_global_name(module, name)?;
+                let index = self.memoize()?;
+                self.memo.globals.insert(key, index);
+                Ok(())
+            }
+            None => self.write_global_name(module, name),
+        }
+    }
+
+    fn write_global_name(&mut self, module: &str, name: &str) -> Result<()> {
+        if self.options.proto >= PickleProto::V4 {
+            use serde::Serializer;
+            self.serialize_str(module)?;
+            self.serialize_str(name)?;
+            self.write_opcode(STACK_GLOBAL)
+        } else {
+            self.write_opcode(GLOBAL)?;
+            self.writer.write_all(module.as_bytes())?;
+            self.writer.write_all(b"\n")?;
+            self.writer.write_all(name.as_bytes())?;
+            self.writer.write_all(b"\n").map_err(From::from)
+        }
+    }
+
     fn serialize_bigint(&mut self, i: &BigInt) -> Result<()> {
+        if self.options.canonical {
+            if let Some(value) = i.to_i32() {
+                return self.write_canonical_int(value.into());
+            }
+        }
+        if self.options.proto < PickleProto::V2 {
+            return self.write_text_int(i, true);
+        }
         let bytes = if i.is_negative() {
             let n_bytes = i.to_bytes_le().1.len();
             let pos = i + (BigInt::from(1) << (n_bytes * 8));
@@ -197,23 +1170,60 @@ impl<W: io::Write> Serializer<W> {
         self.writer.write_all(&bytes).map_err(From::from)
     }
 
+    // Write an integer in the shortest form, like Python (canonical output).
+    fn write_canonical_int(&mut self, value: i128) -> Result<()> {
+        let fits = (-0x8000_0000..0x8000_0000).contains(&value);
+        if self.options.proto == PickleProto::V0 {
+            self.write_text_int(value, !fits)
+        } else if (0..0x100).contains(&value) {
+            self.write_opcode(BININT1)?;
+            self.writer.write_u8(value as u8).map_err(From::from)
+        } else if (0..0x1_0000).contains(&value) {
+            self.write_opcode(BININT2)?;
+            self.writer.write_u16::<LittleEndian>(value as u16).map_err(From::from)
+        } else if fits {
+            self.write_opcode(BININT)?;
+            self.writer.write_i32::<LittleEndian>(value as i32).map_err(From::from)
+        } else {
+            self.serialize_bigint(&BigInt::from(value))
+        }
+    }
+
+    // Serialize a map key or value as a `Value`, so that the map's entries
+    // can be sorted (canonical output).
+    fn to_canonical_value<T: Serialize + ?Sized>(&self, value: &T) -> Result<Value> {
+        to_value_with(value, &self.options, self.depth)
+    }
+
+    // Write the collected entries of a map (canonical output).  They come
+    // from serde, so they aren't passed to the persistent_id callback.
+    fn serialize_sorted_items(&mut self, items: &BTreeMap<HashableValue, Value>) -> Result<()> {
+        let callback = self.options.persistent_id.take();
+        let result = self.serialize_dict_items(items);
+        self.options.persistent_id = callback;
+        result
+    }
+
     fn serialize_tuplevalue<T, F>(&mut self, t: &[T], f: F) -> Result<()>
         where F: Fn(&mut Self, &T) -> Result<()>
     {
         if t.is_empty() {
-            self.write_opcode(EMPTY_TUPLE)
+            self.write_empty(EMPTY_TUPLE, TUPLE)
         } else if t.len() == 1 {
+            self.start_tuple()?;
             f(self, &t[0])?;
-            self.write_opcode(TUPLE1)
+            self.end_tuple(TUPLE1)
         } else if t.len() == 2 {
+            self.start_tuple()?;
             f(self, &t[0])?;
             f(self, &t[1])?;
-            self.write_opcode(TUPLE2)
+            self.end_tuple(TUPLE2)
         } else if t.len() == 3 {
+            self.start_tuple()?;
             f(self, &t[0])?;
             f(self, &t[1])?;
             f(self, &t[2])?;
-            self.write_opcode(TUPLE3)
+            self.end_tuple(TUPLE3)
         } else {
             self.write_opcode(MARK)?;
             for item in t.iter() {
@@ -224,26 +1234,118 @@ impl<W: io::Write> Serializer<W> {
         }
     }
 
-    fn serialize_set(&mut self, items: &BTreeSet<HashableValue>, name: &[u8]) -> Result<()> {
-        self.write_opcode(GLOBAL)?;
-        if self.options.proto == PickleProto::V3 {
-            self.writer.write_all(b"builtins\n")?;
+    fn serialize_bytes_in_band(&mut self, value: &[u8]) -> Result<()> {
+        if !self.options.memoize {
+            return self.write_bytes(value);
+        }
+        if let Some(&index) = self.memo.bytes.get(value) {
+            return self.write_memo_get(index);
+        }
+        self.write_bytes(value)?;
+        let index = self.memoize()?;
+        self.memo.bytes.insert(value.into(), index);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, value: &[u8]) -> Result<()> {
+        use serde::Serializer;
+        if self.options.proto >= PickleProto::V3 {
+            if value.len() < 256 {
+                self.write_counted(SHORT_BINBYTES, 1, value)
+            } else if value.len() as u64 <= 0xffff_ffff {
+                self.write_counted(BINBYTES, 4, value)
+            } else if self.options.proto >= PickleProto::V4 {
+                self.write_counted(BINBYTES8, 8, value)
+            } else {
+                Err(Error::Syntax(ErrorCode::LengthLimit(value.len() as u64), None))
+            }
         } else {
-            self.writer.write_all(b"__builtin__\n")?;
+            // We can't use the BINSTRING opcodes because they depend on the
+            // str encoding in Unpickler, which varies between Py2 and Py3.
+            // Instead, pickle the bytes as unicode codepoints and then encode
+            // them as latin1 on unpickling to get the bytes (Python itself
+            // does this trick)
+            // With `SerOptions::memoize`, the global and 'latin1' are only
+            // written out for the first byte string.
+            self.write_global("_codecs", "encode")?;
+            self.start_tuple()?;
+            // BINUNICODE needs a utf8-encoded string, but we're pretending ours
+            // has a latin1 encoding. Happily, the byte values of an encoded latin1
+            // string match their codepoints. So converting to utf8 encoding is
+            // as simple as interpreting each byte as a unicode codepoint and
+            // then encoding as utf8 - https://stackoverflow.com/a/28175593/2352259
+            let utf8_value: String = value.iter().map(|&c| c as char).collect();
+            self.serialize_str(&utf8_value)?;
+            self.serialize_str("latin1")?;
+            self.end_tuple(TUPLE2)?;
+            self.write_opcode(REDUCE).map_err(From::from)
         }
-        self.writer.write_all(name)?;
-        self.writer.write_all(b"\n")?;
-        self.write_opcode(EMPTY_LIST)?;
-        self.write_opcode(MARK)?;
-        for (n, item) in items.iter().enumerate() {
-            if n % 1000 == 999 {
-                self.write_opcode(APPENDS)?;
+    }
+
+    #[inline]
+    fn write_str(&mut self, value: &str) -> Result<()> {
+        let proto = self.options.proto;
+        if proto == PickleProto::V0 {
+            self.write_line(UNICODE, &raw_unicode_escape(value))
+        } else if value.len() < 256 && proto >= PickleProto::V4 {
+            self.write_counted(SHORT_BINUNICODE, 1, value.as_bytes())
+        } else if value.len() as u64 <= 0xffff_ffff {
+            self.write_counted(BINUNICODE, 4, value.as_bytes())
+        } else if proto >= PickleProto::V4 {
+            self.write_counted(BINUNICODE8, 8, value.as_bytes())
+        } else {
+            Err(Error::Syntax(ErrorCode::LengthLimit(value.len() as u64), None))
+        }
+    }
+
+    fn serialize_set(&mut self, items: &BTreeSet<HashableValue>, name: &str) -> Result<()> {
+        if self.options.proto >= PickleProto::V4 {
+            return if name == "set" {
+                self.write_opcode(EMPTY_SET)?;
+                if items.is_empty() {
+                    return Ok(());
+                }
                 self.write_opcode(MARK)?;
+                for (n, item) in items.iter().enumerate() {
+                    if n % 1000 == 999 {
+                        self.write_opcode(ADDITEMS)?;
+                        self.write_opcode(MARK)?;
+                    }
+                    self.serialize_hashable_value(item)?;
+                }
+                self.write_opcode(ADDITEMS)
+            } else {
+                self.write_opcode(MARK)?;
+                for item in items {
+                    self.serialize_hashable_value(item)?;
+                }
+                self.write_opcode(FROZENSET)
+            };
+        }
+        if self.options.proto >= PickleProto::V3 {
+            self.write_global("builtins", name)?;
+        } else {
+            self.write_global("__builtin__", name)?;
+        }
+        self.start_tuple()?;
+        self.write_empty(EMPTY_LIST, LIST)?;
+        if self.options.proto == PickleProto::V0 {
+            for item in items {
+                self.serialize_hashable_value(item)?;
+                self.write_opcode(APPEND)?;
             }
-            self.serialize_hashable_value(item)?;
+        } else {
+            self.write_opcode(MARK)?;
+            for (n, item) in items.iter().enumerate() {
+                if n % 1000 == 999 {
+                    self.write_opcode(APPENDS)?;
+                    self.write_opcode(MARK)?;
+                }
+                self.serialize_hashable_value(item)?;
+            }
+            self.write_opcode(APPENDS)?;
         }
-        self.write_opcode(APPENDS)?;
-        self.write_opcode(TUPLE1)?;
+        self.end_tuple(TUPLE1)?;
         self.write_opcode(REDUCE)
     }
 }
@@ -251,6 +1353,31 @@ impl<W: io::Write> Serializer<W> {
 pub struct Compound<'a, W: io::Write + 'a> {
     ser: &'a mut Serializer<W>,
     state: Option<usize>,
+    sorted: Option<SortedMap>,
+    // For a struct written as a class instance: whether it has slots.
+    instance: Option<bool>,
+}
+
+/// Entries of a map that are sorted before writing, see `SerOptions::canonical`.
+#[derive(Default)]
+struct SortedMap {
+    items: BTreeMap<HashableValue, Value>,
+    key: Option<HashableValue>,
+}
+
+impl<'a, W: io::Write> Compound<'a, W> {
+    // Write the remaining items of a map, and return the serializer.
+    fn end_map(self) -> Result<&'a mut Serializer<W>> {
+        let result = match self.sorted {
+            Some(ref sorted) if !sorted.items.is_empty() =>
+                self.ser.serialize_sorted_items(&sorted.items),
+            Some(_) => Ok(()),
+            None if self.state.unwrap_or(0) > 0 => self.ser.write_opcode(SETITEMS),
+            None => Ok(()),
+        };
+        self.ser.leave();
+        result.map(|_| self.ser)
+    }
 }
 
 impl<'a, W: io::Write> ser::SerializeSeq for Compound<'a, W> {
@@ -259,20 +1386,31 @@ impl<'a, W: io::Write> ser::SerializeSeq for Compound<'a, W> {
 
     #[inline]
     fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
-        value.serialize(&mut *self.ser)?;
-        // Batch appends as in Python pickle
-        *self.state.as_mut().unwrap() += 1;
-        if self.state.unwrap() == 1000 {
-            self.ser.write_opcode(APPENDS)?;
-            self.ser.write_opcode(MARK)?;
-            self.state = Some(0);
+        // Batch appends as in Python pickle, except for protocol 0
+        match self.state {
+            Some(ref mut n) => {
+                if *n == 0 {
+                    self.ser.write_opcode(MARK)?;
+                }
+                value.serialize(&mut *self.ser)?;
+                *n += 1;
+                if *n == 1000 {
+                    self.ser.write_opcode(APPENDS)?;
+                    *n = 0;
+                }
+                Ok(())
+            }
+            None => {
+                value.serialize(&mut *self.ser)?;
+                self.ser.write_opcode(APPEND)
+            }
         }
-        Ok(())
     }
 
     #[inline]
     fn end(self) -> Result<()> {
-        if self.state.is_some() {
+        self.ser.leave();
+        if self.state.unwrap_or(0) > 0 {
             self.ser.write_opcode(APPENDS)?;
         }
         Ok(())
@@ -290,6 +1428,7 @@ impl<'a, W: io::Write> ser::SerializeTuple for Compound<'a, W> {
 
     #[inline]
     fn end(self) -> Result<()> {
+        self.ser.leave();
         if self.state.is_some() {
             self.ser.write_opcode(TUPLE)?;
         }
@@ -318,14 +1457,21 @@ impl<'a, W: io::Write> ser::SerializeTupleVariant for Compound<'a, W> {
 
     #[inline]
     fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
-        value.serialize(&mut *self.ser)
+        value.serialize(&mut *self.ser)?;
+        if self.state.is_none() {
+            self.ser.write_opcode(APPEND)?;
+        }
+        Ok(())
     }
 
     #[inline]
     fn end(self) -> Result<()> {
-        self.ser.write_opcode(APPENDS)?;
+        self.ser.leave();
+        if self.state.is_some() {
+            self.ser.write_opcode(APPENDS)?;
+        }
         if self.ser.options.compat_enum_repr {
-            self.ser.write_opcode(TUPLE2)
+            self.ser.end_tuple(TUPLE2)
         } else {
             self.ser.write_opcode(SETITEM)
         }
@@ -338,28 +1484,44 @@ impl<'a, W: io::Write> ser::SerializeMap for Compound<'a, W> {
 
     #[inline]
     fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        if let Some(ref mut sorted) = self.sorted {
+            sorted.key = Some(self.ser.to_canonical_value(key)?.into_hashable()?);
+            return Ok(());
+        }
+        if self.state == Some(0) {
+            self.ser.write_opcode(MARK)?;
+        }
         key.serialize(&mut *self.ser)
     }
 
     #[inline]
     fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        if let Some(ref mut sorted) = self.sorted {
+            let value = self.ser.to_canonical_value(value)?;
+            let key = sorted.key.take().ok_or_else(|| {
+                Error::Syntax(ErrorCode::Structure("map value without key".into()), None)
+            })?;
+            sorted.items.insert(key, value);
+            return Ok(());
+        }
         value.serialize(&mut *self.ser)?;
-        // Batch appends as in Python pickle
-        *self.state.as_mut().unwrap() += 1;
-        if self.state.unwrap() == 1000 {
-            self.ser.write_opcode(SETITEMS)?;
-            self.ser.write_opcode(MARK)?;
-            self.state = Some(0);
+        // Batch appends as in Python pickle, except for protocol 0
+        match self.state {
+            Some(ref mut n) => {
+                *n += 1;
+                if *n == 1000 {
+                    self.ser.write_opcode(SETITEMS)?;
+                    *n = 0;
+                }
+                Ok(())
+            }
+            None => self.ser.write_opcode(SETITEM),
         }
-        Ok(())
     }
 
     #[inline]
     fn end(self) -> Result<()> {
-        if self.state.is_some() {
-            self.ser.write_opcode(SETITEMS)?;
-        }
-        Ok(())
+        self.end_map().map(|_| ())
     }
 }
 
@@ -375,7 +1537,17 @@ impl<'a, W: io::Write> ser::SerializeStruct for Compound<'a, W> {
 
     #[inline]
     fn end(self) -> Result<()> {
-        ser::SerializeMap::end(self)
+        let instance = self.instance;
+        let ser = self.end_map()?;
+        match instance {
+            Some(slots) => {
+                if slots {
+                    ser.end_tuple(TUPLE2)?;
+                }
+                ser.write_opcode(BUILD)
+            }
+            None => Ok(()),
+        }
     }
 }
 
@@ -390,13 +1562,11 @@ impl<'a, W: io::Write> ser::SerializeStructVariant for Compound<'a, W> {
 
     #[inline]
     fn end(self) -> Result<()> {
-        if self.state.is_some() {
-            self.ser.write_opcode(SETITEMS)?;
-        }
-        if self.ser.options.compat_enum_repr {
-            self.ser.write_opcode(TUPLE2)
+        let ser = self.end_map()?;
+        if ser.options.compat_enum_repr {
+            ser.end_tuple(TUPLE2)
         } else {
-            self.ser.write_opcode(SETITEM)
+            ser.write_opcode(SETITEM)
         }
     }
 }
@@ -415,11 +1585,20 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
 
     #[inline]
     fn serialize_bool(self, value: bool) -> Result<()> {
+        if self.options.proto < PickleProto::V2 {
+            return self.write_line(INT, if value { b"01" } else { b"00" });
+        }
         self.write_opcode(if value { NEWTRUE } else { NEWFALSE })
     }
 
     #[inline]
     fn serialize_i8(self, value: i8) -> Result<()> {
+        if self.options.canonical {
+            return self.write_canonical_int(value.into());
+        }
+        if self.options.proto == PickleProto::V0 {
+            return self.write_text_int(value, false);
+        }
         if value > 0 {
             self.write_opcode(BININT1)?;
             self.writer.write_i8(value).map_err(From::from)
@@ -431,6 +1610,12 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
 
     #[inline]
     fn serialize_i16(self, value: i16) -> Result<()> {
+        if self.options.canonical {
+            return self.write_canonical_int(value.into());
+        }
+        if self.options.proto == PickleProto::V0 {
+            return self.write_text_int(value, false);
+        }
         if value > 0 {
             self.write_opcode(BININT2)?;
             self.writer.write_i16::<LittleEndian>(value).map_err(From::from)
@@ -442,13 +1627,25 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
 
     #[inline]
     fn serialize_i32(self, value: i32) -> Result<()> {
+        if self.options.canonical {
+            return self.write_canonical_int(value.into());
+        }
+        if self.options.proto == PickleProto::V0 {
+            return self.write_text_int(value, false);
+        }
         self.write_opcode(BININT)?;
         self.writer.write_i32::<LittleEndian>(value).map_err(From::from)
     }
 
     #[inline]
     fn serialize_i64(self, value: i64) -> Result<()> {
-        if -0x8000_0000 <= value && value < 0x8000_0000 {
+        if self.options.canonical {
+            return self.write_canonical_int(value.into());
+        }
+        let fits = -0x8000_0000 <= value && value < 0x8000_0000;
+        if self.options.proto < PickleProto::V2 && (!fits || self.options.proto == PickleProto::V0) {
+            self.write_text_int(value, !fits)
+        } else if fits {
             self.write_opcode(BININT)?;
             self.writer.write_i32::<LittleEndian>(value as i32).map_err(From::from)
         } else {
@@ -460,19 +1657,36 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
 
     #[inline]
     fn serialize_u8(self, value: u8) -> Result<()> {
+        if self.options.canonical {
+            return self.write_canonical_int(value.into());
+        }
+        if self.options.proto == PickleProto::V0 {
+            return self.write_text_int(value, false);
+        }
         self.write_opcode(BININT1)?;
         self.writer.write_u8(value).map_err(From::from)
     }
 
     #[inline]
     fn serialize_u16(self, value: u16) -> Result<()> {
+        if self.options.canonical {
+            return self.write_canonical_int(value.into());
+        }
+        if self.options.proto == PickleProto::V0 {
+            return self.write_text_int(value, false);
+        }
         self.write_opcode(BININT2)?;
         self.writer.write_u16::<LittleEndian>(value).map_err(From::from)
     }
 
     #[inline]
     fn serialize_u32(self, value: u32) -> Result<()> {
-        if value < 0x8000_0000 {
+        if self.options.canonical {
+            return self.write_canonical_int(value.into());
+        }
+        if self.options.proto < PickleProto::V2 {
+            self.serialize_u64(value.into())
+        } else if value < 0x8000_0000 {
             self.write_opcode(BININT)?;
             self.writer.write_u32::<LittleEndian>(value).map_err(From::from)
         } else {
@@ -487,7 +1701,13 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
 
     #[inline]
     fn serialize_u64(self, value: u64) -> Result<()> {
-        if value < 0x8000_0000 {
+        if self.options.canonical {
+            return self.write_canonical_int(value.into());
+        }
+        let fits = value < 0x8000_0000;
+        if self.options.proto < PickleProto::V2 && (!fits || self.options.proto == PickleProto::V0) {
+            self.write_text_int(value, !fits)
+        } else if fits {
             self.write_opcode(BININT)?;
             self.writer.write_u32::<LittleEndian>(value as u32).map_err(From::from)
         } else {
@@ -502,14 +1722,18 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
 
     #[inline]
     fn serialize_f32(self, value: f32) -> Result<()> {
-        self.write_opcode(BINFLOAT)?;
-        // Yes, this one is big endian.
-        self.writer.write_f64::<BigEndian>(value.into()).map_err(From::from)
+        self.serialize_f64(value.into())
     }
 
     #[inline]
     fn serialize_f64(self, value: f64) -> Result<()> {
+        if self.options.proto == PickleProto::V0 {
+            // The shortest representation that reads back exactly, which
+            // Python parses just like its own `repr`.
+            return self.write_line(FLOAT, format!("{:?}", value).as_bytes());
+        }
         self.write_opcode(BINFLOAT)?;
+        // Yes, this one is big endian.
         self.writer.write_f64::<BigEndian>(value).map_err(From::from)
     }
 
@@ -522,43 +1746,33 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
 
     #[inline]
     fn serialize_str(self, value: &str) -> Result<()> {
-        self.write_opcode(BINUNICODE)?;
-        self.writer.write_u32::<LittleEndian>(value.len() as u32)?;
-        self.writer.write_all(value.as_bytes()).map_err(From::from)
+        if !self.options.memoize {
+            return self.write_str(value);
+        }
+        if let Some(&index) = self.memo.strings.get(value) {
+            return self.write_memo_get(index);
+        }
+        self.write_str(value)?;
+        let index = self.memoize()?;
+        self.memo.strings.insert(value.into(), index);
+        Ok(())
     }
 
     #[inline]
     fn serialize_bytes(self, value: &[u8]) -> Result<()> {
-        if self.options.proto == PickleProto::V3 {
-            if value.len() < 256 {
-                self.write_opcode(SHORT_BINBYTES)?;
-                self.writer.write_u8(value.len() as u8)?;
-            } else {
-                self.write_opcode(BINBYTES)?;
-                self.writer.write_u32::<LittleEndian>(value.len() as u32)?;
+        if self.options.proto >= PickleProto::V5 {
+            if let Some(ref callback) = self.options.buffer_callback {
+                match callback(value) {
+                    BufferMode::InBand => {}
+                    BufferMode::ReadOnly => {
+                        self.write_opcode(NEXT_BUFFER)?;
+                        return self.write_opcode(READONLY_BUFFER);
+                    }
+                    BufferMode::Writable => return self.write_opcode(NEXT_BUFFER),
+                }
             }
-            self.writer.write_all(value).map_err(From::from)
-        } else {
-            // We can't use the BINSTRING opcodes because they depend on the
-            // str encoding in Unpickler, which varies between Py2 and Py3.
-            // Instead, pickle the bytes as unicode codepoints and then encode
-            // them as latin1 on unpickling to get the bytes (Python itself
-            // does this trick)
-            // TODO: we could keep track of 'codecs\nencode' and 'latin1' in
-            // the memo rather than writing them out for each byte string
-            self.write_opcode(GLOBAL)?;
-            self.writer.write_all(b"_codecs\nencode\n")?;
-            // BINUNICODE needs a utf8-encoded string, but we're pretending ours
-            // has a latin1 encoding. Happily, the byte values of an encoded latin1
-            // string match their codepoints. So converting to utf8 encoding is
-            // as simple as interpreting each byte as a unicode codepoint and
-            // then encoding as utf8 - https://stackoverflow.com/a/28175593/2352259
-            let utf8_value: String = value.iter().map(|&c| c as char).collect();
-            self.serialize_str(&utf8_value)?;
-            self.serialize_str("latin1")?;
-            self.write_opcode(TUPLE2)?;
-            self.write_opcode(REDUCE).map_err(From::from)
         }
+        self.serialize_bytes_in_band(value)
     }
 
     #[inline]
@@ -576,11 +1790,12 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
     #[inline]
     fn serialize_unit_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str)
                               -> Result<()> {
-        self.serialize_str(variant)?;
         if self.options.compat_enum_repr {
-            self.write_opcode(TUPLE1)
+            self.start_tuple()?;
+            self.serialize_str(variant)?;
+            self.end_tuple(TUPLE1)
         } else {
-            Ok(())
+            self.serialize_str(variant)
         }
     }
 
@@ -594,11 +1809,12 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
                                                         _variant_index: u32, variant: &'static str,
                                                         value: &T) -> Result<()> {
         if self.options.compat_enum_repr {
+            self.start_tuple()?;
             self.serialize_str(variant)?;
             value.serialize(&mut *self)?;
-            self.write_opcode(TUPLE2)
+            self.end_tuple(TUPLE2)
         } else {
-            self.write_opcode(EMPTY_DICT)?;
+            self.write_empty(EMPTY_DICT, DICT)?;
             self.serialize_str(variant)?;
             value.serialize(&mut *self)?;
             self.write_opcode(SETITEM)
@@ -616,25 +1832,23 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
     }
 
     #[inline]
-    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
-        self.write_opcode(EMPTY_LIST)?;
-        match len {
-            Some(len) if len == 0 => Ok(Compound { ser: self, state: None }),
-            _ => {
-                self.write_opcode(MARK)?;
-                Ok(Compound { ser: self, state: Some(0) })
-            }
-        }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        self.enter()?;
+        self.write_empty(EMPTY_LIST, LIST)?;
+        // The MARK is written before the first item of each batch.
+        let state = if self.options.proto == PickleProto::V0 { None } else { Some(0) };
+        Ok(Compound { ser: self, state, sorted: None, instance: None })
     }
 
     #[inline]
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.enter()?;
         if len == 0 {
-            self.write_opcode(EMPTY_TUPLE)?;
-            Ok(Compound { ser: self, state: None })
+            self.write_empty(EMPTY_TUPLE, TUPLE)?;
+            Ok(Compound { ser: self, state: None, sorted: None, instance: None })
         } else {
             self.write_opcode(MARK)?;
-            Ok(Compound { ser: self, state: Some(0) })
+            Ok(Compound { ser: self, state: Some(0), sorted: None, instance: None })
         }
     }
 
@@ -647,56 +1861,82 @@ impl<'a, W: io::Write> ser::Serializer for &'a mut Serializer<W> {
     #[inline]
     fn serialize_tuple_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str,
                                _len: usize) -> Result<Self::SerializeTupleVariant> {
-        if !self.options.compat_enum_repr {
-            self.write_opcode(EMPTY_DICT)?;
+        self.enter()?;
+        if self.options.compat_enum_repr {
+            self.start_tuple()?;
+        } else {
+            self.write_empty(EMPTY_DICT, DICT)?;
         }
         self.serialize_str(variant)?;
-        self.write_opcode(EMPTY_LIST)?;
-        self.write_opcode(MARK)?;
-        Ok(Compound { ser: self, state: None })
+        self.write_empty(EMPTY_LIST, LIST)?;
+        if self.options.proto == PickleProto::V0 {
+            Ok(Compound { ser: self, state: None, sorted: None, instance: None })
+        } else {
+            self.write_opcode(MARK)?;
+            Ok(Compound { ser: self, state: Some(0), sorted: None, instance: None })
+        }
     }
 
     #[inline]
-    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap> {
-        self.write_opcode(EMPTY_DICT)?;
-        match len {
-            Some(len) if len == 0 => Ok(Compound { ser: self, state: None }),
-            _ => {
-                self.write_opcode(MARK)?;
-                Ok(Compound { ser: self, state: Some(0) })
-            }
-        }
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        self.enter()?;
+        self.write_empty(EMPTY_DICT, DICT)?;
+        let sorted = if self.options.canonical { Some(SortedMap::default()) } else { None };
+        let state = if self.options.proto == PickleProto::V0 { None } else { Some(0) };
+        Ok(Compound { ser: self, state, sorted, instance: None })
     }
 
     #[inline]
-    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
-        self.serialize_map(Some(len))
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
+        let class = match self.options.classes.get(name) {
+            Some(class) => class.clone(),
+            None => return self.serialize_map(Some(len)),
+        };
+        // Like Python's `Pickler`, create the instance with `cls.__new__(cls)`
+        // and set the fields as its state.
+        self.write_new_instance(&class.module, &class.name)?;
+        if class.slots {
+            self.start_tuple()?;
+            self.write_opcode(NONE)?;
+        }
+        let mut compound = self.serialize_map(Some(len))?;
+        compound.instance = Some(class.slots);
+        Ok(compound)
     }
 
     #[inline]
     fn serialize_struct_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str,
                                 len: usize) -> Result<Self::SerializeStructVariant> {
-        if !self.options.compat_enum_repr {
-            self.write_opcode(EMPTY_DICT)?;
+        if self.options.compat_enum_repr {
+            self.start_tuple()?;
+        } else {
+            self.write_empty(EMPTY_DICT, DICT)?;
         }
         self.serialize_str(variant)?;
         self.serialize_map(Some(len))
     }
 }
 
-fn wrap_write<W: io::Write, F>(mut writer: W, inner: F, options: SerOptions) -> Result<()>
+// Encode a string for the UNICODE opcode with Python's "raw-unicode-escape"
+// codec.  Like Python, also escape the backslash (which the codec doesn't) and
+// characters that could end the line.
+fn raw_unicode_escape(value: &str) -> Vec<u8> {
+    let mut result = Vec::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '\\' | '\0' | '\n' | '\r' | '\x1a' | '\u{100}' ..= '\u{ffff}' =>
+                result.extend_from_slice(format!("\\u{:04x}", ch as u32).as_bytes()),
+            '\u{1}' ..= '\u{ff}' => result.push(ch as u8),
+            _ => result.extend_from_slice(format!("\\U{:08x}", ch as u32).as_bytes()),
+        }
+    }
+    result
+}
+
+fn wrap_write<W: io::Write, F>(writer: W, inner: F, options: SerOptions) -> Result<()>
     where F: FnOnce(&mut Serializer<W>) -> Result<()>
 {
-    writer.write_all(&[PROTO])?;
-    if options.proto == PickleProto::V3 {
-        writer.write_all(b"\x03")?;
-    } else {
-        writer.write_all(b"\x02")?;
-    }
-    let mut ser = Serializer::new(writer, options);
-    inner(&mut ser)?;
-    let mut writer = ser.into_inner();
-    writer.write_all(&[STOP]).map_err(From::from)
+    Serializer::new(writer, options).write_pickle(inner)
 }
 
 
@@ -728,3 +1968,25 @@ pub fn to_vec<T: Serialize>(value: &T, options: SerOptions) -> Result<Vec<u8>> {
     to_writer(&mut writer, value, options)?;
     Ok(writer)
 }
+
+/// Serialize the items of a `HashSet` in sorted order, so that equal sets are
+/// written the same way (see `SerOptions::canonical`).
+///
+/// # Example
+///
+/// ```
+/// # use std::collections::HashSet;
+/// # use serde_derive::Serialize;
+/// #[derive(Serialize)]
+/// struct Tags {
+///     #[serde(serialize_with = "serde_pickle::ser::sorted_set")]
+///     tags: HashSet<String>,
+/// }
+/// ```
+pub fn sorted_set<T, H, S>(set: &HashSet<T, H>, serializer: S) -> StdResult<S::Ok, S::Error>
+    where T: Serialize + Ord, S: ser::Serializer
+{
+    let mut items: Vec<&T> = set.iter().collect();
+    items.sort();
+    serializer.collect_seq(items)
+}
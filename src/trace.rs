@@ -0,0 +1,122 @@
+// Copyright (c) 2015-2021 Georg Brandl.  Licensed under the Apache License,
+// Version 2.0 <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>
+// or the MIT license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at
+// your option. This file may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Tracing the execution of pickle opcodes, see `Deserializer::set_trace`.
+
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+
+use super::consts::*;
+use super::value::Value;
+
+/// Report about a single executed opcode.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trace {
+    /// Offset of the opcode in the input.
+    pub offset: usize,
+    /// The opcode byte.
+    pub opcode: u8,
+    /// Name of the opcode as in Python's `pickletools`, if it is known.
+    pub name: Option<&'static str>,
+    /// The argument encoded in the input after the opcode, if it has one.
+    ///
+    /// Integers are decoded as `I64` or `Int`, floats as `F64`, and strings
+    /// as `Bytes` or (for Unicode strings and text lines) `String`.  Module
+    /// globals are decoded as a tuple of module and name.
+    pub argument: Option<Value>,
+    /// State of the machine before the opcode.
+    pub before: VmState,
+    /// State of the machine after the opcode, or `None` if it failed.
+    pub after: Option<VmState>,
+}
+
+/// Summary of the stack and memo of the pickle machine.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmState {
+    /// Number of items on the stack, not counting marks.
+    pub stack_len: usize,
+    /// Number of marks on the stack.
+    pub marks: usize,
+    /// Short descriptions of the topmost stack items, the topmost last.
+    ///
+    /// At most `VmState::SHOWN_ITEMS` items are shown, and marks are shown as
+    /// `"MARK"`.
+    pub stack_top: Vec<String>,
+    /// Number of entries in the memo.
+    pub memo_len: usize,
+}
+
+impl VmState {
+    /// Maximum number of entries in `stack_top`.
+    pub const SHOWN_ITEMS: usize = 8;
+}
+
+/// Result of `Deserializer::step`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    /// An opcode was executed.
+    Opcode(Trace),
+    /// The `STOP` opcode was executed, which ends the pickle with the value.
+    Stop(Trace, Value),
+}
+
+// Decode the argument of an opcode from the bytes read after it.
+pub(crate) fn decode_argument(opcode: u8, arg: &[u8]) -> Option<Value> {
+    let mut lines = arg.split(|&b| b == b'\n');
+    let mut line = || {
+        let mut line = lines.next().unwrap_or(b"");
+        if line.last() == Some(&b'\r') {
+            line = &line[..line.len() - 1];
+        }
+        String::from_utf8_lossy(line).into_owned()
+    };
+    Some(match opcode {
+        PROTO | BININT1 | BINGET | BINPUT | EXT1 => Value::I64((*arg.first()?).into()),
+        BININT2 | EXT2 if arg.len() == 2 => Value::I64(LittleEndian::read_u16(arg).into()),
+        BININT | EXT4 if arg.len() == 4 => Value::I64(LittleEndian::read_i32(arg).into()),
+        LONG_BINGET | LONG_BINPUT if arg.len() == 4 =>
+            Value::I64(LittleEndian::read_u32(arg).into()),
+        FRAME if arg.len() == 8 => int_value(LittleEndian::read_u64(arg).into()),
+        BINFLOAT if arg.len() == 8 => Value::F64(BigEndian::read_f64(arg)),
+        INT | LONG | GET | PUT => {
+            let line = line();
+            match &*line {
+                "00" if opcode == INT => Value::Bool(false),
+                "01" if opcode == INT => Value::Bool(true),
+                _ => match line.trim_end_matches('L').parse() {
+                    Ok(int) => int_value(int),
+                    Err(_) => Value::String(line),
+                },
+            }
+        }
+        FLOAT => {
+            let line = line();
+            match line.parse() {
+                Ok(float) => Value::F64(float),
+                Err(_) => Value::String(line),
+            }
+        }
+        STRING | UNICODE | PERSID => Value::String(line()),
+        GLOBAL | INST => Value::Tuple(vec![Value::String(line()), Value::String(line())]),
+        SHORT_BINSTRING | SHORT_BINBYTES => Value::Bytes(arg.get(1..)?.to_vec()),
+        BINSTRING | BINBYTES => Value::Bytes(arg.get(4..)?.to_vec()),
+        BINBYTES8 | BYTEARRAY8 => Value::Bytes(arg.get(8..)?.to_vec()),
+        SHORT_BINUNICODE => Value::String(String::from_utf8_lossy(arg.get(1..)?).into_owned()),
+        BINUNICODE => Value::String(String::from_utf8_lossy(arg.get(4..)?).into_owned()),
+        BINUNICODE8 => Value::String(String::from_utf8_lossy(arg.get(8..)?).into_owned()),
+        LONG1 => int_value(BigInt::from_signed_bytes_le(arg.get(1..)?)),
+        LONG4 => int_value(BigInt::from_signed_bytes_le(arg.get(4..)?)),
+        _ => return None,
+    })
+}
+
+fn int_value(int: BigInt) -> Value {
+    match int.to_i64() {
+        Some(int) => Value::I64(int),
+        None => Value::Int(int),
+    }
+}
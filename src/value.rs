@@ -12,7 +12,7 @@ use std::collections::{BTreeMap, BTreeSet};
 use num_bigint::BigInt;
 use num_traits::{Signed, ToPrimitive};
 
-pub use crate::value_impls::{to_value, from_value};
+pub use crate::value_impls::{to_value, from_value, from_value_with};
 
 use crate::error::{Error, ErrorCode};
 
@@ -24,6 +24,12 @@ use crate::error::{Error, ErrorCode};
 /// all integers are long integers, so all are pickled as such.  While decoding,
 /// we simply put all integers that fit into an i64, and use `BigInt` for the
 /// rest.
+///
+/// Note on nesting: values are compared and dropped recursively.  Values that
+/// are decoded, or converted with `to_value` and `from_value`, are limited in
+/// depth (see `DeOptions::max_depth`), but values that are nested many
+/// thousand levels deep by other means can overflow the stack; use
+/// `Value::dismantle` to drop them.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     /// None
@@ -50,6 +56,39 @@ pub enum Value {
     FrozenSet(BTreeSet<HashableValue>),
     /// Dictionary (map)
     Dict(BTreeMap<HashableValue, Value>),
+    /// Value that contains references to itself, marked with an id
+    /// (only produced with `DeOptions::decode_recursive`)
+    Shared(u32, Box<Value>),
+    /// Reference to the enclosing `Shared` value with the given id
+    Ref(u32),
+    /// Instance of an arbitrary class
+    /// (only produced with `DeOptions::decode_objects`)
+    Object(Box<Object>),
+}
+
+/// Represents an instance of a class that has no builtin equivalent, as it was
+/// created by the pickle stream.
+///
+/// When writing the object, it is recreated by calling `module.name(*args)`,
+/// or `module.name.__new__(module.name, *args, **kwargs)` if `kwargs` is not
+/// `None`.  Then the list and dict items are added, and the state is set,
+/// normally by updating the instance `__dict__`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Object {
+    /// Module of the class
+    pub module: String,
+    /// (Qualified) name of the class
+    pub name: String,
+    /// Positional arguments to the class or `__new__`
+    pub args: Vec<Value>,
+    /// Keyword arguments to `__new__`, or `None` if the class was called
+    pub kwargs: Option<BTreeMap<String, Value>>,
+    /// State passed to `__setstate__`, or used to update the `__dict__`
+    pub state: Option<Value>,
+    /// Items appended to the instance (for list subclasses)
+    pub listitems: Vec<Value>,
+    /// Items set on the instance (for dict subclasses)
+    pub dictitems: BTreeMap<HashableValue, Value>,
 }
 
 /// Represents all primitive builtin Python values that can be contained
@@ -103,7 +142,49 @@ impl Value {
             Value::String(s)    => Ok(HashableValue::String(s)),
             Value::FrozenSet(v) => Ok(HashableValue::FrozenSet(v)),
             Value::Tuple(v)     => values_to_hashable(v).map(HashableValue::Tuple),
-            _                   => Err(Error::Syntax(ErrorCode::ValueNotHashable))
+            _                   => Err(Error::Syntax(ErrorCode::ValueNotHashable, None))
+        }
+    }
+
+    /// Drop the value without recursion.
+    ///
+    /// Dropping a value normally recurses into its items, which can overflow
+    /// the stack for values nested many thousand levels deep.
+    pub fn dismantle(self) {
+        let mut values = vec![self];
+        let mut hashables = Vec::new();
+        while !values.is_empty() || !hashables.is_empty() {
+            while let Some(value) = values.pop() {
+                match value {
+                    Value::List(items) | Value::Tuple(items) => values.extend(items),
+                    Value::Set(items) | Value::FrozenSet(items) => hashables.extend(items),
+                    Value::Dict(items) => for (key, value) in items {
+                        hashables.push(key);
+                        values.push(value);
+                    },
+                    Value::Shared(_, value) => values.push(*value),
+                    Value::Object(object) => {
+                        let object = *object;
+                        values.extend(object.args);
+                        values.extend(object.kwargs.into_iter().flat_map(|kwargs| kwargs.into_iter())
+                                                   .map(|(_, value)| value));
+                        values.extend(object.state);
+                        values.extend(object.listitems);
+                        for (key, value) in object.dictitems {
+                            hashables.push(key);
+                            values.push(value);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            while let Some(value) = hashables.pop() {
+                match value {
+                    HashableValue::Tuple(items) => hashables.extend(items),
+                    HashableValue::FrozenSet(items) => hashables.extend(items),
+                    _ => {}
+                }
+            }
         }
     }
 }
@@ -125,6 +206,28 @@ impl HashableValue {
     }
 }
 
+/// A borrowed `Value` or `HashableValue`.
+///
+/// This is passed to the `SerOptions::persistent_id` callback, so that
+/// dictionary keys and set items can be inspected without converting them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueRef<'a> {
+    /// A value
+    Value(&'a Value),
+    /// A dictionary key or set item
+    Hashable(&'a HashableValue),
+}
+
+impl<'a> ValueRef<'a> {
+    /// Copy the referenced value into a `Value`.
+    pub fn to_value(self) -> Value {
+        match self {
+            ValueRef::Value(v) => v.clone(),
+            ValueRef::Hashable(h) => h.clone().into_value(),
+        }
+    }
+}
+
 fn write_elements<'a, I, T>(f: &mut fmt::Formatter, it: I,
                             prefix: &'static str, suffix: &'static str,
                             len: usize, always_comma: bool) -> fmt::Result
@@ -171,6 +274,30 @@ impl fmt::Display for Value {
                 }
                 write!(f, "}}")
             },
+            Value::Shared(_, ref v) => write!(f, "{}", v),
+            Value::Ref(_)        => write!(f, "..."),
+            Value::Object(ref o) => {
+                write!(f, "{}.{}(", o.module, o.name)?;
+                for (i, arg) in o.args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                if let Some(ref kwargs) = o.kwargs {
+                    for (i, (key, value)) in kwargs.iter().enumerate() {
+                        if i > 0 || !o.args.is_empty() {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}={}", key, value)?;
+                    }
+                }
+                write!(f, ")")?;
+                if let Some(ref state) = o.state {
+                    write!(f, " with state {}", state)?;
+                }
+                Ok(())
+            },
         }
     }
 }
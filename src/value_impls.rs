@@ -16,8 +16,10 @@ use serde::{ser, de, forward_to_deserialize_any};
 use serde::ser::Serialize;
 use serde::de::Visitor;
 
-use crate::value::{Value, HashableValue};
+use crate::value::{Value, HashableValue, Object};
 use crate::error::{Error, ErrorCode, Result};
+use crate::ser::SerOptions;
+use crate::de::DEFAULT_MAX_DEPTH;
 
 impl<'de> de::Deserialize<'de> for Value {
     #[inline]
@@ -203,6 +205,8 @@ impl<'de> de::Deserialize<'de> for HashableValue {
 /// Deserializes a decoded value into any serde supported value.
 pub struct Deserializer {
     value: Option<Value>,
+    depth: usize,
+    max_depth: usize,
 }
 
 impl Deserializer {
@@ -210,19 +214,12 @@ impl Deserializer {
     pub fn new(value: Value) -> Deserializer {
         Deserializer {
             value: Some(value),
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
-}
-
-impl<'de: 'a, 'a> de::Deserializer<'de> for &'a mut Deserializer {
-    type Error = Error;
-
-    fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value> {
-        let value = match self.value.take() {
-            Some(value) => value,
-            None => { return Err(Error::Syntax(ErrorCode::EOFWhileParsing)); }
-        };
 
+    fn visit_value<'de, V: Visitor<'de>>(&mut self, value: Value, visitor: V) -> Result<V::Value> {
         match value {
             Value::None => visitor.visit_unit(),
             Value::Bool(v) => visitor.visit_bool(v),
@@ -232,7 +229,7 @@ impl<'de: 'a, 'a> de::Deserializer<'de> for &'a mut Deserializer {
                     visitor.visit_i64(i)
                 } else {
                     return Err(Error::Syntax(
-                        ErrorCode::InvalidValue("integer too large".into())));
+                        ErrorCode::InvalidValue("integer too large".into()), None));
                 }
             },
             Value::F64(v) => visitor.visit_f64(v),
@@ -241,14 +238,14 @@ impl<'de: 'a, 'a> de::Deserializer<'de> for &'a mut Deserializer {
             Value::List(v) => {
                 let len = v.len();
                 visitor.visit_seq(SeqDeserializer {
-                    de: &mut self,
+                    de: self,
                     iter: v.into_iter(),
                     len,
                 })
             },
             Value::Tuple(v) => {
                 visitor.visit_seq(SeqDeserializer {
-                    de: &mut self,
+                    de: self,
                     len: v.len(),
                     iter: v.into_iter(),
                 })
@@ -256,7 +253,7 @@ impl<'de: 'a, 'a> de::Deserializer<'de> for &'a mut Deserializer {
             Value::Set(v) | Value::FrozenSet(v) => {
                 let v: Vec<_> = v.into_iter().map(HashableValue::into_value).collect();
                 visitor.visit_seq(SeqDeserializer {
-                    de: &mut self,
+                    de: self,
                     len: v.len(),
                     iter: v.into_iter(),
                 })
@@ -264,21 +261,56 @@ impl<'de: 'a, 'a> de::Deserializer<'de> for &'a mut Deserializer {
             Value::Dict(v) => {
                 let len = v.len();
                 visitor.visit_map(MapDeserializer {
-                    de: &mut self,
+                    de: self,
                     iter: v.into_iter(),
                     value: None,
                     len,
                 })
             },
+            Value::Shared(_, v) => {
+                self.value = Some(*v);
+                de::Deserializer::deserialize_any(self, visitor)
+            },
+            Value::Ref(_) => Err(Error::Syntax(ErrorCode::Recursive, None)),
+            Value::Object(o) => {
+                self.value = Some(o.state.unwrap_or_else(|| Value::Dict(BTreeMap::new())));
+                de::Deserializer::deserialize_any(self, visitor)
+            },
         }
     }
+}
+
+impl<'de: 'a, 'a> de::Deserializer<'de> for &'a mut Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let value = match self.value.take() {
+            Some(value) => value,
+            None => { return Err(Error::Syntax(ErrorCode::EOFWhileParsing, None)); }
+        };
+
+        // Containers are limited in depth like decoded values.
+        match value {
+            Value::List(_) | Value::Tuple(_) | Value::Set(_) | Value::FrozenSet(_) |
+            Value::Dict(_) => {}
+            _ => return self.visit_value(value, visitor),
+        }
+        if self.depth >= self.max_depth {
+            value.dismantle();
+            return Err(Error::Syntax(ErrorCode::DepthLimit, None));
+        }
+        self.depth += 1;
+        let result = self.visit_value(value, visitor);
+        self.depth -= 1;
+        result
+    }
 
     #[inline]
     fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
         match self.value {
             Some(Value::None) => visitor.visit_none(),
             Some(_) => visitor.visit_some(self),
-            None => Err(Error::Syntax(ErrorCode::EOFWhileParsing)),
+            None => Err(Error::Syntax(ErrorCode::EOFWhileParsing, None)),
         }
     }
 
@@ -322,7 +354,7 @@ impl<'de: 'a, 'a> de::EnumAccess<'de> for &'a mut Deserializer {
             Some(Value::Dict(v)) => {
                 if v.len() != 1 {
                     Err(Error::Syntax(ErrorCode::Structure("enum variants must \
-                                                            have one dict entry".into())))
+                                                            have one dict entry".into()), None))
                 } else {
                     let (name, args) = v.into_iter().next().unwrap();
                     self.value = Some(name.into_value());
@@ -337,7 +369,7 @@ impl<'de: 'a, 'a> de::EnumAccess<'de> for &'a mut Deserializer {
                 Ok((val, self))
             }
             _ => Err(Error::Syntax(ErrorCode::Structure("enums must be represented as \
-                                                         dicts or tuples".into())))
+                                                         dicts or tuples".into()), None))
         }
     }
 }
@@ -422,20 +454,51 @@ impl<'de: 'a, 'a> de::MapAccess<'de> for MapDeserializer<'a> {
 
 
 /// Create a `serde::Serializer` that serializes a `Serialize`e into a `Value`.
-pub struct Serializer;
+///
+/// With serializer options, enums and structs are represented like the pickle
+/// `Serializer` writes them.
+#[derive(Default)]
+pub struct Serializer<'o> {
+    options: Option<&'o SerOptions>,
+    depth: usize,
+}
+
+impl<'o> Serializer<'o> {
+    // Limit the nesting depth of containers like the pickle `Serializer`.
+    fn enter(&mut self) -> Result<()> {
+        let max_depth = self.options.and_then(|options| options.max_depth);
+        if self.depth >= max_depth.unwrap_or(DEFAULT_MAX_DEPTH) {
+            return Err(Error::Syntax(ErrorCode::DepthLimit, None));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn compat_enum_repr(&self) -> bool {
+        match self.options {
+            Some(options) => options.compat_enum_repr,
+            None => false,
+        }
+    }
 
-impl Default for Serializer {
-    fn default() -> Self {
-        Serializer
+    // Represent a variant with its content, like the pickle `Serializer`.
+    fn variant(&self, variant: &str, value: Value) -> Value {
+        if self.compat_enum_repr() {
+            Value::Tuple(vec![Value::String(variant.into()), value])
+        } else {
+            let mut d = BTreeMap::new();
+            d.insert(HashableValue::String(variant.into()), value);
+            Value::Dict(d)
+        }
     }
 }
 
-pub struct SerializeSeq<'a> {
-    ser: &'a mut Serializer,
+pub struct SerializeSeq<'a, 'o> {
+    ser: &'a mut Serializer<'o>,
     state: Vec<Value>,
 }
 
-impl<'a> ser::SerializeSeq for SerializeSeq<'a> {
+impl<'a, 'o> ser::SerializeSeq for SerializeSeq<'a, 'o> {
     type Ok = Value;
     type Error = Error;
 
@@ -447,11 +510,12 @@ impl<'a> ser::SerializeSeq for SerializeSeq<'a> {
 
     #[inline]
     fn end(self) -> Result<Value> {
+        self.ser.depth -= 1;
         Ok(Value::List(self.state))
     }
 }
 
-impl<'a> ser::SerializeTuple for SerializeSeq<'a> {
+impl<'a, 'o> ser::SerializeTuple for SerializeSeq<'a, 'o> {
     type Ok = Value;
     type Error = Error;
 
@@ -462,11 +526,12 @@ impl<'a> ser::SerializeTuple for SerializeSeq<'a> {
 
     #[inline]
     fn end(self) -> Result<Value> {
+        self.ser.depth -= 1;
         Ok(Value::Tuple(self.state))
     }
 }
 
-impl<'a> ser::SerializeTupleStruct for SerializeSeq<'a> {
+impl<'a, 'o> ser::SerializeTupleStruct for SerializeSeq<'a, 'o> {
     type Ok = Value;
     type Error = Error;
 
@@ -477,17 +542,18 @@ impl<'a> ser::SerializeTupleStruct for SerializeSeq<'a> {
 
     #[inline]
     fn end(self) -> Result<Value> {
+        self.ser.depth -= 1;
         Ok(Value::Tuple(self.state))
     }
 }
 
-pub struct SerializeTupleVariant<'a> {
-    ser: &'a mut Serializer,
+pub struct SerializeTupleVariant<'a, 'o> {
+    ser: &'a mut Serializer<'o>,
     variant: &'a str,
     state: Vec<Value>,
 }
 
-impl<'a> ser::SerializeTupleVariant for SerializeTupleVariant<'a> {
+impl<'a, 'o> ser::SerializeTupleVariant for SerializeTupleVariant<'a, 'o> {
     type Ok = Value;
     type Error = Error;
 
@@ -499,20 +565,21 @@ impl<'a> ser::SerializeTupleVariant for SerializeTupleVariant<'a> {
 
     #[inline]
     fn end(self) -> Result<Value> {
-        let mut d = BTreeMap::new();
-        d.insert(HashableValue::String(self.variant.into()), Value::List(self.state));
-        Ok(Value::Dict(d))
+        self.ser.depth -= 1;
+        Ok(self.ser.variant(self.variant, Value::List(self.state)))
     }
 }
 
-pub struct SerializeMap<'a> {
-    ser: &'a mut Serializer,
+pub struct SerializeMap<'a, 'o> {
+    ser: &'a mut Serializer<'o>,
     variant: &'a str,
+    // For a struct represented as a class instance, see `SerOptions::class`.
+    instance: Option<(&'o str, &'o str, bool)>,
     key: Option<HashableValue>,
     state: BTreeMap<HashableValue, Value>,
 }
 
-impl<'a> ser::SerializeMap for SerializeMap<'a> {
+impl<'a, 'o> ser::SerializeMap for SerializeMap<'a, 'o> {
     type Ok = Value;
     type Error = Error;
 
@@ -533,11 +600,12 @@ impl<'a> ser::SerializeMap for SerializeMap<'a> {
 
     #[inline]
     fn end(self) -> Result<Value> {
+        self.ser.depth -= 1;
         Ok(Value::Dict(self.state))
     }
 }
 
-impl<'a> ser::SerializeStruct for SerializeMap<'a> {
+impl<'a, 'o> ser::SerializeStruct for SerializeMap<'a, 'o> {
     type Ok = Value;
     type Error = Error;
 
@@ -552,11 +620,26 @@ impl<'a> ser::SerializeStruct for SerializeMap<'a> {
 
     #[inline]
     fn end(self) -> Result<Value> {
-        Ok(Value::Dict(self.state))
+        self.ser.depth -= 1;
+        let (module, name, slots) = match self.instance {
+            Some(instance) => instance,
+            None => return Ok(Value::Dict(self.state)),
+        };
+        let fields = Value::Dict(self.state);
+        let state = if slots { Value::Tuple(vec![Value::None, fields]) } else { fields };
+        Ok(Value::Object(Box::new(Object {
+            module: module.into(),
+            name: name.into(),
+            args: Vec::new(),
+            kwargs: Some(BTreeMap::new()),
+            state: Some(state),
+            listitems: Vec::new(),
+            dictitems: BTreeMap::new(),
+        })))
     }
 }
 
-impl<'a> ser::SerializeStructVariant for SerializeMap<'a> {
+impl<'a, 'o> ser::SerializeStructVariant for SerializeMap<'a, 'o> {
     type Ok = Value;
     type Error = Error;
 
@@ -567,21 +650,20 @@ impl<'a> ser::SerializeStructVariant for SerializeMap<'a> {
 
     #[inline]
     fn end(self) -> Result<Value> {
-        let mut d = BTreeMap::new();
-        d.insert(HashableValue::String(self.variant.into()), Value::Dict(self.state));
-        Ok(Value::Dict(d))
+        self.ser.depth -= 1;
+        Ok(self.ser.variant(self.variant, Value::Dict(self.state)))
     }
 }
 
-impl<'a> ser::Serializer for &'a mut Serializer {
+impl<'a, 'o> ser::Serializer for &'a mut Serializer<'o> {
     type Ok = Value;
     type Error = Error;
 
-    type SerializeSeq = SerializeSeq<'a>;
+    type SerializeSeq = SerializeSeq<'a, 'o>;
     type SerializeTuple = Self::SerializeSeq;
     type SerializeTupleStruct = Self::SerializeSeq;
-    type SerializeTupleVariant = SerializeTupleVariant<'a>;
-    type SerializeMap = SerializeMap<'a>;
+    type SerializeTupleVariant = SerializeTupleVariant<'a, 'o>;
+    type SerializeMap = SerializeMap<'a, 'o>;
     type SerializeStruct = Self::SerializeMap;
     type SerializeStructVariant = Self::SerializeMap;
 
@@ -674,6 +756,9 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     #[inline]
     fn serialize_unit_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str)
                               -> Result<Value> {
+        if self.compat_enum_repr() {
+            return Ok(Value::Tuple(vec![Value::String(variant.into())]));
+        }
         Ok(Value::String(variant.into()))
     }
 
@@ -686,9 +771,8 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     #[inline]
     fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _variant_index: u32,
                                                         variant: &'static str, value: &T) -> Result<Value> {
-        let mut d = BTreeMap::new();
-        d.insert(HashableValue::String(variant.into()), to_value(&value)?);
-        Ok(Value::Dict(d))
+        let value = value.serialize(&mut *self)?;
+        Ok(self.variant(variant, value))
     }
 
     #[inline]
@@ -703,11 +787,13 @@ impl<'a> ser::Serializer for &'a mut Serializer {
 
     #[inline]
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        self.enter()?;
         Ok(SerializeSeq { ser: self, state: vec![] })
     }
 
     #[inline]
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.enter()?;
         Ok(SerializeSeq { ser: self, state: Vec::with_capacity(len) })
     }
 
@@ -720,35 +806,65 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     #[inline]
     fn serialize_tuple_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str,
                                len: usize) -> Result<Self::SerializeTupleVariant> {
+        self.enter()?;
         Ok(SerializeTupleVariant { ser: self, variant, state: Vec::with_capacity(len) })
     }
 
     #[inline]
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
-        Ok(SerializeMap { ser: self, variant: "", key: None, state: BTreeMap::new() })
+        self.enter()?;
+        Ok(SerializeMap { ser: self, variant: "", instance: None, key: None,
+                          state: BTreeMap::new() })
     }
 
     #[inline]
-    fn serialize_struct(self, _name: &'static str, _len: usize)
+    fn serialize_struct(self, name: &'static str, _len: usize)
                         -> Result<Self::SerializeStruct> {
-        Ok(SerializeMap { ser: self, variant: "", key: None, state: BTreeMap::new() })
+        self.enter()?;
+        let instance = self.options.and_then(|options| options.classes.get(name))
+                                   .map(|class| (&*class.module, &*class.name, class.slots));
+        Ok(SerializeMap { ser: self, variant: "", instance, key: None, state: BTreeMap::new() })
     }
 
     #[inline]
     fn serialize_struct_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str,
                                 _len: usize) -> Result<Self::SerializeStructVariant> {
-        Ok(SerializeMap { ser: self, variant, key: None, state: BTreeMap::new() })
+        self.enter()?;
+        Ok(SerializeMap { ser: self, variant, instance: None, key: None,
+                          state: BTreeMap::new() })
     }
 }
 
 
 /// Serialize any serde serializable object into a `value::Value`.
+///
+/// Like with `SerOptions::max_depth`, the nesting depth is limited to 256
+/// (`ErrorCode::DepthLimit`).
 pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value> {
-    value.serialize(&mut Serializer)
+    value.serialize(&mut Serializer::default())
+}
+
+/// Serialize into a `value::Value` as the pickle `Serializer` would write it
+/// with the given options, starting at the given nesting depth.
+pub(crate) fn to_value_with<T: Serialize + ?Sized>(value: &T, options: &SerOptions, depth: usize)
+                                                   -> Result<Value> {
+    value.serialize(&mut Serializer { options: Some(options), depth })
 }
 
 /// Deserialize a `value::Value` from any serde deserializable object.
+///
+/// Like with `DeOptions::max_depth`, the nesting depth is limited to 256
+/// (`ErrorCode::DepthLimit`); use `from_value_with` for a different limit.
 pub fn from_value<'de, T: de::Deserialize<'de>>(value: Value) -> Result<T> {
     let mut de = Deserializer::new(value);
     de::Deserialize::deserialize(&mut de)
 }
+
+/// Like `from_value`, but with a different limit for the nesting depth of the
+/// value's containers.
+///
+/// Values are visited recursively, so a higher limit may need a larger stack.
+pub fn from_value_with<'de, T: de::Deserialize<'de>>(value: Value, max_depth: usize) -> Result<T> {
+    let mut de = Deserializer { value: Some(value), depth: 0, max_depth };
+    de::Deserialize::deserialize(&mut de)
+}
@@ -94,6 +94,8 @@ impl Arbitrary for Value {
             Value::Set(ref v) => Box::new(Arbitrary::shrink(v).map(Value::Set)),
             Value::FrozenSet(ref v) => Box::new(Arbitrary::shrink(v).map(Value::FrozenSet)),
             Value::Dict(ref v) => Box::new(Arbitrary::shrink(v).map(Value::Dict)),
+            Value::Shared(_, ref v) => Box::new(Arbitrary::shrink(&**v)),
+            Value::Ref(_) | Value::Object(_) => empty_shrinker(),
         }
     }
 }
@@ -39,12 +39,15 @@ macro_rules! hpyobj {
 
 mod struct_tests {
     use std::fmt;
+    use std::io;
+    use std::cell::Cell;
     use std::iter::FromIterator;
-    use std::collections::BTreeMap;
+    use std::collections::{BTreeMap, HashMap, HashSet};
     use serde::{ser, de};
     use serde_derive::{Serialize, Deserialize};
-    use crate::{HashableValue, SerOptions, Value, from_slice, from_value, to_value,
-                to_vec, value_from_slice, value_to_vec};
+    use crate::{HashableValue, SerOptions, DeOptions, Value, Object, Deserializer, from_slice,
+                from_reader, from_value, to_value, to_vec, value_from_slice, value_to_vec};
+    use crate::error::{Error, ErrorCode};
 
     #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
     struct Inner {
@@ -96,8 +99,8 @@ mod struct_tests {
         assert_eq!(py_val, target);
     }
 
-    fn test_decode_ok<'de, T>(pyvalue: Value, target: T)
-        where T: PartialEq + fmt::Debug + de::Deserialize<'de>,
+    fn test_decode_ok<T>(pyvalue: Value, target: T)
+        where T: PartialEq + fmt::Debug + de::DeserializeOwned,
     {
         // Test deserialization from pickle.
         let vec = value_to_vec(&pyvalue, Default::default()).unwrap();
@@ -168,6 +171,73 @@ mod struct_tests {
         test_encode_ok_with_opt(Animal::Cat { age: 5, name: "Molyneux".into() },
                                 pyobj!(t=(s="Cat", d={s="age" => i=5, s="name" => s="Molyneux"})),
                                 SerOptions::new().compat_enum_repr());
+
+        // Also for the sorted entries of maps in canonical output.
+        let map = HashMap::<_, _>::from_iter(vec![(1, Animal::Dog),
+                                                  (2, Animal::Frog("Henry".into(), vec![1]))]);
+        test_encode_ok_with_opt(map, pyobj!(d={i=1 => t=(s="Dog"),
+                                               i=2 => t=(s="Frog", l=[s="Henry", l=[i=1]])}),
+                                SerOptions::new().compat_enum_repr().canonical());
+    }
+
+    #[test]
+    fn encode_sorted_set() {
+        #[derive(Serialize)]
+        struct Tags {
+            #[serde(serialize_with = "crate::ser::sorted_set")]
+            tags: HashSet<String>,
+        }
+
+        let tags1 = Tags { tags: (0..100).map(|i| format!("t{}", i)).collect() };
+        let mut tags2 = Tags { tags: HashSet::with_capacity(1000) };
+        tags2.tags.extend((0..100).rev().map(|i| format!("t{}", i)));
+        let vec = to_vec(&tags1, SerOptions::new().canonical()).unwrap();
+        assert_eq!(vec, to_vec(&tags2, SerOptions::new().canonical()).unwrap());
+        let mut expected: Vec<_> = tags1.tags.iter().cloned().collect();
+        expected.sort();
+        assert_eq!(from_slice::<BTreeMap<String, Vec<String>>>(&vec, Default::default()).unwrap(),
+                   BTreeMap::from_iter(vec![("tags".to_string(), expected)]));
+    }
+
+    #[test]
+    fn encode_class_instance() {
+        let inner = Inner { a: (), b: 32, c: vec!["doc".into()] };
+        let vec = to_vec(&inner, SerOptions::new().class("Inner", "geo", "Inner")).unwrap();
+        assert_eq!(vec, &b"\x80\x03cgeo\nInner\n)\x81}(X\x01\x00\x00\x00aN\
+                           X\x01\x00\x00\x00bJ \x00\x00\x00\
+                           X\x01\x00\x00\x00c](X\x03\x00\x00\x00doceub."[..]);
+        let object = Object {
+            module: "geo".into(),
+            name: "Inner".into(),
+            args: vec![],
+            kwargs: Some(BTreeMap::new()),
+            state: Some(pyobj!(d={s="a" => n=None, s="b" => i=32, s="c" => l=[s="doc"]})),
+            listitems: vec![],
+            dictitems: BTreeMap::new(),
+        };
+        assert_eq!(value_from_slice(&vec, DeOptions::new().decode_objects()).unwrap(),
+                   Value::Object(Box::new(object.clone())));
+        assert_eq!(from_slice::<Inner>(&vec, Default::default()).unwrap(), inner);
+
+        // Classes with slots, nested in other classes, in all protocols.
+        let outer = Outer { inner: vec![inner.clone(), inner] };
+        for options in &[SerOptions::new().proto_v0(), SerOptions::new().proto_v1(),
+                         SerOptions::new().proto_v2(), SerOptions::new().proto_v4(),
+                         SerOptions::new().canonical()] {
+            let options = options.clone().class_with_slots("Inner", "geo", "Shape.Inner");
+            let vec = to_vec(&outer, options).unwrap();
+            let object = Object {
+                name: "Shape.Inner".into(),
+                state: Some(Value::Tuple(vec![Value::None, object.state.clone().unwrap()])),
+                ..object.clone()
+            };
+            let object = Value::Object(Box::new(object));
+            let list = Value::List(vec![object.clone(), object]);
+            let expected = Value::Dict(BTreeMap::from_iter(vec![(hpyobj!(s="inner"), list)]));
+            assert_eq!(value_from_slice(&vec, DeOptions::new().decode_objects()).unwrap(),
+                       expected);
+            assert_eq!(from_slice::<Outer>(&vec, Default::default()).unwrap(), outer);
+        }
     }
 
     #[test]
@@ -201,6 +271,193 @@ mod struct_tests {
                        Inner { a: (), b: 32, c: vec!["doc".into()] });
     }
 
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Borrowed<'a> {
+        name: &'a str,
+        alias: &'a str,
+        #[serde(borrow)]
+        data: Vec<&'a [u8]>,
+    }
+
+    #[test]
+    fn decode_borrowed() {
+        // The "x" string is memoized and referenced twice.
+        let data = b"\x80\x03}q\x00(X\x04\x00\x00\x00nameq\x01X\x01\x00\x00\x00xq\x02\
+                     X\x05\x00\x00\x00aliasq\x03h\x02X\x04\x00\x00\x00dataq\x04]q\x05\
+                     (C\x02\x00\x01q\x06C\x00q\x07eu.";
+        let mut de = Deserializer::from_slice(data, Default::default());
+        let val: Borrowed = de::Deserialize::deserialize(&mut de).unwrap();
+        de.end().unwrap();
+        assert_eq!(val, Borrowed { name: "x", alias: "x", data: vec![b"\x00\x01", b""] });
+        // The strings point into the input.
+        let start = data.as_ptr() as usize;
+        let inside = |ptr: *const u8| (start..start + data.len()).contains(&(ptr as usize));
+        assert!(inside(val.name.as_ptr()) && inside(val.data[0].as_ptr()));
+        let val: Borrowed = from_slice(data, Default::default()).unwrap();
+        assert!(inside(val.alias.as_ptr()));
+
+        // Strings of the text protocol can't be borrowed.
+        let mut de = Deserializer::from_slice(b"Vabc\n.", Default::default());
+        assert!(<&str as de::Deserialize>::deserialize(&mut de).is_err());
+        let mut de = Deserializer::from_slice(b"Vabc\n.", Default::default());
+        assert_eq!(<String as de::Deserialize>::deserialize(&mut de).unwrap(), "abc");
+    }
+
+    #[test]
+    fn decode_streamed() {
+        fn roundtrip<T>(value: T)
+            where T: PartialEq + fmt::Debug + ser::Serialize + de::DeserializeOwned,
+        {
+            let vec = to_vec(&value, Default::default()).unwrap();
+            let val: T = from_slice(&vec, DeOptions::new().stream_containers()).unwrap();
+            assert_eq!(val, value);
+        }
+        roundtrip(Outer { inner: vec![Inner { a: (), b: 1, c: vec!["x".into(), "y".into()] },
+                                      Inner { a: (), b: 2, c: vec![] }] });
+        // Several batches of items.
+        roundtrip((0..2500).map(|i| vec![i; i % 3]).collect::<Vec<_>>());
+        roundtrip(BTreeMap::from_iter(vec![("a".to_string(), Some(vec![(1, 2)])),
+                                           ("b".to_string(), None)]));
+        roundtrip(Some(vec![Animal::Dog, Animal::Cat { age: 5, name: "?".into() }]));
+
+        // Containers that are referenced again, modified, or wrapped in other
+        // values after their items are decoded give the same result.
+        fn same<T>(data: &[u8], value: T)
+            where T: PartialEq + fmt::Debug + de::DeserializeOwned,
+        {
+            let val: T = from_slice(data, Default::default()).unwrap();
+            assert_eq!(val, value);
+            let val: T = from_slice(data, DeOptions::new().stream_containers()).unwrap();
+            assert_eq!(val, value);
+            let val: T = from_reader(data, DeOptions::new().stream_containers()).unwrap();
+            assert_eq!(val, value);
+        }
+        same(b"\x80\x02](]q\x01(K\x01K\x02e]q\x02K\x03ah\x01e.",
+             vec![vec![1, 2], vec![3], vec![1, 2]]);
+        same(b"\x80\x02](K\x01e\x85.", vec![vec![1]]);
+        same(b"\x80\x02]q\x00(K\x01eh\x00K\x02a.", vec![1, 2]);
+        same(b"\x80\x02](]q\x01(K\x01eh\x01K\x02ae.", vec![vec![1, 2], vec![1, 2]]);
+        same(b"\x80\x02](K\x01e(K\x02\x85e.", (1, (2,)));
+        same(b"\x80\x02}(X\x01\x00\x00\x00ac__builtin__\nset\n](K\x01e\x85Ru.",
+             BTreeMap::from_iter(vec![("a".to_string(), vec![1])]));
+        // Pickles written by Python.
+        same(b"\x80\x02}q\x00(X\x01\x00\x00\x00aq\x01]q\x02(K\x01K\x02eX\x01\x00\x00\x00bq\x03]q\x04u.",
+             BTreeMap::from_iter(vec![("a".to_string(), vec![1, 2]), ("b".to_string(), vec![])]));
+        same(b"\x80\x04\x95\x12\x00\x00\x00\x00\x00\x00\x00]\x94(]\x94(K\x01K\x02e]\x94K\x03ae.",
+             vec![vec![1, 2], vec![3]]);
+        same(b"(lp0\n(lp1\nI1\naI2\naa(lp2\nI3\naa.", vec![vec![1, 2], vec![3]]);
+
+        // Memoized lists as written by Python are streamed: the items of the
+        // first batch are visited before the second one is decoded.
+        thread_local!(static EXECUTED: Cell<usize> = Cell::new(0));
+        struct Probe(usize);
+        impl<'de> de::Deserialize<'de> for Probe {
+            fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                de::IgnoredAny::deserialize(deserializer)?;
+                Ok(Probe(EXECUTED.with(Cell::get)))
+            }
+        }
+        let mut data = b"\x80\x02]q\x00(".to_vec();
+        data.extend(b"K\x01".repeat(1000));
+        data.extend(b"e(");
+        data.extend(b"K\x01".repeat(500));
+        data.extend(b"e.");
+        for &stream in &[false, true] {
+            let options = if stream { DeOptions::new().stream_containers() } else { DeOptions::new() };
+            let mut de = Deserializer::from_slice(&data, options);
+            de.set_trace(|_| EXECUTED.with(|n| n.set(n.get() + 1)));
+            let val: Vec<Probe> = de::Deserialize::deserialize(&mut de).unwrap();
+            assert_eq!(val[0].0 < val[1000].0, stream);
+            EXECUTED.with(|n| n.set(0));
+        }
+        // The same from a reader, which is left after the end of the pickle.
+        data.extend(b"rest");
+        let rdr = io::Cursor::new(&data[..]);
+        let mut de = Deserializer::from_buf_read(rdr, DeOptions::new().stream_containers());
+        de.set_trace(|_| EXECUTED.with(|n| n.set(n.get() + 1)));
+        let val: Vec<Probe> = de::Deserialize::deserialize(&mut de).unwrap();
+        assert!(val[0].0 < val[1000].0);
+        assert_eq!(io::BufRead::fill_buf(&mut de.into_inner()).unwrap(), b"rest");
+        EXECUTED.with(|n| n.set(0));
+        // Reading ahead stops at the limits, which decoding then reports.
+        let mut data = b"\x80\x02](K\x01X\x64\x00\x00\x00".to_vec();
+        data.extend(b"x".repeat(100));
+        data.extend(b"e.");
+        let options = DeOptions::new().stream_containers().max_length(10);
+        match from_reader::<_, Vec<Value>>(&data[..], options) {
+            Err(Error::Eval(ErrorCode::LengthLimit(100), _)) => { }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // At most 1 MiB is read ahead: larger pickles are decoded in full, as
+        // they are read.
+        thread_local! {
+            static READ: Cell<usize> = Cell::new(0);
+            static READ_AT_START: Cell<usize> = Cell::new(0);
+        }
+        struct Counting<'a>(&'a [u8]);
+        impl<'a> io::Read for Counting<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.read(buf)?;
+                READ.with(|r| r.set(r.get() + n));
+                Ok(n)
+            }
+        }
+        let value = (0..300_000).collect::<Vec<i64>>();
+        let data = to_vec(&value, Default::default()).unwrap();
+        assert!(data.len() > 1 << 20);
+        let mut de = Deserializer::new(Counting(&data), DeOptions::new().stream_containers());
+        de.set_trace(|trace| if trace.name == Some("EMPTY_LIST") {
+            READ_AT_START.with(|n| n.set(READ.with(Cell::get)));
+        });
+        let val: Vec<i64> = de::Deserialize::deserialize(&mut de).unwrap();
+        assert_eq!(val, value);
+        // The reader is wrapped in a `BufReader` with a buffer of 8 KiB.
+        assert!(READ_AT_START.with(Cell::get) <= (1 << 20) + 8192);
+    }
+
+    #[test]
+    fn error_context() {
+        // Errors while executing an opcode report it.
+        let data = b"\x80\x02K\x01a.";
+        let err = value_from_slice(data, DeOptions::new()).unwrap_err();
+        let context = err.context().unwrap();
+        assert_eq!((context.offset, context.opcode), (5, Some(("APPEND", 4))));
+        assert_eq!(err.to_string(), "eval error at offset 5 (opcode APPEND at 4): \
+                                     pickle stack underflow");
+
+        // Errors while deserializing report the path.
+        let mut value = Outer { inner: vec![Inner { a: (), b: 1, c: vec![] }; 3] };
+        value.inner[2].c = vec!["x".into(), "y".into()];
+        let mut pyvalue = to_value(&value).unwrap();
+        if let Value::Dict(ref mut dict) = pyvalue {
+            if let Some(Value::List(inner)) = dict.get_mut(&HashableValue::String("inner".into())) {
+                inner[1] = Value::Dict(BTreeMap::from_iter(vec![
+                    (HashableValue::String("a".into()), Value::None),
+                    (HashableValue::String("b".into()), Value::String("two".into())),
+                    (HashableValue::String("c".into()), Value::List(vec![])),
+                ]));
+            }
+        }
+        let data = value_to_vec(&pyvalue, Default::default()).unwrap();
+        for options in vec![DeOptions::new(), DeOptions::new().stream_containers()] {
+            match from_slice::<Outer>(&data, options) {
+                Err(Error::Syntax(ErrorCode::Structure(_), Some(context))) =>
+                    assert_eq!(context.path, "inner[1].b"),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        let err = from_slice::<Vec<(i32, String)>>(b"\x80\x02]K\x01K\x02\x86a.", Default::default())
+            .unwrap_err();
+        assert_eq!(err.context().unwrap().path, "[0][1]");
+        assert_eq!(err.context().unwrap().offset, 10);
+        // Other dict keys are given by the index of the item.
+        let data = value_to_vec(&pyobj!(d={i=1 => i=2, t=(i=1, i=2) => s="x"}), Default::default())
+            .unwrap();
+        let err = from_slice::<BTreeMap<HashableValue, i32>>(&data, Default::default()).unwrap_err();
+        assert_eq!(err.context().unwrap().path, "[1]");
+    }
+
     #[test]
     fn decode_enum() {
         // tuple representation
@@ -233,15 +490,19 @@ mod struct_tests {
 
 mod value_tests {
     use std::fs::File;
-    use std::collections::{BTreeMap, BTreeSet};
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
     use std::iter::FromIterator;
+    use std::sync::{Arc, Mutex};
     use num_bigint::BigInt;
     use rand::{RngCore, thread_rng};
     use quickcheck::{QuickCheck, StdGen};
     use serde_json;
-    use crate::{value_from_reader, value_to_vec, value_from_slice, to_vec, from_slice};
-    use crate::{Value, HashableValue, SerOptions, DeOptions};
-    use crate::Deserializer;
+    use crate::{value_from_reader, value_to_vec, value_from_slice, to_vec, from_slice, to_value,
+                from_value, from_value_with};
+    use crate::{Value, HashableValue, Object, SerOptions, DeOptions, GlobalPolicy, Reconstruct,
+                StringEncoding, BufferMode, ValueRef};
+    use crate::{Deserializer, StreamDeserializer, PickleParser, ParseStatus, Step, Trace, VmState,
+                Serializer};
     use crate::error::{Error, ErrorCode};
 
     // combinations of (python major, pickle proto) to test
@@ -301,14 +562,246 @@ mod value_tests {
         assert_eq!(dict, tripped);
     }
 
+    #[test]
+    fn protocol_4() {
+        let options = SerOptions::new().proto_v4();
+        for proto in &[2, 3, 4, 5] {
+            let file = File::open(format!("test/data/tests_py3_proto{}.pickle", proto)).unwrap();
+            let value = value_from_reader(file, Default::default()).unwrap();
+            let vec = value_to_vec(&value, options.clone()).unwrap();
+            assert_eq!(value_from_slice(&vec, Default::default()).unwrap(), value);
+            let vec = value_to_vec(&value, SerOptions::new().proto_v5()).unwrap();
+            assert_eq!(value_from_slice(&vec, Default::default()).unwrap(), value);
+        }
+
+        // Short strings, native sets and frozensets, and a small frame.
+        let vec = value_to_vec(&pyobj!(t=(ss=(s="a"), fs=(s="a"))), options.clone()).unwrap();
+        assert_eq!(vec, b"\x80\x04\x95\x0d\x00\x00\x00\x00\x00\x00\x00\
+                          \x8f(\x8c\x01a\x90(\x8c\x01a\x91\x86.");
+        // Globals are written with STACK_GLOBAL.
+        let object = Value::Object(Box::new(Object {
+            module: "decimal".into(), name: "Decimal".into(), args: vec![pyobj!(s="1.5")],
+            kwargs: None, state: None, listitems: vec![], dictitems: BTreeMap::new(),
+        }));
+        let vec = value_to_vec(&object, options.clone()).unwrap();
+        assert_eq!(&vec[11..], b"\x8c\x07decimal\x8c\x07Decimal\x93\x8c\x031.5\x85R.");
+
+        // Large pickles are split into frames of 64 KiB, and large strings
+        // are written outside of frames.
+        let list = Value::List((0..20000).map(|i| Value::String(format!("{:08}", i))).collect());
+        let vec = value_to_vec(&list, options.clone()).unwrap();
+        assert_eq!(value_from_slice(&vec, Default::default()).unwrap(), list);
+        let mut de = Deserializer::new(&vec[..], Default::default());
+        let frames = Arc::new(Mutex::new(vec![]));
+        let sizes = frames.clone();
+        de.set_trace(move |trace: &Trace| if let Some(Value::I64(size)) = trace.argument {
+            if trace.opcode == b'\x95' {
+                sizes.lock().unwrap().push(size);
+            }
+        });
+        de.deserialize_value().unwrap();
+        let frames = frames.lock().unwrap();
+        assert_eq!(frames.len(), 4);
+        assert!(frames[..3].iter().all(|&size| size >= 65536));
+        let string = Value::String("x".repeat(100000));
+        let vec = value_to_vec(&string, options).unwrap();
+        assert_eq!(&vec[..7], b"\x80\x04X\xa0\x86\x01\x00");
+        assert_eq!(&vec[100007..], b".");
+
+        // Shared values are memoized without an explicit index.
+        let val = Value::Shared(300, Box::new(Value::List(vec![Value::Ref(300)])));
+        let vec = value_to_vec(&val, SerOptions::new().proto_v4()).unwrap();
+        assert_eq!(vec, b"\x80\x04\x95\x07\x00\x00\x00\x00\x00\x00\x00]\x94(h\x00e.");
+    }
+
+    #[test]
+    fn protocols_0_and_1() {
+        for options in &[SerOptions::new().proto_v0(), SerOptions::new().proto_v1()] {
+            for &(major, proto) in TEST_CASES {
+                let file = File::open(format!("test/data/tests_py{}_proto{}.pickle", major, proto))
+                    .unwrap();
+                let value = value_from_reader(file, Default::default()).unwrap();
+                let vec = value_to_vec(&value, options.clone()).unwrap();
+                assert_eq!(value_from_slice(&vec, Default::default()).unwrap(), value);
+            }
+        }
+
+        // Protocol 0 uses text opcodes only, with raw-unicode-escaped strings.
+        let value = (true, -5i8, 1u64 << 40, 0.1f64, "a\\\u{e9}\u{20ac}\u{1f600}\n", vec![1, 2], ());
+        let vec = to_vec(&value, SerOptions::new().proto_v0()).unwrap();
+        assert_eq!(vec, &b"(I01\nI-5\nL1099511627776L\nF0.1\n\
+                           Va\\u005c\xe9\\u20ac\\U0001f600\\u000a\n(lI1\naI2\naNt."[..]);
+        assert_eq!(from_slice::<(bool, i8, u64, f64, String, Vec<i32>, ())>(&vec, Default::default())
+                   .unwrap(), (value.0, value.1, value.2, value.3, value.4.into(), value.5, ()));
+        // Protocol 1 has no PROTO header, bools, long integers or small tuples.
+        let vec = to_vec(&(true, 1u64 << 40, (1,)), SerOptions::new().proto_v1()).unwrap();
+        assert_eq!(vec, b"(I01\nL1099511627776L\n(J\x01\x00\x00\x00tt.");
+
+        // The memo is used with PUT/GET, or their binary forms.
+        let val = Value::Shared(300, Box::new(Value::List(vec![Value::Ref(300)])));
+        let vec = value_to_vec(&val, SerOptions::new().proto_v0()).unwrap();
+        assert_eq!(vec, b"(lp300\ng300\na.");
+        let vec = value_to_vec(&val, SerOptions::new().proto_v1()).unwrap();
+        assert_eq!(vec, b"]r,\x01\x00\x00(j,\x01\x00\x00e.");
+    }
+
+    #[test]
+    fn memoize() {
+        // Repeated strings are written once, and referenced by the shortest
+        // opcodes for the protocol.
+        let value = vec!["a", "a"];
+        let vec = to_vec(&value, SerOptions::new().proto_v0().memoize()).unwrap();
+        assert_eq!(vec, b"(lVa\np0\nag0\na.");
+        let vec = to_vec(&value, SerOptions::new().memoize()).unwrap();
+        assert_eq!(vec, b"\x80\x03](X\x01\x00\x00\x00aq\x00h\x00e.");
+        let vec = to_vec(&value, SerOptions::new().proto_v4().memoize()).unwrap();
+        assert_eq!(&vec[11..], b"](\x8c\x01a\x94h\x00e.");
+
+        let rows: Vec<BTreeMap<String, i32>> = (0..1000).map(|i| BTreeMap::from_iter(vec![
+            ("name".to_string(), i), ("value".to_string(), 2 * i)])).collect();
+        let plain = to_vec(&rows, SerOptions::new()).unwrap();
+        let vec = to_vec(&rows, SerOptions::new().memoize()).unwrap();
+        assert!(vec.len() < plain.len() * 2 / 3);
+        assert_eq!(from_slice::<Vec<BTreeMap<String, i32>>>(&vec, Default::default()).unwrap(), rows);
+
+        // Tuples are memoized by their exact value, and only if immutable.
+        let value = pyobj!(l=[bb=b"b", bb=b"b", t=(i=1), t=(i=1), t=(f=1.0), t=(l=[]), t=(l=[]),
+                              fs=(i=1), fs=(i=1)]);
+        for options in &[SerOptions::new().proto_v0(), SerOptions::new().proto_v2(),
+                         SerOptions::new(), SerOptions::new().proto_v5()] {
+            let plain = value_to_vec(&value, options.clone()).unwrap();
+            let vec = value_to_vec(&value, options.clone().memoize_tuples()).unwrap();
+            assert!(vec.len() < plain.len());
+            assert_eq!(value_from_slice(&vec, Default::default()).unwrap(), value);
+        }
+        // Nested tuples are memoized at every level.
+        let mut nested = pyobj!(t=(s="leaf"));
+        for i in 0..100 {
+            nested = Value::Tuple(vec![Value::I64(i), nested]);
+        }
+        let once = value_to_vec(&Value::List(vec![nested.clone()]),
+                                SerOptions::new().memoize_tuples()).unwrap();
+        let value = Value::List(vec![nested.clone(), nested.clone(),
+                                     Value::Tuple(vec![Value::I64(1), nested])]);
+        let vec = value_to_vec(&value, SerOptions::new().memoize_tuples()).unwrap();
+        assert!(vec.len() < once.len() + 20);
+        assert_eq!(value_from_slice(&vec, Default::default()).unwrap(), value);
+
+        // Memo indices don't clash with shared values.
+        let value = Value::List(vec![pyobj!(s="a"),
+                                     Value::Shared(0, Box::new(Value::List(vec![Value::Ref(0)])))]);
+        let vec = value_to_vec(&value, SerOptions::new().memoize()).unwrap();
+        assert_eq!(vec, b"\x80\x03](X\x01\x00\x00\x00aq\x00]q\x01(h\x01ee.");
+        // Shared ids are local to each pickle.
+        let mut ser = Serializer::new(Vec::new(), SerOptions::new().memoize());
+        ser.dump_value(&value).unwrap();
+        ser.dump_value(&Value::Shared(0, Box::new(Value::List(vec![Value::Ref(0)])))).unwrap();
+        assert!(ser.dump_value(&Value::List(vec![Value::Ref(0)])).is_err());
+        let output = ser.into_inner();
+        assert_eq!(&output[vec.len()..vec.len() + 10], b"\x80\x03]q\x02(h\x02e.");
+
+        // The memo is kept across pickles.
+        let mut ser = Serializer::new(Vec::new(), SerOptions::new().memoize());
+        ser.dump("x").unwrap();
+        ser.dump(&vec!["x", "y"]).unwrap();
+        ser.clear_memo();
+        ser.dump_value(&pyobj!(s="x")).unwrap();
+        let vec = ser.into_inner();
+        assert_eq!(vec, &b"\x80\x03X\x01\x00\x00\x00xq\x00.\
+                           \x80\x03](h\x00X\x01\x00\x00\x00yq\x01e.\
+                           \x80\x03X\x01\x00\x00\x00xq\x00."[..]);
+        let mut de = Deserializer::new(&vec[..], DeOptions::new().keep_memo());
+        assert_eq!(de.deserialize_value().unwrap(), pyobj!(s="x"));
+        assert_eq!(de.deserialize_value().unwrap(), pyobj!(l=[s="x", s="y"]));
+        de.reset_memo();
+        assert_eq!(de.deserialize_value().unwrap(), pyobj!(s="x"));
+        let mut de = Deserializer::new(&vec[..], DeOptions::new());
+        de.deserialize_value().unwrap();
+        assert!(de.deserialize_value().is_err());
+        let expected = vec![pyobj!(s="x"), pyobj!(l=[s="x", s="y"]), pyobj!(s="x")];
+        let values = StreamDeserializer::values(&vec[..], DeOptions::new().keep_memo())
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(values, expected);
+        let mut parser = PickleParser::new(DeOptions::new().keep_memo());
+        let mut values = vec![];
+        for byte in vec.chunks(1) {
+            if let ParseStatus::Done(value) = parser.feed(byte).unwrap() {
+                values.push(value);
+            }
+        }
+        assert_eq!(parser.finish().unwrap(), vec![]);
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn canonical() {
+        // Maps are written in key order, whatever their iteration order.
+        let map1: HashMap<String, i32> = (0..100).map(|i| (format!("k{}", i), i)).collect();
+        let mut map2 = HashMap::with_capacity(1000);
+        map2.extend((0..100).rev().map(|i| (format!("k{}", i), i)));
+        for options in &[SerOptions::new().proto_v0(), SerOptions::new().proto_v2(),
+                         SerOptions::new().proto_v4().memoize()] {
+            let options = options.clone().canonical();
+            let vec = to_vec(&map1, options.clone()).unwrap();
+            assert_eq!(vec, to_vec(&map2, options.clone()).unwrap());
+            assert_eq!(vec, value_to_vec(&to_value(&map1).unwrap(), options).unwrap());
+            assert_eq!(from_slice::<HashMap<String, i32>>(&vec, Default::default()).unwrap(), map1);
+        }
+        let vec = to_vec(&BTreeMap::from_iter(vec![(2, "b"), (-1, "a")]),
+                         SerOptions::new().canonical()).unwrap();
+        assert_eq!(vec, b"\x80\x03}(J\xff\xff\xff\xffX\x01\x00\x00\x00aK\x02X\x01\x00\x00\x00bu.");
+
+        // Integers are written in the same form for all Rust types.
+        let canonical = SerOptions::new().canonical();
+        assert_eq!(to_vec(&1000u64, canonical.clone()).unwrap(), b"\x80\x03M\xe8\x03.");
+        assert_eq!(to_vec(&1000i16, canonical.clone()).unwrap(), b"\x80\x03M\xe8\x03.");
+        assert_eq!(value_to_vec(&Value::Int(BigInt::from(1000)), canonical.clone()).unwrap(),
+                   b"\x80\x03M\xe8\x03.");
+
+        // Shared values get consecutive memo indices.
+        let value = Value::List(vec![Value::Shared(7, Box::new(pyobj!(l=[]))), Value::Ref(7),
+                                     Value::Shared(3, Box::new(pyobj!(l=[]))), Value::Ref(3)]);
+        let vec = value_to_vec(&value, canonical).unwrap();
+        assert_eq!(vec, b"\x80\x03](]q\x00h\x00]q\x01h\x01e.");
+    }
+
     #[test]
     fn recursive() {
         for proto in &[0, 1, 2, 3, 4, 5] {
             let file = File::open(format!("test/data/test_recursive_proto{}.pickle", proto)).unwrap();
             match value_from_reader(file, Default::default()) {
-                Err(Error::Syntax(ErrorCode::Recursive)) => { }
+                Err(Error::Syntax(ErrorCode::Recursive, _)) => { }
                 _ => assert!(false, "wrong/no error returned for recursive structure")
             }
+            let file = File::open(format!("test/data/test_recursive_proto{}.pickle", proto)).unwrap();
+            let val = value_from_reader(file, DeOptions::new().decode_recursive()).unwrap();
+            let id = match val {
+                Value::Shared(id, _) => id,
+                _ => panic!("no shared value decoded: {:?}", val)
+            };
+            let expected = Value::Shared(id, Box::new(Value::List(vec![
+                Value::Tuple(vec![Value::List(vec![Value::Ref(id)])])])));
+            assert_eq!(val, expected);
+        }
+
+        // Recursive lists and dicts can be written back.
+        let val = Value::Shared(300, Box::new(Value::Dict(BTreeMap::from_iter(vec![
+            (hpyobj!(s="self"), Value::Ref(300)),
+            (hpyobj!(s="list"), pyobj!(l=[i=1])),
+        ]))));
+        let vec = value_to_vec(&val, Default::default()).unwrap();
+        assert_eq!(value_from_slice(&vec, DeOptions::new().decode_recursive()).unwrap(), val);
+        assert!(value_from_reader(&vec[..], DeOptions::new()).is_err());
+        let val = Value::Shared(1, Box::new(pyobj!(t=(i=1))));
+        assert!(value_to_vec(&val, Default::default()).is_err());
+        // References must be to an enclosing shared value.
+        for options in &[SerOptions::new(), SerOptions::new().proto_v0(), SerOptions::new().proto_v4()] {
+            let val = pyobj!(l=[i=1]);
+            let val = Value::List(vec![Value::Ref(1), Value::Shared(1, Box::new(val))]);
+            match value_to_vec(&val, options.clone()) {
+                Err(Error::Syntax(ErrorCode::Structure(_), _)) => {}
+                other => panic!("unknown reference written: {:?}", other),
+            }
         }
     }
 
@@ -328,9 +821,12 @@ mod value_tests {
     #[test]
     fn qc_roundtrip() {
         fn roundtrip(original: Value) {
-            let vec: Vec<_> = value_to_vec(&original, Default::default()).unwrap();
-            let tripped = value_from_slice(&vec, Default::default()).unwrap();
-            assert_eq!(original, tripped);
+            for options in &[SerOptions::new(), SerOptions::new().proto_v0(),
+                             SerOptions::new().proto_v1(), SerOptions::new().proto_v4()] {
+                let vec: Vec<_> = value_to_vec(&original, options.clone()).unwrap();
+                let tripped = value_from_slice(&vec, Default::default()).unwrap();
+                assert_eq!(original, tripped);
+            }
         }
         QuickCheck::new().gen(StdGen::new(thread_rng(), 10))
                          .tests(5000)
@@ -364,6 +860,789 @@ mod value_tests {
         de.end().unwrap();
     }
 
+    #[test]
+    fn string_encodings() {
+        // Python 2 str objects 'caf\xc3\xa9' and 'caf\xe9', in both protocols.
+        let proto0 = b"(lp0\nS'caf\\xc3\\xa9'\np1\naS'caf\\xe9'\np2\na.";
+        let proto2 = b"\x80\x02]q\x00(U\x05caf\xc3\xa9q\x01U\x04caf\xe9q\x02e.";
+        let check = |encoding, first: Value, second: Value| {
+            for data in &[&proto0[..], &proto2[..]] {
+                let options = DeOptions::new().string_encoding(encoding);
+                assert_eq!(value_from_slice(data, options).unwrap(),
+                           Value::List(vec![first.clone(), second.clone()]));
+            }
+        };
+        check(StringEncoding::Bytes, pyobj!(bb=b"caf\xc3\xa9"), pyobj!(bb=b"caf\xe9"));
+        check(StringEncoding::Utf8Lossy, pyobj!(s="café"), pyobj!(s="caf\u{fffd}"));
+        check(StringEncoding::Latin1, pyobj!(s="cafÃ©"), pyobj!(s="café"));
+        check(StringEncoding::Utf8OrLatin1, pyobj!(s="café"), pyobj!(s="café"));
+        for data in &[&proto0[..], &proto2[..]] {
+            let options = DeOptions::new().string_encoding(StringEncoding::Utf8);
+            match value_from_slice(data, options) {
+                Err(Error::Eval(ErrorCode::StringNotUTF8, _)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn persistent_ids() {
+        // Pickled by Python, with persistent_id() mapping 'ext' to 'abc'.
+        let proto0 = b"(lp0\nPabc\naI1\na.";
+        let proto2 = b"\x80\x02]q\x00(X\x03\x00\x00\x00abcq\x01QK\x01e.";
+        let options = DeOptions::new().persistent_load(|pid| {
+            assert_eq!(pid, pyobj!(s="abc"));
+            Ok(pyobj!(s="ext"))
+        });
+        for data in &[&proto0[..], &proto2[..]] {
+            assert!(value_from_slice(data, Default::default()).is_err());
+            let val = value_from_slice(data, options.clone()).unwrap();
+            assert_eq!(val, pyobj!(l=[s="ext", i=1]));
+        }
+
+        let options = SerOptions::new().persistent_id(|value| match value {
+            ValueRef::Value(&Value::String(ref s)) |
+            ValueRef::Hashable(&HashableValue::String(ref s)) if s == "ext" =>
+                Some(pyobj!(t=(s="abc", i=1))),
+            _ => None,
+        });
+        let val = pyobj!(l=[s="ext", s="int", d={s="ext" => s="ext"}, fs=(t=(s="ext"))]);
+        let vec = value_to_vec(&val, options).unwrap();
+        let options = DeOptions::new().persistent_load(|pid| {
+            assert_eq!(pid, pyobj!(t=(s="abc", i=1)));
+            Ok(pyobj!(s="ext"))
+        });
+        assert_eq!(vec.windows(3).filter(|w| w == b"abc").count(), 4);
+        assert_eq!(value_from_slice(&vec, options).unwrap(), val);
+    }
+
+    #[test]
+    fn extension_registry() {
+        // Pickled by Python after registering the globals in copyreg.
+        let data = b"\x80\x02]q\x00(\x82\x01]q\x01K\x01a\x85q\x02Rq\x03\x834\x12]q\x04K\x02a\
+                     \x85q\x05Rq\x06\x84V4\x12\x00X\x01\x00\x00\x00aq\x07X\x06\x00\x00\x00\
+                     latin1q\x08\x86q\tRq\ne.";
+        match value_from_slice(data, Default::default()) {
+            Err(Error::Eval(ErrorCode::UnregisteredExtension(1), _)) => { }
+            _ => assert!(false, "wrong/no error returned for unregistered extension")
+        }
+        let options = DeOptions::new().extension("builtins", "set", 1)
+                                      .extension("builtins", "frozenset", 0x1234)
+                                      .extension("_codecs", "encode", 0x123456);
+        let val = value_from_slice(data, options.clone()).unwrap();
+        assert_eq!(val, pyobj!(l=[ss=(i=1), fs=(i=2), bb=b"a"]));
+
+        let options = SerOptions::new().proto_v2()
+                                       .extension("__builtin__", "set", 1)
+                                       .extension("__builtin__", "frozenset", 0x1234)
+                                       .extension("_codecs", "encode", 0x123456);
+        let vec = value_to_vec(&val, options).unwrap();
+        assert!(vec.windows(2).any(|w| w == b"\x82\x01"));
+        assert!(vec.windows(3).any(|w| w == b"\x834\x12"));
+        assert!(vec.windows(5).any(|w| w == b"\x84V4\x12\x00"));
+        let options = DeOptions::new().extension("__builtin__", "set", 1)
+                                      .extension("__builtin__", "frozenset", 0x1234)
+                                      .extension("_codecs", "encode", 0x123456);
+        assert_eq!(value_from_slice(&vec, options).unwrap(), val);
+    }
+
+    #[test]
+    fn out_of_band_buffers() {
+        // Pickled by Python with a buffer_callback taking all PickleBuffers.
+        let data = b"\x80\x05\x95\r\x00\x00\x00\x00\x00\x00\x00]\x94(\x97\x98\x97C\x02in\x94e.";
+        match value_from_slice(data, Default::default()) {
+            Err(Error::Eval(ErrorCode::MissingBuffer, _)) => { }
+            _ => assert!(false, "wrong/no error returned for missing buffer")
+        }
+        let mut de = Deserializer::new(&data[..], DeOptions::new());
+        de.set_buffers(vec![b"abc".to_vec(), b"xyz".to_vec()]);
+        assert_eq!(de.deserialize_value().unwrap(), pyobj!(l=[bb=b"abc", bb=b"xyz", bb=b"in"]));
+        // The first buffer was a bytes object, the second a bytearray.
+        assert_eq!(de.readonly_buffers(), &[true, false]);
+
+        let buffers = Arc::new(Mutex::new(Vec::new()));
+        let sink = buffers.clone();
+        let options = SerOptions::new().proto_v5().buffer_callback(move |data| {
+            if data.len() < 3 {
+                return BufferMode::InBand;
+            }
+            sink.lock().unwrap().push(data.to_vec());
+            BufferMode::ReadOnly
+        });
+        let val = pyobj!(l=[bb=b"abc", bb=b"in", d={bb=b"key" => bb=b"xyz"}]);
+        let vec = value_to_vec(&val, options).unwrap();
+        assert_eq!(vec.iter().filter(|&&b| b == 0x97).count(), 2);
+        let buffers = buffers.lock().unwrap().clone();
+        assert_eq!(buffers, vec![b"abc".to_vec(), b"xyz".to_vec()]);
+        let mut de = Deserializer::new(vec.as_slice(), DeOptions::new());
+        de.set_buffers(buffers);
+        assert_eq!(de.deserialize_value().unwrap(), val);
+        assert_eq!(de.readonly_buffers(), &[true, true]);
+
+        // Writable buffers are not marked read-only.
+        let options = SerOptions::new().proto_v5().buffer_callback(|data: &[u8]| {
+            if data == b"abc" { BufferMode::Writable } else { BufferMode::ReadOnly }
+        });
+        let val = pyobj!(l=[bb=b"abc", bb=b"xyz"]);
+        let vec = value_to_vec(&val, options).unwrap();
+        assert_eq!(vec, b"\x80\x05\x95\x07\x00\x00\x00\x00\x00\x00\x00](\x97\x97\x98e.");
+        let mut de = Deserializer::new(vec.as_slice(), DeOptions::new());
+        de.set_buffers(vec![b"abc".to_vec(), b"xyz".to_vec()]);
+        assert_eq!(de.deserialize_value().unwrap(), val);
+        assert_eq!(de.readonly_buffers(), &[false, true]);
+    }
+
+    #[test]
+    fn class_instances() {
+        // Pickled by Python: [Decimal('1.5'), Point() (with x=1, y=2),
+        // KW(a=1) (with __getnewargs_ex__), L([1]) (a list subclass)].
+        let data = b"\x80\x04\x95q\x00\x00\x00\x00\x00\x00\x00]\x94(\x8c\x07decimal\x94\x8c\x07\
+                     Decimal\x94\x93\x94\x8c\x031.5\x94\x85\x94R\x94\x8c\x08__main__\x94\x8c\x05\
+                     Point\x94\x93\x94)\x81\x94}\x94(\x8c\x01x\x94K\x01\x8c\x01y\x94K\x02ubh\x07\
+                     \x8c\x02KW\x94\x93\x94)}\x94\x8c\x01a\x94K\x01s\x92\x94h\x07\x8c\x01L\x94\x93\
+                     \x94)\x81\x94K\x01ae.";
+        assert!(value_from_slice(data, Default::default()).is_err());
+        let object = |name: &str, args, kwargs: Option<Vec<(&str, Value)>>| Object {
+            module: if name == "Decimal" { "decimal" } else { "__main__" }.into(),
+            name: name.into(),
+            args,
+            kwargs: kwargs.map(|kw| kw.into_iter().map(|(k, v)| (k.into(), v)).collect()),
+            state: None,
+            listitems: vec![],
+            dictitems: BTreeMap::new(),
+        };
+        let decimal = object("Decimal", vec![pyobj!(s="1.5")], None);
+        let point = Object { state: Some(pyobj!(d={s="x" => i=1, s="y" => i=2})),
+                             ..object("Point", vec![], Some(vec![])) };
+        let kw = object("KW", vec![], Some(vec![("a", pyobj!(i=1))]));
+        let list = Object { listitems: vec![pyobj!(i=1)], ..object("L", vec![], Some(vec![])) };
+        let expected = Value::List(vec![decimal, point.clone(), kw, list].into_iter()
+                                   .map(|o| Value::Object(Box::new(o))).collect());
+        let val = value_from_slice(data, DeOptions::new().decode_objects()).unwrap();
+        assert_eq!(val, expected);
+        assert_eq!(val.to_string(),
+                   "[decimal.Decimal(\"1.5\"), __main__.Point() with state {\"x\": 1, \"y\": 2}, \
+                    __main__.KW(a=1), __main__.L()]");
+
+        let vec = value_to_vec(&val, Default::default()).unwrap();
+        assert_eq!(value_from_slice(&vec, DeOptions::new().decode_objects()).unwrap(), val);
+
+        // Protocol 0 (INST) and the serde path, which uses the state.
+        let data = b"(i__main__\nOld\np0\n(dp1\nVz\np2\nI0\nsb.";
+        let val = value_from_slice(data, DeOptions::new().decode_objects()).unwrap();
+        assert_eq!(val, Value::Object(Box::new(Object { state: Some(pyobj!(d={s="z" => i=0})),
+                                                        ..object("Old", vec![], None) })));
+        let map: BTreeMap<String, i32> = from_slice(data, DeOptions::new().decode_objects()).unwrap();
+        assert_eq!(map, BTreeMap::from_iter(vec![("z".into(), 0)]));
+
+        // Protocol 0 with new-style classes (copy_reg._reconstructor): a
+        // plain class, and a subclass of list.
+        let data = b"ccopy_reg\n_reconstructor\np0\n(c__main__\nPoint\np1\nc__builtin__\nobject\n\
+                     p2\nNtp3\nRp4\n(dp5\nVx\np6\nI1\nsVy\np7\nI2\nsb.";
+        let val = value_from_slice(data, DeOptions::new().decode_objects()).unwrap();
+        assert_eq!(val, Value::Object(Box::new(point)));
+        assert_eq!(value_from_slice(data, DeOptions::new()).unwrap(),
+                   pyobj!(d={s="x" => i=1, s="y" => i=2}));
+        let data = b"ccopy_reg\n_reconstructor\np0\n(c__main__\nMyList\np1\nc__builtin__\nlist\n\
+                     p2\n(lp3\nI1\naI2\natp4\nRp5\n(dp6\nVz\np7\nI3\nsb.";
+        let val = value_from_slice(data, DeOptions::new().decode_objects()).unwrap();
+        assert_eq!(val, Value::Object(Box::new(Object { state: Some(pyobj!(d={s="z" => i=3})),
+                                                        ..object("MyList", vec![pyobj!(l=[i=1, i=2])],
+                                                                 None) })));
+
+        // Below protocol 2, instances are created like Python does there,
+        // without NEWOBJ.
+        let point = Object { state: Some(pyobj!(d={s="x" => i=1})),
+                             ..object("Point", vec![], Some(vec![])) };
+        let pair = object("Pair", vec![pyobj!(i=1)], Some(vec![]));
+        let val = Value::List(vec![Value::Object(Box::new(point)), Value::Object(Box::new(pair))]);
+        let vec = value_to_vec(&val, SerOptions::new().proto_v0()).unwrap();
+        assert_eq!(vec, &b"(lccopy_reg\n_reconstructor\n(c__main__\nPoint\nc__builtin__\nobject\n\
+                           NtR(dVx\nI1\nsbaccopy_reg\n__newobj__\n(c__main__\nPair\nI1\ntRa."[..]);
+        for options in &[SerOptions::new().proto_v0(), SerOptions::new().proto_v1()] {
+            let vec = value_to_vec(&val, options.clone()).unwrap();
+            assert_eq!(value_from_slice(&vec, DeOptions::new().decode_objects()).unwrap(), val);
+            let kw = Value::Object(Box::new(object("KW", vec![], Some(vec![("a", pyobj!(i=1))]))));
+            assert!(value_to_vec(&kw, options.clone()).is_err());
+        }
+
+        // Keyword arguments below protocol 4, without NEWOBJ_EX.
+        let kw = Value::Object(Box::new(object("KW", vec![pyobj!(i=1)], Some(vec![("a", pyobj!(i=2))]))));
+        let vec = value_to_vec(&kw, SerOptions::new().proto_v2()).unwrap();
+        assert_eq!(vec, &b"\x80\x02ccopy_reg\n__newobj_ex__\nc__main__\nKW\nJ\x01\x00\x00\x00\x85}(X\x01\
+                           \x00\x00\x00aJ\x02\x00\x00\x00u\x87R."[..]);
+        for options in &[SerOptions::new().proto_v2(), SerOptions::new()] {
+            let vec = value_to_vec(&kw, options.clone()).unwrap();
+            assert_eq!(value_from_slice(&vec, DeOptions::new().decode_objects()).unwrap(), kw);
+        }
+    }
+
+    #[test]
+    fn custom_resolver() {
+        // Pickled by Python: [Decimal('1.5'), Point() (with x=1, y=2)].
+        let data = b"\x80\x02]q\x00(cdecimal\nDecimal\nq\x01X\x03\x00\x00\x001.5q\x02\x85q\x03\
+                     Rq\x04c__main__\nPoint\nq\x05)\x81q\x06}q\x07(X\x01\x00\x00\x00xq\x08K\x01\
+                     X\x01\x00\x00\x00yq\tK\x02ube.";
+        let resolver = |module: &str, name: &str| -> Option<Box<Reconstruct>> {
+            match (module, name) {
+                ("decimal", "Decimal") => Some(Box::new(|obj: Object| match obj.args[0] {
+                    Value::String(ref s) => Ok(Value::F64(s.parse().unwrap())),
+                    _ => Err(Error::Syntax(ErrorCode::InvalidValue("decimal".into()), None)),
+                })),
+                ("__main__", "Point") => Some(Box::new(|obj: Object| match obj.state {
+                    Some(Value::Dict(d)) => Ok(Value::Tuple(d.into_iter().map(|(_, v)| v).collect())),
+                    _ => Err(Error::Syntax(ErrorCode::InvalidValue("point".into()), None)),
+                })),
+                _ => None,
+            }
+        };
+        let options = DeOptions::new().resolver(resolver);
+        let val = value_from_slice(data, options.clone()).unwrap();
+        assert_eq!(val, pyobj!(l=[f=1.5, t=(i=1, i=2)]));
+        let val: (f64, (i32, i32)) = from_slice(data, options).unwrap();
+        assert_eq!(val, (1.5, (1, 2)));
+    }
+
+    #[test]
+    fn global_policy() {
+        // Pickled by Python: [Decimal('1.5'), Point()].
+        let data = b"\x80\x02]q\x00(cdecimal\nDecimal\nq\x01X\x03\x00\x00\x001.5q\x02\x85q\x03\
+                     Rq\x04c__main__\nPoint\nq\x05)\x81q\x06}q\x07(X\x01\x00\x00\x00xq\x08K\x01\
+                     X\x01\x00\x00\x00yq\tK\x02ube.";
+        let check = |policy, global: Option<(&str, &str, usize)>| {
+            let options = DeOptions::new().decode_objects().global_policy(policy);
+            match (value_from_slice(data, options), global) {
+                (Ok(_), None) => { }
+                (Err(Error::Eval(ErrorCode::DisallowedGlobal(m, n), ctx)), Some(global)) =>
+                    assert_eq!((&*m, &*n, ctx.offset), global),
+                (res, _) => panic!("unexpected result {:?}", res),
+            }
+        };
+        check(GlobalPolicy::allow_all(), None);
+        check(GlobalPolicy::deny_all(), Some(("decimal", "Decimal", 23)));
+        check(GlobalPolicy::allowlist(vec![("decimal", "*")]), Some(("__main__", "Point", 57)));
+        check(GlobalPolicy::allowlist(vec![("decimal", "Decimal"), ("__main__", "Point")]), None);
+        check(GlobalPolicy::predicate(|m, _| m != "__main__"), Some(("__main__", "Point", 57)));
+
+        let policy = GlobalPolicy::allowlist(vec![("numpy.*", "dtype")]);
+        assert!(policy.allows("numpy", "dtype"));
+        assert!(policy.allows("numpy.core.multiarray", "dtype"));
+        assert!(!policy.allows("numpyx", "dtype"));
+        assert!(!policy.allows("numpy", "ndarray"));
+
+        // Globals used for builtin types are always allowed.
+        let val = pyobj!(l=[ss=(i=1), fs=(i=2), bb=b"a"]);
+        let vec = value_to_vec(&val, SerOptions::new().proto_v2()).unwrap();
+        let options = DeOptions::new().global_policy(GlobalPolicy::deny_all());
+        assert_eq!(value_from_slice(&vec, options).unwrap(), val);
+    }
+
+    #[test]
+    fn resource_limits() {
+        fn check(data: &[u8], options: DeOptions, code: ErrorCode) {
+            assert!(value_from_slice(data, DeOptions::new()).is_ok());
+            match value_from_slice(data, options) {
+                Err(Error::Eval(ref c, _)) if *c == code => { }
+                res => panic!("expected {:?}, got {:?}", code, res),
+            }
+        }
+
+        let nested = value_to_vec(&pyobj!(l=[l=[l=[i=1]]]), Default::default()).unwrap();
+        check(&nested, DeOptions::new().max_depth(3), ErrorCode::DepthLimit);
+        assert!(value_from_slice(&nested, DeOptions::new().max_depth(4)).is_ok());
+        match from_slice::<Value>(&nested, DeOptions::new().max_depth(3)) {
+            Err(Error::Eval(ErrorCode::DepthLimit, _)) => { }
+            res => panic!("expected depth limit, got {:?}", res),
+        }
+        // Objects visited as their state count once.
+        let object = b"\x80\x02c__main__\nP\n)\x81}X\x01\x00\x00\x00xK\x01sb.";
+        let options = DeOptions::new().decode_objects().max_depth(2);
+        assert_eq!(from_slice::<BTreeMap<String, i32>>(object, options).unwrap(),
+                   BTreeMap::from_iter(vec![("x".into(), 1)]));
+        // Streamed containers are reported at their opcode.
+        let streamed = b"\x80\x02](](](eee.";
+        let options = DeOptions::new().stream_containers().max_depth(2);
+        match from_slice::<Vec<Vec<Vec<i32>>>>(streamed, options) {
+            Err(Error::Eval(ErrorCode::DepthLimit, context)) =>
+                assert_eq!(context.opcode, Some(("EMPTY_LIST", 6))),
+            res => panic!("expected depth limit, got {:?}", res),
+        }
+
+        let bytes = value_to_vec(&pyobj!(bb=[0u8; 100]), Default::default()).unwrap();
+        check(&bytes, DeOptions::new().max_length(99), ErrorCode::LengthLimit(100));
+        check(b"S'abcdef'\n.", DeOptions::new().max_length(5), ErrorCode::LengthLimit(7));
+        assert!(value_from_slice(&bytes, DeOptions::new().max_length(100)).is_ok());
+
+        // Pickled by Python: ["a", "b", "c"]
+        let memo = b"\x80\x02]q\x00(X\x01\x00\x00\x00aq\x01X\x01\x00\x00\x00bq\x02\
+                     X\x01\x00\x00\x00cq\x03e.";
+        check(memo, DeOptions::new().max_memo_size(3), ErrorCode::MemoLimit);
+        assert!(value_from_slice(memo, DeOptions::new().max_memo_size(4)).is_ok());
+
+        check(b"(((lll.", DeOptions::new().max_mark_depth(2), ErrorCode::MarkLimit);
+
+        // A list with many references to the same string.
+        let mut refs = b"\x80\x02](C\x10AAAAAAAAAAAAAAAAq\x00".to_vec();
+        refs.extend(b"h\x00".iter().cycle().take(2000));
+        refs.extend(b"e.");
+        check(&refs, DeOptions::new().max_allocation(10000), ErrorCode::AllocationLimit);
+        assert!(value_from_slice(&refs, DeOptions::new().max_allocation(200000)).is_ok());
+    }
+
+    #[test]
+    fn deep_nesting() {
+        fn check(data: &[u8], options: DeOptions) {
+            match value_from_slice(data, options.clone()) {
+                Err(Error::Eval(ErrorCode::DepthLimit, _)) => { }
+                res => panic!("expected depth limit, got {:?}", res.map(|_| ())),
+            }
+            match from_slice::<serde_json::Value>(data, options) {
+                Err(Error::Eval(ErrorCode::DepthLimit, _)) => { }
+                res => panic!("expected depth limit, got {:?}", res.map(|_| ())),
+            }
+        }
+
+        // Lists, memoized lists, tuples and dicts nested 300000 deep.
+        const N: usize = 300_000;
+        let mut lists = b"\x80\x02".to_vec();
+        lists.extend(b"]".repeat(N));
+        lists.extend(b"a".repeat(N - 1));
+        let mut memoized = b"\x80\x04".to_vec();
+        memoized.extend(b"]\x94".repeat(N));
+        memoized.extend(b"a".repeat(N - 1));
+        let mut tuples = b"(".repeat(N);
+        tuples.extend(b"t".repeat(N));
+        let mut dicts = b"}X\x01\x00\x00\x00k".repeat(N - 1);
+        dicts.extend(b"}");
+        dicts.extend(b"s".repeat(N - 1));
+        for data in &mut [lists, memoized, tuples, dicts] {
+            data.push(b'.');
+            check(data, DeOptions::new());
+            check(data, DeOptions::new().stream_containers());
+            check(&data[..data.len() - 2], DeOptions::new().recover());
+            // Truncated, with the nested value still on the stack.
+            let truncated = &data[..data.len() - 1];
+            assert!(value_from_slice(truncated, DeOptions::new()).is_err());
+            let mut parser = PickleParser::new(DeOptions::new());
+            assert_eq!(parser.feed(truncated).unwrap(), ParseStatus::NeedMoreData);
+        }
+
+        // Writing is limited in the same way.
+        let mut value = Value::None;
+        for _ in 0..299 {
+            value = Value::List(vec![value]);
+        }
+        match value_to_vec(&value, SerOptions::new()) {
+            Err(Error::Syntax(ErrorCode::DepthLimit, None)) => { }
+            res => panic!("expected depth limit, got {:?}", res),
+        }
+        #[derive(serde_derive::Serialize)]
+        struct Nested(Vec<Nested>);
+        let mut nested = Nested(vec![]);
+        for _ in 0..299 {
+            nested = Nested(vec![nested]);
+        }
+        match to_vec(&nested, SerOptions::new()) {
+            Err(Error::Syntax(ErrorCode::DepthLimit, None)) => { }
+            res => panic!("expected depth limit, got {:?}", res),
+        }
+
+        // And so is converting to and from `Value`.
+        match to_value(&nested) {
+            Err(Error::Syntax(ErrorCode::DepthLimit, None)) => { }
+            res => panic!("expected depth limit, got {:?}", res),
+        }
+        match from_value::<serde_json::Value>(value.clone()) {
+            Err(Error::Syntax(ErrorCode::DepthLimit, None)) => { }
+            res => panic!("expected depth limit, got {:?}", res),
+        }
+        // The rest of a deeper value is dropped without overflowing the stack.
+        let mut deep = Value::None;
+        for _ in 0..N {
+            deep = Value::List(vec![deep]);
+        }
+        assert!(from_value::<serde_json::Value>(deep).is_err());
+
+        // Higher limits can be set.
+        assert!(to_vec(&nested, SerOptions::new().max_depth(300)).is_ok());
+        assert!(from_value_with::<serde_json::Value>(value.clone(), 300).is_ok());
+        let data = value_to_vec(&value, SerOptions::new().max_depth(300)).unwrap();
+        assert!(value_from_slice(&data, DeOptions::new()).is_err());
+        assert_eq!(value_from_slice(&data, DeOptions::new().max_depth(300)).unwrap(), value);
+    }
+
+    #[test]
+    fn drop_deep_values() {
+        // Values built by hand can be dismantled without recursion, whatever
+        // they are nested in.
+        let mut value = Value::None;
+        let mut key = HashableValue::None;
+        for i in 0..1_000_000 {
+            value = match i % 5 {
+                0 => Value::List(vec![value]),
+                1 => Value::Tuple(vec![Value::I64(1), value]),
+                2 => Value::Dict(BTreeMap::from_iter(vec![(hpyobj!(i=1), value)])),
+                3 => Value::Shared(1, Box::new(value)),
+                _ => Value::Object(Box::new(Object {
+                    module: "m".into(),
+                    name: "C".into(),
+                    args: vec![],
+                    kwargs: None,
+                    state: Some(value),
+                    listitems: vec![],
+                    dictitems: BTreeMap::new(),
+                })),
+            };
+            key = match i % 2 {
+                0 => HashableValue::Tuple(vec![key]),
+                _ => HashableValue::FrozenSet(BTreeSet::from_iter(vec![key])),
+            };
+        }
+        Value::Dict(BTreeMap::from_iter(vec![(key, value)])).dismantle();
+    }
+
+    #[test]
+    fn shared_references() {
+        // [a, a, {'k': a}] with a = [1, [2]]
+        let data = b"\x80\x02]q\x00(]q\x01(K\x01]q\x02K\x02aeh\x01}q\x03X\x01\x00\x00\x00kq\x04h\x01se.";
+        assert_eq!(value_from_slice(data, Default::default()).unwrap(),
+                   pyobj!(l=[l=[i=1, l=[i=2]], l=[i=1, l=[i=2]], d={s="k" => l=[i=1, l=[i=2]]}]));
+
+        // A shared list modified in a later batch.
+        let data = b"\x80\x02](]q\x00K\x01ae(h\x00K\x02ae.";
+        for options in vec![DeOptions::new(), DeOptions::new().stream_containers()] {
+            let val: Vec<Vec<i32>> = from_slice(data, options).unwrap();
+            assert_eq!(val, vec![vec![1, 2], vec![1, 2]]);
+        }
+
+        // [a, set(a)], where a is modified after set() was called with it.
+        let data = b"\x80\x02]q\x00(]q\x01K\x01ac__builtin__\nset\nq\x02h\x01\x85Rq\x03eh\x01K\x02a0.";
+        let expected = pyobj!(l=[l=[i=1, i=2], ss=(i=1)]);
+        assert_eq!(value_from_slice(data, Default::default()).unwrap(), expected);
+        let val: (Vec<i32>, BTreeSet<i32>) = from_slice(data, Default::default()).unwrap();
+        assert_eq!(val, (vec![1, 2], BTreeSet::from_iter(vec![1])));
+
+        // Objects sharing their memoized arguments and slot state.
+        let data = b"\x80\x02](c__main__\nC\nq\x01]q\x02K\x01a\x85q\x03\x81q\x04N}q\x05X\x01\x00\
+                     \x00\x00aK\x01s\x86q\x06bh\x01h\x03\x81h\x06be.";
+        let object = Value::Object(Box::new(Object {
+            module: "__main__".into(),
+            name: "C".into(),
+            args: vec![pyobj!(l=[i=1])],
+            kwargs: Some(BTreeMap::new()),
+            state: Some(pyobj!(t=(n=None, d={s="a" => i=1}))),
+            listitems: vec![],
+            dictitems: BTreeMap::new(),
+        }));
+        assert_eq!(value_from_slice(data, DeOptions::new().decode_objects()).unwrap(),
+                   Value::List(vec![object.clone(), object]));
+        assert_eq!(value_from_slice(data, Default::default()).unwrap(),
+                   pyobj!(l=[d={s="a" => i=1}, d={s="a" => i=1}]));
+    }
+
+    #[test]
+    fn stream_deserializer() {
+        let first = to_vec(&(1, "a"), Default::default()).unwrap();
+        let second = to_vec(&(2, "bc"), Default::default()).unwrap();
+        let mut data = first.clone();
+        data.extend(&second);
+
+        let mut stream = StreamDeserializer::<_, (i32, String)>::new(&data[..], DeOptions::new());
+        assert_eq!(stream.next().unwrap().unwrap(), (1, "a".into()));
+        assert_eq!(stream.byte_offset(), 0);
+        assert_eq!(stream.next().unwrap().unwrap(), (2, "bc".into()));
+        assert_eq!(stream.byte_offset(), first.len());
+        assert!(stream.next().is_none());
+
+        // Memo ids are reused in each pickle.
+        let list = b"\x80\x02]q\x00X\x01\x00\x00\x00aq\x01a.";
+        let mut data = list.to_vec();
+        data.extend(&list[..]);
+        let values = StreamDeserializer::values(&data[..], DeOptions::new())
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(values, vec![Value::List(vec![Value::String("a".into())]); 2]);
+
+        // A truncated pickle ends the stream with an error.
+        data.extend(&list[..5]);
+        let mut stream = StreamDeserializer::values(&data[..], DeOptions::new());
+        assert!(stream.next().unwrap().is_ok());
+        assert!(stream.next().unwrap().is_ok());
+        match stream.next() {
+            Some(Err(Error::Eval(ErrorCode::EOFWhileParsing, _))) => { }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(stream.byte_offset(), 2 * list.len());
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn exact_consumption() {
+        use std::io::{BufRead, BufReader, Read};
+        // Protocol 0 to exercise line-based reading, followed by another pickle.
+        let mut data = b"(lp0\nI1\naVab\np1\na.".to_vec();
+        data.extend(to_vec(&"next", Default::default()).unwrap());
+        data.extend(b"rest");
+
+        let rdr = BufReader::with_capacity(4, &data[..]);
+        let mut de = Deserializer::from_buf_read(rdr, DeOptions::new());
+        assert_eq!(de.deserialize_value().unwrap(),
+                   Value::List(vec![Value::I64(1), Value::String("ab".into())]));
+        let mut rdr = de.into_inner();
+        assert_eq!(rdr.fill_buf().unwrap()[0], b'\x80');
+
+        let mut de = Deserializer::from_buf_read(rdr, DeOptions::new());
+        let next: String = serde::Deserialize::deserialize(&mut de).unwrap();
+        assert_eq!(next, "next");
+        let mut rest = String::new();
+        de.into_inner().read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "rest");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_io() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+        use tokio::io::{AsyncRead, AsyncReadExt, BufReader, ReadBuf};
+        use crate::{from_async_reader, value_from_async_reader, to_async_writer};
+
+        fn block_on<F: Future>(fut: F) -> F::Output {
+            fn raw() -> RawWaker {
+                static VTABLE: RawWakerVTable = RawWakerVTable::new(|_| raw(), |_| (), |_| (), |_| ());
+                RawWaker::new(std::ptr::null(), &VTABLE)
+            }
+            let waker = unsafe { Waker::from_raw(raw()) };
+            let mut cx = Context::from_waker(&waker);
+            let mut fut = Box::pin(fut);
+            loop {
+                if let Poll::Ready(result) = fut.as_mut().poll(&mut cx) {
+                    return result;
+                }
+            }
+        }
+
+        // Delivers a few bytes at a time, and is not ready every other time.
+        // If the last field is set, the connection stays open after the data.
+        struct Trickle(Vec<u8>, usize, bool, bool);
+
+        impl AsyncRead for Trickle {
+            fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf)
+                         -> Poll<std::io::Result<()>> {
+                self.2 = !self.2;
+                if self.2 {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                let start = self.1;
+                if start == self.0.len() && self.3 {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                let end = (start + 3).min(self.0.len()).min(start + buf.remaining());
+                buf.put_slice(&self.0[start..end]);
+                self.1 = end;
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let data = std::fs::read("test/data/tests_py3_proto3.pickle").unwrap();
+        let expected = value_from_slice(&data, DeOptions::new()).unwrap();
+        let fut = value_from_async_reader(BufReader::new(Trickle(data.clone(), 0, false, false)), DeOptions::new());
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&fut);
+        assert_eq!(block_on(fut).unwrap(), expected);
+        let tuple = to_vec(&(1, "a", vec![2.5]), SerOptions::new()).unwrap();
+        let val: (i32, String, Vec<f64>) =
+            block_on(from_async_reader(BufReader::new(Trickle(tuple, 0, false, false)), DeOptions::new())).unwrap();
+        assert_eq!(val, (1, "a".into(), vec![2.5]));
+
+        // Decoding stops at the end of the pickle, and leaves what follows.
+        let mut trailing = data.clone();
+        trailing.extend_from_slice(b"next");
+        let mut rdr = BufReader::new(Trickle(trailing, 0, false, false));
+        assert_eq!(block_on(value_from_async_reader(&mut rdr, DeOptions::new())).unwrap(), expected);
+        let mut rest = Vec::new();
+        block_on(rdr.read_to_end(&mut rest)).unwrap();
+        assert_eq!(rest, b"next");
+        // The value is returned before the connection is closed.
+        let rdr = BufReader::new(Trickle(data.clone(), 0, false, true));
+        assert_eq!(block_on(value_from_async_reader(rdr, DeOptions::new())).unwrap(), expected);
+        match block_on(value_from_async_reader(&data[..data.len() - 1], DeOptions::new())) {
+            Err(Error::Eval(ErrorCode::EOFWhileParsing, _)) => { }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // Long strings arrive in many reads, and are decoded once.
+        let long = Value::Tuple(vec![Value::Bytes(vec![b'x'; 16 << 20]),
+                                     Value::String("y".repeat(16 << 20))]);
+        let data = value_to_vec(&long, SerOptions::new()).unwrap();
+        assert_eq!(block_on(value_from_async_reader(&data[..], DeOptions::new())).unwrap(), long);
+
+        let mut written = Vec::new();
+        block_on(to_async_writer(&mut written, &val, SerOptions::new())).unwrap();
+        assert_eq!(written, to_vec(&val, SerOptions::new()).unwrap());
+    }
+
+    #[test]
+    fn pickle_parser() {
+        for proto in &[0, 2, 3, 5] {
+            let data = std::fs::read(format!("test/data/tests_py3_proto{}.pickle", proto)).unwrap();
+            let expected = value_from_slice(&data, DeOptions::new()).unwrap();
+            let mut input = data.clone();
+            input.extend(&data);
+
+            // Feed one byte at a time.
+            let mut parser = PickleParser::new(DeOptions::new());
+            let mut values = vec![];
+            for byte in input.chunks(1) {
+                if let ParseStatus::Done(value) = parser.feed(byte).unwrap() {
+                    values.push(value);
+                }
+            }
+            assert_eq!(parser.finish().unwrap(), vec![]);
+            assert_eq!(values, vec![expected.clone(), expected.clone()]);
+            assert_eq!(parser.position(), input.len());
+
+            // Feed everything at once, and take the second pickle by polling.
+            let mut parser = PickleParser::new(DeOptions::new());
+            assert_eq!(parser.feed(&input).unwrap(), ParseStatus::Done(expected.clone()));
+            assert_eq!(parser.poll().unwrap(), ParseStatus::Done(expected.clone()));
+            assert_eq!(parser.poll().unwrap(), ParseStatus::NeedMoreData);
+            assert_eq!(parser.feed(&[]).unwrap(), ParseStatus::NeedMoreData);
+            // Or when the input ends.
+            let mut parser = PickleParser::new(DeOptions::new());
+            assert_eq!(parser.feed(&input).unwrap(), ParseStatus::Done(expected.clone()));
+            assert_eq!(parser.finish().unwrap(), vec![expected.clone()]);
+            let mut parser = PickleParser::new(DeOptions::new());
+            input.extend(&data[..data.len() - 1]);
+            assert_eq!(parser.feed(&input).unwrap(), ParseStatus::Done(expected.clone()));
+            match parser.finish() {
+                Err(Error::Eval(ErrorCode::EOFWhileParsing, _)) => { }
+                other => panic!("unexpected result: {:?}", other),
+            }
+
+            // Incomplete input.
+            let mut parser = PickleParser::new(DeOptions::new());
+            assert_eq!(parser.feed(&data[..data.len() - 1]).unwrap(), ParseStatus::NeedMoreData);
+            match parser.finish() {
+                Err(Error::Eval(ErrorCode::EOFWhileParsing, _)) => { }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        // Long strings fed in small chunks are decoded once, not again for
+        // every chunk.
+        let long = Value::Tuple(vec![Value::Bytes(vec![b'x'; 16 << 20]),
+                                     Value::String("y".repeat(16 << 20))]);
+        let data = value_to_vec(&long, SerOptions::new()).unwrap();
+        let mut parser = PickleParser::new(DeOptions::new());
+        let mut values = vec![];
+        for chunk in data.chunks(1024) {
+            if let ParseStatus::Done(value) = parser.feed(chunk).unwrap() {
+                values.push(value);
+            }
+        }
+        assert_eq!(parser.finish().unwrap(), vec![]);
+        assert_eq!(values, vec![long]);
+    }
+
+    #[test]
+    fn recovery() {
+        // {'a': [1, 2], 'b': 3}
+        let data = b"\x80\x02}q\x00(X\x01\x00\x00\x00aq\x01]q\x02(K\x01K\x02eX\x01\x00\x00\x00bq\x03K\x03u.";
+        let dict = |items: Vec<i64>| Value::Dict(BTreeMap::from_iter(vec![
+            (HashableValue::String("a".into()), Value::List(items.into_iter().map(Value::I64).collect())),
+        ]));
+        let recover = |data: &[u8]| {
+            let mut de = Deserializer::new(data, DeOptions::new().recover());
+            let value = de.deserialize_value().unwrap();
+            let report = de.recovery().unwrap();
+            let code = report.error.code().cloned().unwrap();
+            (value, code, report.offset, report.open_marks, report.dropped.clone())
+        };
+        assert_eq!(recover(&data[..20]),
+                   (dict(vec![1]), ErrorCode::EOFWhileParsing, 20, 2, vec![]));
+        assert_eq!(recover(&data[..31]),
+                   (dict(vec![1, 2]), ErrorCode::EOFWhileParsing, 31, 1, vec!["\"b\" (memo 3)".into()]));
+        let mut corrupted = data.to_vec();
+        corrupted[31] = 0xff;
+        assert_eq!(recover(&corrupted),
+                   (dict(vec![1, 2]), ErrorCode::Unsupported('\u{ff}'), 31, 1, vec!["\"b\" (memo 3)".into()]));
+        // The rest of the input is ignored.
+        assert_eq!(value_from_slice(&corrupted, DeOptions::new().recover()).unwrap(), dict(vec![1, 2]));
+        assert!(value_from_slice(&corrupted, DeOptions::new()).is_err());
+
+        // Complete pickles aren't affected, and limits are still enforced.
+        let mut de = Deserializer::new(&data[..], DeOptions::new().recover());
+        de.deserialize_value().unwrap();
+        assert!(de.recovery().is_none());
+        assert!(value_from_slice(&data[..31], DeOptions::new().recover().max_memo_size(2)).is_err());
+
+        // Every truncated pickle can be decoded or fails cleanly.
+        for proto in &[0, 1, 2, 3, 4, 5] {
+            let data = std::fs::read(format!("test/data/tests_py3_proto{}.pickle", proto)).unwrap();
+            for end in 0..data.len() {
+                let _ = value_from_slice(&data[..end], DeOptions::new().recover());
+            }
+        }
+    }
+
+    #[test]
+    fn tracing() {
+        // [1, 'a'] with the list memoized, in protocol 2.
+        let data = b"\x80\x02]q\x00(K\x01X\x01\x00\x00\x00ae.";
+        let mut de = Deserializer::new(&data[..], DeOptions::new());
+        let mut traces = vec![];
+        let value = loop {
+            match de.step().unwrap() {
+                Step::Opcode(trace) => traces.push(trace),
+                Step::Stop(trace, value) => { traces.push(trace); break value; }
+            }
+        };
+        assert_eq!(value, Value::List(vec![Value::I64(1), Value::String("a".into())]));
+        let summary: Vec<_> = traces.iter().map(|t| (t.offset, t.name.unwrap(), t.argument.clone()))
+                                           .collect();
+        assert_eq!(summary, vec![
+            (0, "PROTO", Some(Value::I64(2))),
+            (2, "EMPTY_LIST", None),
+            (3, "BINPUT", Some(Value::I64(0))),
+            (5, "MARK", None),
+            (6, "BININT1", Some(Value::I64(1))),
+            (8, "BINUNICODE", Some(Value::String("a".into()))),
+            (14, "APPENDS", None),
+            (15, "STOP", None),
+        ]);
+        assert_eq!(traces[5].after, Some(VmState {
+            stack_len: 3, marks: 1, memo_len: 1,
+            stack_top: vec!["list(0) (memo 0)".into(), "MARK".into(), "1".into(), "\"a\"".into()],
+        }));
+        assert_eq!(traces[6].after.as_ref().unwrap().stack_top, vec!["list(2) (memo 0)"]);
+
+        // The callback also sees opcodes that fail.
+        let seen = Arc::new(Mutex::new(Vec::<Trace>::new()));
+        let seen2 = seen.clone();
+        let mut de = Deserializer::new(&b"\x80\x02K\x01a."[..], DeOptions::new());
+        de.set_trace(move |trace| seen2.lock().unwrap().push(trace.clone()));
+        assert!(de.deserialize_value().is_err());
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 3);
+        assert_eq!((seen[2].name, seen[2].after.as_ref()), (Some("APPEND"), None));
+        assert_eq!(seen[2].before.stack_top, vec!["1"]);
+
+        // Stepping gives the same results as decoding directly.
+        for proto in &[0, 1, 2, 3, 4, 5] {
+            let data = std::fs::read(format!("test/data/tests_py3_proto{}.pickle", proto)).unwrap();
+            let mut de = Deserializer::new(&data[..], DeOptions::new());
+            let value = loop {
+                if let Step::Stop(_, value) = de.step().unwrap() {
+                    break value;
+                }
+            };
+            assert_eq!(value, value_from_slice(&data, DeOptions::new()).unwrap());
+        }
+    }
+
     #[test]
     fn unresolvable_global() {
         let data = std::fs::read("test/data/test_unresolvable_global.pickle").unwrap();